@@ -1,7 +1,10 @@
 use std::{
     error::Error,
     fs::{self, File, OpenOptions},
+    ffi::CStr,
     io::{self, Read, Write},
+    mem,
+    os::raw::{c_char, c_void},
     slice,
 };
 
@@ -151,9 +154,95 @@ pub struct InputEventStruct {
     event_value: i32,
 }
 
-fn ac_is_online() -> Result<bool, io::Error> {
+const ABI_VERSION: u32 = 2;
+const CAP_EVDEV: u32 = 1 << 0;
+const CAP_ACPI: u32 = 1 << 1;
+const CAP_IDLE: u32 = 1 << 2;
+const CAP_POWER_SUPPLY: u32 = 1 << 3;
+
+#[repr(C)]
+pub struct PluginInfo {
+    abi_version: u32,
+    info_size: u32,
+    input_event_size: u32,
+    acpi_event_size: u32,
+    capabilities: u32,
+    power_supply_size: u32,
+}
+
+/// State of a power supply passed to `power_supply_handler`; unknown
+/// values are -1
+#[repr(C)]
+pub struct PowerSupply {
+    name: [u8; 32],
+    supply_type: u32,
+    status: u32,
+    online: i32,
+    capacity: i32,
+    energy_now: i64,
+    energy_full: i64,
+    charge_now: i64,
+    charge_full: i64,
+    time_to_empty: i64,
+}
+
+static PLUGIN_INFO: PluginInfo = PluginInfo {
+    abi_version: ABI_VERSION,
+    info_size: mem::size_of::<PluginInfo>() as u32,
+    input_event_size: mem::size_of::<InputEventStruct>() as u32,
+    // device_class (20) + bus_id (16) + event_type (4) + event_data (4)
+    acpi_event_size: 44,
+    capabilities: CAP_EVDEV | CAP_ACPI | CAP_IDLE | CAP_POWER_SUPPLY,
+    power_supply_size: mem::size_of::<PowerSupply>() as u32,
+};
+
+#[no_mangle]
+pub extern "C" fn pwrsurge_plugin_info() -> *const PluginInfo {
+    &PLUGIN_INFO
+}
+
+#[repr(C)]
+pub struct PluginConfig {
+    config_size: u32,
+    abi_version: u32,
+    lib_path: *const c_char,
+    config_path: *const c_char,
+}
+
+/// State shared by all handler calls; created in `pwrsurge_init` and
+/// freed in `pwrsurge_shutdown`.
+struct Context {
+    ac_online_files: Vec<String>,
+}
+
+/// # Safety
+///
+/// `config` must point to a valid `PluginConfig`.
+#[no_mangle]
+pub unsafe extern "C" fn pwrsurge_init(config: *const PluginConfig) -> *mut c_void {
+    let config_path = CStr::from_ptr((*config).config_path);
+    println!("Initializing with config file {}", config_path.to_string_lossy());
+    let ac_online_files = match ac_online_files() {
+        Ok(files) => files,
+        Err(e) => {
+            println!("Failed to find AC adapters: {}", e);
+            Vec::new()
+        }
+    };
+    Box::into_raw(Box::new(Context { ac_online_files })) as *mut c_void
+}
+
+/// # Safety
+///
+/// `ctx` must be the pointer returned by `pwrsurge_init`.
+#[no_mangle]
+pub unsafe extern "C" fn pwrsurge_shutdown(ctx: *mut c_void) {
+    drop(Box::from_raw(ctx as *mut Context));
+}
+
+fn ac_online_files() -> Result<Vec<String>, io::Error> {
     let readdir = fs::read_dir("/sys/bus/acpi/drivers/ac/")?;
-    let mut online = false;
+    let mut files = Vec::new();
     for entry in readdir {
         let direntry = entry?;
         let is_symlink = direntry.file_type()?.is_symlink();
@@ -165,17 +254,24 @@ fn ac_is_online() -> Result<bool, io::Error> {
                     return Err(io::Error::from(io::ErrorKind::InvalidInput));
                 }
             };
-            let ac_file = format!(
+            files.push(format!(
                 "/sys/bus/acpi/drivers/ac/{}/power_supply/AC/online",
                 direntry_string
-            );
-            let mut file = File::open(ac_file.as_str())?;
-            let mut online_string = String::new();
-            file.read_to_string(&mut online_string)?;
-            let online_str = online_string.trim();
-            if online_str == "1" {
-                online = true;
-            }
+            ));
+        }
+    }
+    Ok(files)
+}
+
+fn ac_is_online(ctx: &Context) -> Result<bool, io::Error> {
+    let mut online = false;
+    for ac_file in ctx.ac_online_files.iter() {
+        let mut file = File::open(ac_file.as_str())?;
+        let mut online_string = String::new();
+        file.read_to_string(&mut online_string)?;
+        let online_str = online_string.trim();
+        if online_str == "1" {
+            online = true;
         }
     }
     Ok(online)
@@ -278,7 +374,8 @@ fn battery(event: &AcpiEvent) -> i32 {
     println!("Event type: {}", event.event_type);
     println!("Event data: {}", event.event_data);
 
-    0
+    // Only logs the event, so let the next plugin see it as well
+    1
 }
 
 fn assert_cpu_state(is_online: bool, path: &str) -> Result<(), Box<dyn Error>> {
@@ -291,9 +388,9 @@ fn assert_cpu_state(is_online: bool, path: &str) -> Result<(), Box<dyn Error>> {
         || ((state.as_str() != "performance") && is_online);
     if needs_state_change {
         if is_online {
-            rw_file.write(b"performance")?;
+            rw_file.write_all(b"performance")?;
         } else {
-            rw_file.write(b"powersave")?;
+            rw_file.write_all(b"powersave")?;
         }
     }
     Ok(())
@@ -323,22 +420,27 @@ fn assert_all_cpu_states(is_online: bool) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn ac_adapter() -> i32 {
-    let is_online = try_int!(ac_is_online());
+fn ac_adapter(ctx: &Context) -> i32 {
+    let is_online = try_int!(ac_is_online(ctx));
     try_int!(assert_all_cpu_states(is_online));
     0
 }
 
+/// # Safety
+///
+/// `event_ptr` must point to a serialized `AcpiEvent` and `ctx` must be
+/// the pointer returned by `pwrsurge_init`.
 #[no_mangle]
-pub fn acpi_handler(event_ptr: *const u8) -> i32 {
-    let event_buf = unsafe { slice::from_raw_parts(event_ptr, AcpiEvent::type_size().expect("constant size")) };
+pub unsafe extern "C" fn acpi_handler(event_ptr: *const u8, ctx: *mut c_void) -> i32 {
+    let ctx = &*(ctx as *const Context);
+    let event_buf = slice::from_raw_parts(event_ptr, AcpiEvent::type_size().expect("constant size"));
     let event = try_int!(AcpiEvent::deserialize(event_buf));
     println!("{:?}", event);
 
     match event.device_class.0.as_str() {
         "battery" => battery(&event),
-        "ac_adapter" | "processor" => ac_adapter(),
-        _ => 0,
+        "ac_adapter" | "processor" => ac_adapter(ctx),
+        _ => 1,
     }
 }
 
@@ -351,16 +453,47 @@ pub fn acpi_handler(event_ptr: *const u8) -> i32 {
 //    0
 //}
 
+/// # Safety
+///
+/// `event` must point to a valid `input_event`.
 #[no_mangle]
-pub fn evdev_handler(event: *const InputEvent) -> i32 {
-    let event_ref = unsafe { &*event };
+pub unsafe extern "C" fn evdev_handler(event: *const InputEvent, _ctx: *mut c_void) -> i32 {
+    let event_ref = &*event;
     println!("Seconds: {}", event_ref.get_timestamp().tv_sec);
     println!("Microseconds: {}", event_ref.get_timestamp().tv_usec);
     println!("Event type: {}", event_ref.get_event_type());
     println!("Event code: {}", event_ref.get_event_code());
     println!("Event value: {}", event_ref.get_event_value());
 
-    0
+    1
+}
+
+/// # Safety
+///
+/// `ctx` must be the pointer returned by `pwrsurge_init`.
+#[no_mangle]
+pub unsafe extern "C" fn idle_handler(level: u32, _ctx: *mut c_void) -> i32 {
+    if level == 0 {
+        println!("Active again");
+    } else {
+        println!("Entered idle level {}", level);
+    }
+
+    1
+}
+
+/// # Safety
+///
+/// `state` must point to a valid `PowerSupply`.
+#[no_mangle]
+pub unsafe extern "C" fn power_supply_handler(state: *const PowerSupply, _ctx: *mut c_void) -> i32 {
+    let state = &*state;
+    let name = CStr::from_bytes_until_nul(&state.name).map(|s| s.to_string_lossy());
+    println!("Power supply: {}", name.unwrap_or_default());
+    println!("Capacity: {}%", state.capacity);
+    println!("Time to empty: {}s", state.time_to_empty);
+
+    1
 }
 
 /// Only for `examples` directory to compile on `cargo test`
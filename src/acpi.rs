@@ -1,3 +1,5 @@
+use std::fmt::{self, Display};
+
 use neli::{
     consts::{genl::*, nl::*},
     deserialize,
@@ -22,7 +24,7 @@ pub fn acpi_event(msg: Nlmsghdr<GenlId, Genlmsghdr<CtrlCmd, u16>>) -> Result<Acp
         }
     };
     let attr_handle = genl.get_attr_handle();
-    Ok(attr_handle.get_attr_payload_as::<AcpiEvent>(1)?)
+    attr_handle.get_attr_payload_as::<AcpiEvent>(1)
 }
 
 impl_var!(
@@ -32,6 +34,22 @@ impl_var!(
     Event => 1
 );
 
+/// Device classes of the ACPI events sent by the kernel's own drivers.
+/// Vendor drivers such as `thinkpad_acpi` add their own, such as
+/// `ibm/hotkey`.
+pub const DEVICE_CLASSES: &[&str] = &[
+    "ac_adapter",
+    "battery",
+    "button/power",
+    "button/sleep",
+    "button/lid",
+    "processor",
+    "thermal_zone",
+    "video",
+    "sbs",
+    "wmi",
+];
+
 #[derive(Debug, PartialEq)]
 pub struct DeviceClass(pub String);
 
@@ -114,6 +132,16 @@ pub struct AcpiEvent {
     pub event_data: u32,
 }
 
+impl Display for AcpiEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} type {:#x} data {:#x}",
+            self.device_class.0, self.bus_id.0, self.event_type, self.event_data
+        )
+    }
+}
+
 impl Nl for AcpiEvent {
     fn serialize(&self, mem: SerBuffer) -> Result<(), SerError> {
         serialize! {
@@ -162,12 +190,12 @@ mod test {
     fn test_acpi_event_serialize() {
         let mut acpi_event_serialized = Cursor::new(Vec::new());
         acpi_event_serialized
-            .write(&vec![
+            .write_all(&[
                 65, 65, 65, 65, 65, 65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             ])
             .unwrap();
         acpi_event_serialized
-            .write(&vec![65, 65, 65, 65, 65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .write_all(&[65, 65, 65, 65, 65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
             .unwrap();
         acpi_event_serialized.write_u32::<NativeEndian>(5).unwrap();
         acpi_event_serialized.write_u32::<NativeEndian>(7).unwrap();
@@ -194,12 +222,12 @@ mod test {
 
         let mut acpi_event_buffer = Cursor::new(Vec::new());
         acpi_event_buffer
-            .write(&vec![
+            .write_all(&[
                 65, 65, 65, 65, 65, 65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             ])
             .unwrap();
         acpi_event_buffer
-            .write(&vec![65, 65, 65, 65, 65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .write_all(&[65, 65, 65, 65, 65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
             .unwrap();
         acpi_event_buffer.write_u32::<NativeEndian>(5).unwrap();
         acpi_event_buffer.write_u32::<NativeEndian>(7).unwrap();
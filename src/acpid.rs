@@ -0,0 +1,506 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    fs,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, RwLock},
+};
+
+use regex::Regex;
+use tokio::{
+    io::AsyncWriteExt,
+    net::UnixListener,
+    spawn,
+    sync::broadcast::{self, error::RecvError},
+    task::spawn_blocking,
+};
+
+use crate::{
+    acpi::AcpiEvent,
+    args::AcpidConfig,
+    codes::{
+        KEY_BATTERY, KEY_BRIGHTNESSDOWN, KEY_BRIGHTNESSUP, KEY_MUTE, KEY_POWER, KEY_PROG1,
+        KEY_SCREENLOCK, KEY_SLEEP, KEY_SUSPEND, KEY_SWITCHVIDEOMODE, KEY_VOLUMEDOWN, KEY_VOLUMEUP,
+        KEY_WAKEUP, KEY_WLAN, SW_DOCK, SW_HEADPHONE_INSERT, SW_MICROPHONE_INSERT, SW_TABLET_MODE,
+    },
+    evdev::{InputEvent, EV_KEY, EV_SW, SW_LID},
+};
+
+/// Lines buffered for each socket client before it starts missing
+/// events
+const SOCKET_BACKLOG: usize = 64;
+
+#[derive(Debug)]
+pub struct RuleError(String);
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for RuleError {}
+
+/// Format an event the way acpid passes it to rules, for example
+/// `button/lid LID 00000080 00000001`.
+pub fn format_event(event: &AcpiEvent) -> String {
+    format!(
+        "{} {} {:08x} {:08x}",
+        event.device_class.0, event.bus_id.0, event.event_type, event.event_data
+    )
+}
+
+/// Input layer keys that acpid reports, with the event it formats for
+/// a key press.
+const KEY_EVENTS: &[(u16, &str)] = &[
+    (KEY_MUTE, "button/mute MUTE 00000080 00000000"),
+    (KEY_VOLUMEDOWN, "button/volumedown VOLDN 00000080 00000000"),
+    (KEY_VOLUMEUP, "button/volumeup VOLUP 00000080 00000000"),
+    (KEY_POWER, "button/power PBTN 00000080 00000000"),
+    (KEY_SLEEP, "button/sleep SBTN 00000080 00000000"),
+    (KEY_WAKEUP, "button/wakeup WKUP 00000080 00000000"),
+    (KEY_PROG1, "button/prog1 PROG1 00000080 00000000"),
+    (
+        KEY_SCREENLOCK,
+        "button/screenlock SCRNLCK 00000080 00000000",
+    ),
+    (KEY_SUSPEND, "button/suspend SUSP 00000080 00000000"),
+    (
+        KEY_BRIGHTNESSDOWN,
+        "video/brightnessdown BRTDN 00000087 00000000",
+    ),
+    (
+        KEY_BRIGHTNESSUP,
+        "video/brightnessup BRTUP 00000086 00000000",
+    ),
+    (
+        KEY_SWITCHVIDEOMODE,
+        "video/switchmode VMOD 00000080 00000000",
+    ),
+    (KEY_BATTERY, "button/battery BAT 00000080 00000000"),
+    (KEY_WLAN, "button/wlan WLAN 00000080 00000000"),
+];
+
+/// Input layer switches that acpid reports, with the events it formats
+/// for the switch turning off and on.
+const SWITCH_EVENTS: &[(u16, &str, &str)] = &[
+    (SW_LID, "button/lid LID open", "button/lid LID close"),
+    (
+        SW_TABLET_MODE,
+        "video/tabletmode TBLT 0000008A 00000000",
+        "video/tabletmode TBLT 0000008A 00000001",
+    ),
+    (
+        SW_HEADPHONE_INSERT,
+        "jack/headphone HEADPHONE unplug",
+        "jack/headphone HEADPHONE plug",
+    ),
+    (
+        SW_MICROPHONE_INSERT,
+        "jack/microphone MICROPHONE unplug",
+        "jack/microphone MICROPHONE plug",
+    ),
+    (SW_DOCK, "button/dock DOCK undock", "button/dock DOCK dock"),
+];
+
+/// Format an input event the way acpid's input layer support does, if
+/// acpid reports it at all. Keys are only reported when pressed, not
+/// on release or autorepeat.
+pub fn format_input_event(event: &InputEvent) -> Option<&'static str> {
+    let code = event.get_event_code();
+    match event.get_event_type() {
+        EV_KEY if event.get_event_value() == 1 => KEY_EVENTS
+            .iter()
+            .find(|(key, _)| *key == code)
+            .map(|(_, line)| *line),
+        EV_SW => SWITCH_EVENTS
+            .iter()
+            .find(|(switch, _, _)| *switch == code)
+            .map(|(_, off, on)| if event.get_event_value() != 0 { *on } else { *off }),
+        _ => None,
+    }
+}
+
+/// Substitute the event for `%e` and `%` for `%%` in an action.
+fn expand_action(action: &str, event: &str) -> String {
+    let mut out = String::with_capacity(action.len() + event.len());
+    let mut chars = action.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('e') => out.push_str(event),
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// acpid only reads files named like run-parts scripts so that editor
+/// backups and package manager leftovers are ignored.
+fn is_rule_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// A rule file: an event pattern and the shell command to run when an
+/// event matches it.
+struct Rule {
+    file: PathBuf,
+    event: Regex,
+    action: String,
+}
+
+fn parse_rule(file: &Path, contents: &str) -> Result<Rule, RuleError> {
+    let mut event = None;
+    let mut action = None;
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => {
+                return Err(RuleError(format!(
+                    "{}:{}: expected key=value",
+                    file.display(),
+                    lineno + 1
+                )))
+            }
+        };
+        match key {
+            "event" => event = Some(value),
+            "action" => action = Some(value),
+            _ => {
+                return Err(RuleError(format!(
+                    "{}:{}: unknown key {}",
+                    file.display(),
+                    lineno + 1,
+                    key
+                )))
+            }
+        }
+    }
+    let event = event.ok_or_else(|| RuleError(format!("{}: no event key", file.display())))?;
+    let action = action.ok_or_else(|| RuleError(format!("{}: no action key", file.display())))?;
+    Ok(Rule {
+        file: file.to_path_buf(),
+        event: Regex::new(event)
+            .map_err(|e| RuleError(format!("{}: invalid event regex: {}", file.display(), e)))?,
+        action: action.to_string(),
+    })
+}
+
+/// Read every rule file in `dir` in name order. Files that fail to
+/// parse are logged and skipped like acpid does.
+fn load_rules(dir: &Path) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !name.to_str().map(is_rule_file_name).unwrap_or(false) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            continue;
+        }
+        files.push(entry.path());
+    }
+    files.sort();
+
+    let mut rules = Vec::new();
+    for file in files {
+        match fs::read_to_string(&file)
+            .map_err(|e| RuleError(format!("{}: {}", file.display(), e)))
+            .and_then(|contents| parse_rule(&file, &contents))
+        {
+            Ok(rule) => rules.push(rule),
+            Err(e) => println!("Skipping acpid rule: {}", e),
+        }
+    }
+    Ok(rules)
+}
+
+/// The rules of an acpid events directory such as `/etc/acpi/events`.
+pub struct AcpidRules {
+    dir: Box<Path>,
+    rules: RwLock<Arc<Vec<Rule>>>,
+}
+
+impl AcpidRules {
+    pub fn load(dir: &str) -> Result<Self, Box<dyn Error>> {
+        let dir = Path::new(dir);
+        let rules = load_rules(dir)?;
+        println!("Loaded {} acpid rules from {}", rules.len(), dir.display());
+        Ok(AcpidRules {
+            dir: Box::from(dir),
+            rules: RwLock::new(Arc::new(rules)),
+        })
+    }
+
+    /// Read the rules directory again. On failure the current rules
+    /// stay in place.
+    pub fn reload(&self) -> Result<usize, Box<dyn Error>> {
+        let rules = load_rules(&self.dir)?;
+        let count = rules.len();
+        *self.rules.write().expect("Lock poisoned") = Arc::new(rules);
+        Ok(count)
+    }
+
+    /// Run the action of every rule matching the formatted `event` in
+    /// the background.
+    pub fn run(&self, event: &str) {
+        let rules = Arc::clone(&self.rules.read().expect("Lock poisoned"));
+        for rule in rules.iter().filter(|rule| rule.event.is_match(event)) {
+            let action = expand_action(&rule.action, event);
+            let file = rule.file.clone();
+            spawn_blocking(move || {
+                match Command::new("/bin/sh").arg("-c").arg(&action).status() {
+                    Ok(status) if status.success() => (),
+                    Ok(status) => println!(
+                        "Action of acpid rule {} ({}) exited with {}",
+                        file.display(),
+                        action,
+                        status
+                    ),
+                    Err(e) => println!(
+                        "Failed to run action of acpid rule {}: {}",
+                        file.display(),
+                        e
+                    ),
+                }
+            });
+        }
+    }
+}
+
+/// Listening socket that acpid clients such as `acpi_listen` connect
+/// to. Every event is written to every client as a line of text;
+/// anything the clients send is ignored.
+struct AcpidSocket {
+    path: Box<Path>,
+    sender: broadcast::Sender<Arc<str>>,
+}
+
+impl AcpidSocket {
+    /// Bind the socket and start accepting clients. Must be called from
+    /// within the runtime.
+    fn bind(path: &str, mode: u32) -> Result<Self, Box<dyn Error>> {
+        match fs::remove_file(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            res => res?,
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        let (sender, _) = broadcast::channel::<Arc<str>>(SOCKET_BACKLOG);
+        let accept_sender = sender.clone();
+        spawn(async move {
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        println!("Failed to accept acpid socket client: {}", e);
+                        continue;
+                    }
+                };
+                let mut receiver = accept_sender.subscribe();
+                spawn(async move {
+                    loop {
+                        match receiver.recv().await {
+                            Ok(line) => {
+                                if stream.write_all(line.as_bytes()).await.is_err() {
+                                    return;
+                                }
+                            }
+                            Err(RecvError::Lagged(n)) => {
+                                println!("acpid socket client missed {} events", n)
+                            }
+                            Err(RecvError::Closed) => return,
+                        }
+                    }
+                });
+            }
+        });
+        println!("Listening for acpid clients on {}", path);
+        Ok(AcpidSocket {
+            path: Box::from(Path::new(path)),
+            sender,
+        })
+    }
+
+    fn send(&self, event: &str) {
+        // Failing to send only means that no client is connected
+        let _ = self.sender.send(Arc::from(format!("{}\n", event)));
+    }
+}
+
+impl Drop for AcpidSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// acpid compatibility: rules run for and clients notified of every
+/// ACPI event and every input event acpid would report.
+pub struct Acpid {
+    rules: Option<AcpidRules>,
+    socket: Option<AcpidSocket>,
+}
+
+impl Acpid {
+    /// Load the rules and bind the socket configured in `config`. Must
+    /// be called from within the runtime.
+    pub fn new(config: &AcpidConfig) -> Result<Self, Box<dyn Error>> {
+        Ok(Acpid {
+            rules: match config.events_dir {
+                Some(ref dir) => Some(AcpidRules::load(dir)?),
+                None => None,
+            },
+            socket: match config.socket {
+                Some(ref path) => Some(AcpidSocket::bind(path, config.socket_mode)?),
+                None => None,
+            },
+        })
+    }
+
+    pub fn rules(&self) -> Option<&AcpidRules> {
+        self.rules.as_ref()
+    }
+
+    /// Number of socket clients that are sent events
+    #[cfg(test)]
+    pub fn socket_clients(&self) -> usize {
+        self.socket
+            .as_ref()
+            .map(|socket| socket.sender.receiver_count())
+            .unwrap_or_default()
+    }
+
+    fn handle(&self, event: &str) {
+        if let Some(ref socket) = self.socket {
+            socket.send(event);
+        }
+        if let Some(ref rules) = self.rules {
+            rules.run(event);
+        }
+    }
+
+    pub fn handle_acpi(&self, event: &AcpiEvent) {
+        if self.rules.is_some() || self.socket.is_some() {
+            self.handle(&format_event(event));
+        }
+    }
+
+    pub fn handle_evdev(&self, event: &InputEvent) {
+        if let Some(line) = format_input_event(event) {
+            self.handle(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::acpi::{BusId, DeviceClass};
+
+    #[test]
+    fn test_format_event() {
+        let event = AcpiEvent {
+            device_class: DeviceClass("button/lid".to_string()),
+            bus_id: BusId("LID".to_string()),
+            event_type: 0x80,
+            event_data: 1,
+        };
+        assert_eq!(format_event(&event), "button/lid LID 00000080 00000001");
+    }
+
+    #[test]
+    fn test_format_input_event() {
+        let event = |event_type, event_code, event_value| {
+            let mut event = InputEvent::new_buffer([0; std::mem::size_of::<crate::evdev::InputEventStruct>()]);
+            event.set_event_type(event_type);
+            event.set_event_code(event_code);
+            event.set_event_value(event_value);
+            event
+        };
+        assert_eq!(
+            format_input_event(&event(EV_KEY, 116, 1)),
+            Some("button/power PBTN 00000080 00000000")
+        );
+        assert_eq!(format_input_event(&event(EV_KEY, 116, 0)), None);
+        assert_eq!(format_input_event(&event(EV_KEY, 116, 2)), None);
+        assert_eq!(format_input_event(&event(EV_KEY, 30, 1)), None);
+        assert_eq!(format_input_event(&event(EV_SW, 0, 1)), Some("button/lid LID close"));
+        assert_eq!(format_input_event(&event(EV_SW, 0, 0)), Some("button/lid LID open"));
+        assert_eq!(
+            format_input_event(&event(EV_KEY, 152, 1)),
+            Some("button/screenlock SCRNLCK 00000080 00000000")
+        );
+        assert_eq!(
+            format_input_event(&event(EV_SW, 4, 1)),
+            Some("jack/microphone MICROPHONE plug")
+        );
+        assert_eq!(
+            format_input_event(&event(EV_KEY, 227, 1)),
+            Some("video/switchmode VMOD 00000080 00000000")
+        );
+        assert_eq!(format_input_event(&event(EV_SW, 5, 0)), Some("button/dock DOCK undock"));
+        let codes = KEY_EVENTS
+            .iter()
+            .map(|(code, _)| *code)
+            .chain(SWITCH_EVENTS.iter().map(|(code, _, _)| *code))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            vec![113, 114, 115, 116, 142, 143, 148, 152, 205, 224, 225, 227, 236, 238, 0, 1, 2, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_expand_action() {
+        assert_eq!(
+            expand_action("/etc/acpi/lid.sh %e", "button/lid LID 00000080 00000001"),
+            "/etc/acpi/lid.sh button/lid LID 00000080 00000001"
+        );
+        assert_eq!(expand_action("echo 100%% %x%", "ev"), "echo 100% %x%");
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = parse_rule(
+            Path::new("lid"),
+            "# Lid switch\nevent=button/lid.*\naction = /etc/acpi/lid.sh %e\n",
+        )
+        .unwrap();
+        assert!(rule.event.is_match("button/lid LID 00000080 00000001"));
+        assert!(!rule.event.is_match("button/power PBTN 00000080 00000001"));
+        assert_eq!(rule.action, "/etc/acpi/lid.sh %e");
+
+        assert!(parse_rule(Path::new("x"), "event=.*\n").is_err());
+        assert!(parse_rule(Path::new("x"), "event=(\naction=true\n").is_err());
+        assert!(parse_rule(Path::new("x"), "event=.*\naction=true\nfoo=bar\n").is_err());
+    }
+
+    #[test]
+    fn test_is_rule_file_name() {
+        assert!(is_rule_file_name("lid"));
+        assert!(is_rule_file_name("power-btn_1"));
+        assert!(!is_rule_file_name("lid.dpkg-old"));
+        assert!(!is_rule_file_name("lid~"));
+        assert!(!is_rule_file_name(".hidden"));
+    }
+}
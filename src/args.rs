@@ -1,29 +1,252 @@
 use std::{
     env,
     error::Error,
+    path::{Component, Path, PathBuf},
     process,
+    time::Duration,
 };
 
 use getopts::Options;
-use ini::Ini;
+use ini::{ini::Properties, Ini};
+use regex::Regex;
 
-use crate::filter::{AcpiFilter, EvdevFilter};
+use crate::{
+    acpi::DEVICE_CLASSES,
+    codes::{parse_code, parse_type, parse_value},
+    filter::{
+        AcpiFilter, DeviceFilter, DeviceMatcher, EvdevFilter, EvdevRule, MaskPattern, Pattern,
+        Patterns, StringPattern, ThermalFilter,
+    },
+    rule::Expr,
+    thermal::ThermalEventKind,
+    throttle::{RateLimit, ThrottleConfig, ThrottleRule, Window},
+};
+
+/// Where a plugin's code runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Isolation {
+    /// Loaded into the daemon itself
+    InProcess,
+    /// Loaded by a sandboxed worker process
+    Worker { private_network: bool },
+}
+
+#[derive(Clone, Copy)]
+pub struct DaemonConfig {
+    pub watch_plugins: bool,
+    pub isolation: Isolation,
+    pub fatal_code: Option<i32>,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+}
+
+/// Limits on an executable script handler
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScriptConfig {
+    /// Time after which a running script is killed
+    pub timeout: Duration,
+    /// Number of instances of the script that may run at once
+    pub max_concurrent: usize,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        ScriptConfig {
+            timeout: Duration::from_secs(10),
+            max_concurrent: 4,
+        }
+    }
+}
+
+/// sysfs files a WebAssembly handler may access through the host API
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WasmConfig {
+    /// Files or directories that may be read
+    pub sysfs_read: Vec<PathBuf>,
+    /// Files or directories that may be written
+    pub sysfs_write: Vec<PathBuf>,
+}
+
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+impl WasmConfig {
+    fn allowed(allowed: &[PathBuf], path: &Path) -> bool {
+        path.is_absolute()
+            && path
+                .components()
+                .all(|c| c != Component::ParentDir && c != Component::CurDir)
+            && allowed.iter().any(|prefix| path.starts_with(prefix))
+    }
+
+    pub fn can_read(&self, path: &Path) -> bool {
+        // Anything that may be written may also be read back
+        Self::allowed(&self.sysfs_read, path) || Self::allowed(&self.sysfs_write, path)
+    }
+
+    pub fn can_write(&self, path: &Path) -> bool {
+        Self::allowed(&self.sysfs_write, path)
+    }
+}
+
+/// How the handler at a plugin's path is run
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackendKind {
+    /// A shared library implementing the handler ABI
+    Library(Isolation),
+    /// An executable spawned for every event
+    Script(ScriptConfig),
+    /// A WebAssembly module
+    Wasm(WasmConfig),
+}
+
+/// A handler and the events it is interested in
+pub struct PluginSpec {
+    pub name: String,
+    pub path: Box<str>,
+    pub backend: BackendKind,
+    pub acpi: AcpiFilter,
+    pub evdev: EvdevFilter,
+    pub thermal: ThermalFilter,
+}
+
+/// Compatibility with acpid configuration and clients
+pub struct AcpidConfig {
+    /// Directory of acpid rule files, such as `/etc/acpi/events`
+    pub events_dir: Option<Box<str>>,
+    /// Path of the socket acpid clients connect to, such as
+    /// `/var/run/acpid.socket`
+    pub socket: Option<Box<str>>,
+    pub socket_mode: u32,
+}
+
+impl Default for AcpidConfig {
+    fn default() -> Self {
+        AcpidConfig {
+            events_dir: None,
+            socket: None,
+            socket_mode: 0o666,
+        }
+    }
+}
+
+/// A stage of idleness, such as dimming the screen or suspending
+#[derive(Debug, PartialEq)]
+pub struct IdleLevel {
+    pub name: String,
+    /// Time without activity after which the level is entered while on
+    /// AC power; the level is skipped on AC power if unset
+    pub ac_timeout: Option<Duration>,
+    pub battery_timeout: Option<Duration>,
+    /// Shell command run when the level is entered
+    pub action: Option<Box<str>>,
+}
+
+impl IdleLevel {
+    pub fn timeout(&self, on_ac: bool) -> Option<Duration> {
+        if on_ac {
+            self.ac_timeout
+        } else {
+            self.battery_timeout
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct IdleConfig {
+    /// Whether every input event counts as activity rather than only
+    /// the events passing the `[evdev]` whitelist
+    pub reset_on_input: bool,
+    /// Idle levels in the order they are entered
+    pub levels: Vec<IdleLevel>,
+    /// Shell command run on the first activity after a level was entered
+    pub active_action: Option<Box<str>>,
+}
+
+/// What the daemon itself does when the lid is closed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LidAction {
+    Ignore,
+    Suspend,
+    /// Suspend and hibernate if still suspended on battery power after
+    /// `hibernate_delay`
+    SuspendThenHibernate,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SuspendConfig {
+    pub lid_action: LidAction,
+    pub hibernate_delay: Duration,
+    /// Name of the RTC under `/sys/class/rtc` used to wake up
+    pub rtc: Box<str>,
+}
+
+impl Default for SuspendConfig {
+    fn default() -> Self {
+        SuspendConfig {
+            lid_action: LidAction::Ignore,
+            hibernate_delay: Duration::from_secs(3 * 60 * 60),
+            rtc: Box::from("rtc0"),
+        }
+    }
+}
+
+/// Keys reported as a chord when they are all held down
+#[derive(Debug, PartialEq)]
+pub struct Chord {
+    pub name: String,
+    pub keys: Vec<u16>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GestureConfig {
+    /// Keys whose short, long and double presses are reported
+    pub keys: Vec<u16>,
+    pub long_press: Duration,
+    /// Time within which a second press makes a double press. Short
+    /// presses are reported on release if unset.
+    pub double_press: Option<Duration>,
+    pub chords: Vec<Chord>,
+}
+
+impl GestureConfig {
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.chords.is_empty()
+    }
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        GestureConfig {
+            keys: Vec::new(),
+            long_press: Duration::from_millis(1000),
+            double_press: Some(Duration::from_millis(300)),
+            chords: Vec::new(),
+        }
+    }
+}
 
 pub struct CfgFile {
     pub acpi: AcpiFilter,
+    pub acpid: AcpidConfig,
+    pub devices: DeviceFilter,
     pub evdev: EvdevFilter,
-    pub input: bool,
+    pub thermal: ThermalFilter,
+    pub daemon: DaemonConfig,
+    pub plugins: Vec<PluginSpec>,
+    pub timer: IdleConfig,
+    pub suspend: SuspendConfig,
+    pub throttle: ThrottleConfig,
+    pub gestures: GestureConfig,
 }
 
 pub struct PArgs {
-    pub lib_path: Box<str>,
+    pub config_path: Box<str>,
     pub config_file: CfgFile,
 }
 
 pub fn parse_args() -> Result<PArgs, Box<dyn Error>> {
     let mut options = Options::new();
     options
-        .optopt("l", "lib", "LIBRARY_PATH", "Path to plugin library")
+        .optmulti("l", "lib", "LIBRARY_PATH", "Path to plugin library; may be repeated")
         .optopt("c", "config", "CONFIG_PATH", "Path to config file")
         .optflag("h", "help", "Help text");
     let matches = options.parse(env::args())?;
@@ -33,90 +256,1372 @@ pub fn parse_args() -> Result<PArgs, Box<dyn Error>> {
         process::exit(0);
     }
 
-    let c_optstr = matches.opt_str("c");
-    let config_path = c_optstr.as_ref().map(|s| s.as_ref());
-    let cfg = parse_config(config_path.unwrap_or("/etc/pwrsurge/pwrsurge.conf"))?;
+    let config_path = matches
+        .opt_str("c")
+        .map(Box::from)
+        .unwrap_or_else(|| Box::from("/etc/pwrsurge/pwrsurge.conf"));
+    let mut cfg = parse_config(&config_path)?;
+
+    // Libraries given on the command line are dispatched to first and
+    // receive every event that passes the global filters.
+    let isolation = cfg.daemon.isolation;
+    let cli_plugins = matches.opt_strs("l").into_iter().map(|path| PluginSpec {
+        name: path.clone(),
+        path: Box::from(path),
+        backend: BackendKind::Library(isolation),
+        acpi: AcpiFilter::default(),
+        evdev: EvdevFilter::new(Vec::new()),
+        thermal: ThermalFilter::new(Vec::new(), Vec::new()),
+    });
+    cfg.plugins.splice(0..0, cli_plugins);
+    if cfg.plugins.is_empty() {
+        cfg.plugins.push(PluginSpec {
+            name: "default".to_string(),
+            path: Box::from("/usr/lib/pwrsurge/libevents.so"),
+            backend: BackendKind::Library(isolation),
+            acpi: AcpiFilter::default(),
+            evdev: EvdevFilter::new(Vec::new()),
+            thermal: ThermalFilter::new(Vec::new(), Vec::new()),
+        });
+    }
 
-    let mut args = PArgs {
-        lib_path: Box::from(""),
+    Ok(PArgs {
+        config_path,
         config_file: cfg,
+    })
+}
+
+/// Parse a string pattern, which is a regular expression if prefixed
+/// with `re:` and a shell style pattern otherwise.
+fn parse_string_pattern(s: &str) -> Result<StringPattern, Box<dyn Error>> {
+    match s.strip_prefix("re:") {
+        Some(regex) => Ok(StringPattern::Regex(
+            Regex::new(regex).map_err(|e| format!("Invalid regex {}: {}", regex, e))?,
+        )),
+        None => Ok(StringPattern::Glob(s.to_string())),
+    }
+}
+
+/// Parse a number pattern of the form `VALUE` or `VALUE/MASK`, where
+/// both are decimal or hexadecimal with a `0x` prefix.
+fn parse_mask_pattern(s: &str) -> Result<MaskPattern, Box<dyn Error>> {
+    let number = |s: &str| {
+        let s = s.trim();
+        match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse::<u32>(),
+        }
+        .map_err(|e| format!("Invalid number {}: {}", s, e))
+    };
+    let (value, mask) = match s.find('/') {
+        Some(i) => (number(&s[..i])?, number(&s[i + 1..])?),
+        None => (number(s)?, u32::MAX),
+    };
+    Ok(MaskPattern { value, mask })
+}
+
+/// Parse the `KEY_whitelist` and `KEY_blacklist` keys of a section
+fn parse_patterns<P>(
+    section: &Properties,
+    key: &str,
+    parse: fn(&str) -> Result<P, Box<dyn Error>>,
+) -> Result<Patterns<P>, Box<dyn Error>>
+where
+    P: Pattern,
+{
+    let list = |suffix: &str| {
+        let key = format!("{}_{}", key, suffix);
+        section
+            .get(&key)
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .split(",")
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| parse(s).map_err(|e| format!("Invalid {}: {}", key, e).into()))
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()
+    };
+    Ok(Patterns::new(list("whitelist")?, list("blacklist")?))
+}
+
+fn parse_acpi_section(acpi: &Properties) -> Result<AcpiFilter, Box<dyn Error>> {
+    // Vendor drivers use classes of their own, so unknown classes are
+    // only worth a warning
+    let classes = acpi
+        .get("device_class_whitelist")
+        .map(|s| s.as_str())
+        .unwrap_or_default()
+        .split(",")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty() && !s.starts_with("re:") && !s.contains(&['*', '?'][..]));
+    for class in classes.filter(|c| !DEVICE_CLASSES.contains(c)) {
+        println!(
+            "ACPI device class {} in device_class_whitelist is not one of {}",
+            class,
+            DEVICE_CLASSES.join(", ")
+        );
+    }
+    Ok(AcpiFilter::new(
+        parse_patterns(acpi, "device_class", parse_string_pattern)?,
+        parse_patterns(acpi, "bus_id", parse_string_pattern)?,
+        parse_patterns(acpi, "acpi_event_type", parse_mask_pattern)?,
+        parse_patterns(acpi, "acpi_event_data", parse_mask_pattern)?,
+    ))
+}
+
+fn parse_thermal_section(thermal: &Properties) -> Result<ThermalFilter, Box<dyn Error>> {
+    let list = |key: &str| {
+        thermal
+            .get(key)
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .split(",")
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
     };
+    let events = list("thermal_event_whitelist")
+        .into_iter()
+        .map(|name| {
+            ThermalEventKind::from_name(name)
+                .ok_or_else(|| format!("Unknown thermal event {}", name).into())
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    let types = list("thermal_type_whitelist")
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+    Ok(ThermalFilter::new(events, types))
+}
+
+pub fn parse_thermal_config(ini: &Ini) -> Result<ThermalFilter, Box<dyn Error>> {
+    match ini.section(Some("thermal")) {
+        Some(thermal) => parse_thermal_section(thermal),
+        _ => Ok(ThermalFilter::new(Vec::new(), Vec::new())),
+    }
+}
+
+pub fn parse_acpi_config(ini: &Ini) -> Result<AcpiFilter, Box<dyn Error>> {
+    match ini.section(Some("acpi")) {
+        Some(acpi) => parse_acpi_section(acpi),
+        _ => Ok(AcpiFilter::default()),
+    }
+}
+
+/// Parse the `rule.NAME` keys of an evdev filter section. The flat
+/// type, code and value whitelists are turned into one more rule.
+/// Only the global `[evdev]` section may route rules to plugins.
+fn parse_evdev_section(evdev: &Properties, routing: bool) -> Result<EvdevFilter, Box<dyn Error>> {
+    fn list<T>(
+        evdev: &Properties,
+        key: &str,
+        parse: fn(&str) -> Result<T, String>,
+    ) -> Result<Vec<T>, Box<dyn Error>> {
+        evdev
+            .get(key)
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .split(",")
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| parse(s).map_err(|e| format!("Invalid {}: {}", key, e).into()))
+            .collect()
+    }
+    let type_whitelist = list(evdev, "event_type_whitelist", parse_type)?;
+    let code_whitelist = list(evdev, "event_code_whitelist", parse_code)?;
+    let value_whitelist = list(evdev, "event_value_whitelist", parse_value)?;
+    let mut rules = evdev
+        .iter()
+        .filter_map(|(key, value)| key.strip_prefix("rule.").map(|name| (name, value)))
+        .map(|(name, value)| EvdevRule::parse(name, value))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(rule) = rules.iter().find(|rule| !routing && !rule.plugins.is_empty()) {
+        return Err(format!(
+            "Rule {} can only route events to plugins in the [evdev] section",
+            rule.name
+        )
+        .into());
+    }
+    // Properties are not ordered
+    rules.sort_by(|a, b| a.name.cmp(&b.name));
+    rules.extend(EvdevRule::from_whitelists(
+        &type_whitelist,
+        &code_whitelist,
+        &value_whitelist,
+    ));
+    Ok(EvdevFilter::new(rules))
+}
 
-    args.lib_path = matches
-        .opt_str("l")
-        .map(|s| Box::from(s))
-        .unwrap_or(Box::from("/usr/lib/pwrsurge/libevents.so"));
-    Ok(args)
+pub fn parse_evdev_config(ini: &Ini) -> Result<EvdevFilter, Box<dyn Error>> {
+    match ini.section(Some("evdev")) {
+        Some(evdev) => parse_evdev_section(evdev, true),
+        _ => Ok(EvdevFilter::new(Vec::new())),
+    }
 }
 
-pub fn parse_acpi_config(ini: &Ini) -> AcpiFilter {
-    let vec = match ini.section(Some("acpi")) {
-        Some(acpi) => {
-            let whitelist = acpi.get("device_class_whitelist").map(|s| s.to_owned());
-            match whitelist {
-                Some(wl) => wl
-                    .split(",")
-                    .filter_map(|s| if s == "" { None } else { Some(s.to_string()) })
-                    .collect::<Vec<String>>(),
-                _ => Vec::new(),
+/// Parse the `[plugins]` section, whose `order` key lists the plugins to
+/// load in dispatch order, and the `[plugin.NAME]` section of each.
+pub fn parse_plugins_config(
+    ini: &Ini,
+    default_isolation: Isolation,
+) -> Result<Vec<PluginSpec>, Box<dyn Error>> {
+    let order = ini
+        .section(Some("plugins"))
+        .and_then(|plugins| plugins.get("order"))
+        .map(|s| s.to_owned())
+        .unwrap_or_default();
+    let names = order
+        .split(",")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+
+    for section in ini.sections().filter_map(|s| s.as_ref()) {
+        if let Some(name) = section.strip_prefix("plugin.") {
+            if !names.contains(&name) {
+                return Err(format!(
+                    "Section [{}] is not listed in the order key of the [plugins] section",
+                    section
+                )
+                .into());
+            }
+        }
+    }
+
+    let mut specs = Vec::new();
+    for name in names {
+        let section_name = format!("plugin.{}", name);
+        let section = ini
+            .section(Some(section_name.as_str()))
+            .ok_or_else(|| format!("Plugin {} has no [{}] section", name, section_name))?;
+        let keys = ["path", "script", "wasm"]
+            .iter()
+            .filter_map(|key| section.get(*key).map(|value| (*key, value)))
+            .collect::<Vec<_>>();
+        let (path, backend) = match keys.as_slice() {
+            [("path", path)] => (
+                path,
+                BackendKind::Library(parse_isolation(section, default_isolation)?),
+            ),
+            [("script", script)] => (script, BackendKind::Script(parse_script_config(section)?)),
+            [("wasm", module)] => (module, BackendKind::Wasm(parse_wasm_config(section)?)),
+            [] => {
+                return Err(format!(
+                    "Section [{}] has no path, script or wasm key",
+                    section_name
+                )
+                .into())
+            }
+            _ => {
+                return Err(format!(
+                    "Section [{}] may only have one of the path, script and wasm keys",
+                    section_name
+                )
+                .into())
             }
+        };
+        specs.push(PluginSpec {
+            name: name.to_string(),
+            path: Box::from(path.as_str()),
+            backend,
+            acpi: parse_acpi_section(section)?,
+            evdev: parse_evdev_section(section, false)?,
+            thermal: parse_thermal_section(section)?,
+        });
+    }
+    Ok(specs)
+}
+
+/// Parse a device matcher of the form `KIND:VALUE`, such as
+/// `name:Lid Switch`, `vendor:046d` or `sw:0`.
+fn parse_device_matcher(s: &str) -> Result<DeviceMatcher, Box<dyn Error>> {
+    let (kind, value) = match s.find(':') {
+        Some(i) => (s[..i].trim(), s[i + 1..].trim()),
+        None => return Err(format!("Invalid device matcher {}; expected KIND:VALUE", s).into()),
+    };
+    let hex = |value: &str| {
+        u16::from_str_radix(value, 16)
+            .map_err(|e| format!("Invalid {} {} in device matcher: {}", kind, value, e))
+    };
+    let code = |value: &str, parse: fn(&str) -> Result<u16, String>| {
+        parse(value).map_err(|e| format!("Invalid {} in device matcher: {}", kind, e))
+    };
+    Ok(match kind {
+        "name" => DeviceMatcher::Name(value.to_string()),
+        "phys" => DeviceMatcher::Phys(value.to_string()),
+        "sysfs" => DeviceMatcher::Sysfs(value.to_string()),
+        "bus" => DeviceMatcher::Bus(hex(value)?),
+        "vendor" => DeviceMatcher::Vendor(hex(value)?),
+        "product" => DeviceMatcher::Product(hex(value)?),
+        "ev" => DeviceMatcher::Ev(code(value, parse_type)?),
+        "key" => DeviceMatcher::Key(code(value, parse_code)?),
+        "sw" => DeviceMatcher::Sw(code(value, parse_code)?),
+        _ => return Err(format!("Unknown device matcher kind {}", kind).into()),
+    })
+}
+
+fn parse_device_matchers(value: Option<&String>) -> Result<Vec<DeviceMatcher>, Box<dyn Error>> {
+    value
+        .map(|s| s.as_str())
+        .unwrap_or_default()
+        .split(",")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(parse_device_matcher)
+        .collect()
+}
+
+/// Collect the `match` matchers of every `[device.NAME]` section with
+/// `grab = true`.
+fn parse_device_sections(ini: &Ini) -> Result<Vec<DeviceMatcher>, Box<dyn Error>> {
+    let mut grab = Vec::new();
+    for (name, section) in ini {
+        let name = match name.as_ref().and_then(|n| n.strip_prefix("device.")) {
+            Some(name) => name,
+            None => continue,
+        };
+        let matchers = parse_device_matchers(section.get("match"))?;
+        if matchers.is_empty() {
+            return Err(format!("Device section {} needs a match key", name).into());
+        }
+        let grab_device = match section.get("grab") {
+            Some(v) => v
+                .parse::<bool>()
+                .map_err(|e| format!("Invalid grab {} for device {}: {}", v, name, e))?,
+            None => false,
+        };
+        if grab_device {
+            grab.extend(matchers);
+        }
+    }
+    Ok(grab)
+}
+
+/// Parse the `include` and `exclude` keys of the `[devices]` section,
+/// which select the input devices that are opened.
+pub fn parse_devices_config(ini: &Ini) -> Result<DeviceFilter, Box<dyn Error>> {
+    let grab = parse_device_sections(ini)?;
+    match ini.section(Some("devices")) {
+        Some(devices) => Ok(DeviceFilter::new(
+            parse_device_matchers(devices.get("include"))?,
+            parse_device_matchers(devices.get("exclude"))?,
+            grab,
+        )),
+        _ => Ok(DeviceFilter::new(Vec::new(), Vec::new(), grab)),
+    }
+}
+
+pub fn parse_acpid_config(ini: &Ini) -> Result<AcpidConfig, Box<dyn Error>> {
+    let mut config = AcpidConfig::default();
+    if let Some(acpid) = ini.section(Some("acpid")) {
+        config.events_dir = acpid.get("events_dir").map(|s| Box::from(s.as_str()));
+        config.socket = acpid.get("socket").map(|s| Box::from(s.as_str()));
+        if let Some(mode) = acpid.get("socket_mode") {
+            config.socket_mode = u32::from_str_radix(mode, 8)
+                .map_err(|e| format!("Invalid socket_mode {}: {}", mode, e))?;
         }
-        _ => Vec::new(),
+    }
+    Ok(config)
+}
+
+fn parse_timeout_secs(section: &Properties, key: &str) -> Result<Option<Duration>, Box<dyn Error>> {
+    section
+        .get(key)
+        .map(|secs| {
+            secs.parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|e| format!("Invalid {} {}: {}", key, secs, e).into())
+        })
+        .transpose()
+}
+
+/// Parse the `[timer]` section and the `[timer.NAME]` section of each
+/// idle level listed in its `levels` key.
+pub fn parse_timer_config(ini: &Ini) -> Result<IdleConfig, Box<dyn Error>> {
+    let timer = match ini.section(Some("timer")) {
+        Some(timer) => timer,
+        None => return Ok(IdleConfig::default()),
     };
-    AcpiFilter::new(vec)
+    let reset_on_input = timer
+        .get("reset_on_input")
+        .map(|v| v.parse::<bool>().unwrap_or(false))
+        .unwrap_or(false);
+    let mut levels = Vec::new();
+    for name in timer
+        .get("levels")
+        .map(|s| s.as_str())
+        .unwrap_or_default()
+        .split(",")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+    {
+        let section_name = format!("timer.{}", name);
+        let section = ini
+            .section(Some(section_name.as_str()))
+            .ok_or_else(|| format!("Idle level {} has no [{}] section", name, section_name))?;
+        let level = IdleLevel {
+            name: name.to_string(),
+            ac_timeout: parse_timeout_secs(section, "ac_timeout")?,
+            battery_timeout: parse_timeout_secs(section, "battery_timeout")?,
+            action: section.get("action").map(|s| Box::from(s.as_str())),
+        };
+        if level.ac_timeout.is_none() && level.battery_timeout.is_none() {
+            return Err(format!(
+                "Idle level {} needs an ac_timeout, a battery_timeout or both",
+                name
+            )
+            .into());
+        }
+        levels.push(level);
+    }
+    Ok(IdleConfig {
+        reset_on_input,
+        levels,
+        active_action: timer.get("active_action").map(|s| Box::from(s.as_str())),
+    })
 }
 
-pub fn parse_evdev_config(ini: &Ini) -> EvdevFilter {
-    match ini.section(Some("evdev")) {
-        Some(evdev) => {
-            let type_whitelist = evdev
-                .get("event_type_whitelist")
-                .map(|s| s.to_owned())
-                .unwrap_or(String::new())
-                .split(",")
-                .filter_map(|s| s.parse::<u16>().ok())
-                .collect::<Vec<_>>();
-            let code_whitelist = evdev
-                .get("event_code_whitelist")
-                .map(|s| s.to_owned())
-                .unwrap_or(String::new())
-                .split(",")
-                .filter_map(|s| s.parse::<u16>().ok())
-                .collect::<Vec<_>>();
-            let value_whitelist = evdev
-                .get("event_value_whitelist")
-                .map(|s| s.to_owned())
-                .unwrap_or(String::new())
-                .split(",")
-                .filter_map(|s| s.parse::<i32>().ok())
-                .collect::<Vec<_>>();
-            EvdevFilter::new(type_whitelist, code_whitelist, value_whitelist)
-        }
-        _ => EvdevFilter::new(Vec::new(), Vec::new(), Vec::new()),
-    }
-}
-
-pub fn parse_timer_config(ini: &Ini) -> bool {
-    match ini.section(Some("timer")) {
-        Some(timer) => {
-            let input = timer
-                .get("reset_on_input")
-                .map(|v| v.parse::<bool>().unwrap_or(false))
-                .unwrap_or(false);
-            input
-        }
-        _ => false,
+pub fn parse_suspend_config(ini: &Ini) -> Result<SuspendConfig, Box<dyn Error>> {
+    let mut config = SuspendConfig::default();
+    let suspend = match ini.section(Some("suspend")) {
+        Some(suspend) => suspend,
+        None => return Ok(config),
+    };
+    config.lid_action = match suspend.get("lid_action").map(|s| s.as_str()) {
+        Some("ignore") | None => LidAction::Ignore,
+        Some("suspend") => LidAction::Suspend,
+        Some("suspend_then_hibernate") => LidAction::SuspendThenHibernate,
+        Some(other) => {
+            return Err(format!(
+                "Invalid lid_action {}; expected ignore, suspend or suspend_then_hibernate",
+                other
+            )
+            .into())
+        }
+    };
+    if let Some(delay) = parse_timeout_secs(suspend, "hibernate_delay")? {
+        if delay.as_secs() == 0 {
+            return Err("hibernate_delay must be at least one second".into());
+        }
+        config.hibernate_delay = delay;
     }
+    if let Some(rtc) = suspend.get("rtc") {
+        if rtc.is_empty() || rtc.contains('/') {
+            return Err(format!("Invalid rtc {}", rtc).into());
+        }
+        config.rtc = Box::from(rtc.as_str());
+    }
+    Ok(config)
+}
+
+fn parse_throttle_section<M>(
+    name: &str,
+    section: &Properties,
+    matcher: M,
+) -> Result<ThrottleRule<M>, Box<dyn Error>> {
+    let millis = |key: &str| -> Result<Option<Duration>, Box<dyn Error>> {
+        section
+            .get(key)
+            .map(|ms| match ms.parse::<u64>() {
+                Ok(0) => Err(format!("{} of throttle rule {} must be positive", key, name).into()),
+                Ok(ms) => Ok(Duration::from_millis(ms)),
+                Err(e) => Err(format!("Invalid {} {}: {}", key, ms, e).into()),
+            })
+            .transpose()
+    };
+    let window = match (millis("debounce_ms")?, millis("coalesce_ms")?) {
+        (Some(_), Some(_)) => {
+            return Err(format!(
+                "Throttle rule {} can either debounce or coalesce events, not both",
+                name
+            )
+            .into())
+        }
+        (Some(window), None) => Some(Window::Debounce(window)),
+        (None, Some(window)) => Some(Window::Coalesce(window)),
+        (None, None) => None,
+    };
+    let burst = section
+        .get("burst")
+        .map(|burst| {
+            burst
+                .parse::<u32>()
+                .ok()
+                .filter(|burst| *burst > 0)
+                .ok_or_else(|| format!("Invalid burst {}", burst))
+        })
+        .transpose()?;
+    let rate_limit = match (section.get("rate"), burst) {
+        (Some(rate), burst) => {
+            let rate = rate
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| rate.is_finite() && *rate > 0.0)
+                .ok_or_else(|| format!("Invalid rate {}", rate))?;
+            Some(RateLimit {
+                rate,
+                burst: burst.unwrap_or(1),
+            })
+        }
+        (None, Some(_)) => {
+            return Err(format!("Throttle rule {} has a burst but no rate", name).into())
+        }
+        (None, None) => None,
+    };
+    if window.is_none() && rate_limit.is_none() {
+        return Err(format!(
+            "Throttle rule {} needs a debounce_ms, a coalesce_ms or a rate",
+            name
+        )
+        .into());
+    }
+    Ok(ThrottleRule {
+        name: name.to_string(),
+        matcher,
+        window,
+        rate_limit,
+    })
+}
+
+/// Parse the `[throttle.NAME]` sections, which debounce, coalesce and
+/// rate limit the ACPI or evdev events they match.
+pub fn parse_throttle_config(ini: &Ini) -> Result<ThrottleConfig, Box<dyn Error>> {
+    let mut config = ThrottleConfig {
+        acpi: Vec::new(),
+        evdev: Vec::new(),
+    };
+    for (name, section) in ini {
+        let name = match name.as_ref().and_then(|n| n.strip_prefix("throttle.")) {
+            Some(name) => name,
+            None => continue,
+        };
+        match section.get("events").map(|s| s.as_str()) {
+            Some("acpi") => {
+                let filter = parse_acpi_section(section)?;
+                config.acpi.push(parse_throttle_section(name, section, filter)?);
+            }
+            Some("evdev") => {
+                let expr = match section.get("match") {
+                    Some(expr) => Expr::parse(expr)?,
+                    None => Expr::And(Vec::new()),
+                };
+                config.evdev.push(parse_throttle_section(name, section, expr)?);
+            }
+            _ => {
+                return Err(format!(
+                    "Throttle rule {} needs events = acpi or events = evdev",
+                    name
+                )
+                .into())
+            }
+        }
+    }
+    // Sections are not ordered
+    config.acpi.sort_by(|a, b| a.name.cmp(&b.name));
+    config.evdev.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(config)
+}
+
+/// Parse the `[gestures]` section, which lists the keys and chords
+/// gesture events are reported for.
+pub fn parse_gestures_config(ini: &Ini) -> Result<GestureConfig, Box<dyn Error>> {
+    let mut config = GestureConfig::default();
+    let gestures = match ini.section(Some("gestures")) {
+        Some(gestures) => gestures,
+        None => return Ok(config),
+    };
+    let millis = |key: &str| -> Result<Option<u64>, Box<dyn Error>> {
+        gestures
+            .get(key)
+            .map(|ms| {
+                ms.parse::<u64>()
+                    .map_err(|e| format!("Invalid {} {}: {}", key, ms, e).into())
+            })
+            .transpose()
+    };
+    config.keys = gestures
+        .get("keys")
+        .map(|s| s.as_str())
+        .unwrap_or_default()
+        .split(",")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| parse_code(s).map_err(|e| format!("Invalid keys: {}", e).into()))
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    match millis("long_press_ms")? {
+        Some(0) => return Err("long_press_ms must be positive".into()),
+        Some(ms) => config.long_press = Duration::from_millis(ms),
+        None => (),
+    }
+    match millis("double_press_ms")? {
+        Some(0) => config.double_press = None,
+        Some(ms) => config.double_press = Some(Duration::from_millis(ms)),
+        None => (),
+    }
+    for (key, value) in gestures.iter() {
+        let name = match key.strip_prefix("chord.") {
+            Some(name) => name,
+            None => continue,
+        };
+        let keys = value
+            .split("+")
+            .map(|s| parse_code(s.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid chord {}: {}", name, e))?;
+        if keys.len() < 2 {
+            return Err(format!("Chord {} needs at least two keys", name).into());
+        }
+        config.chords.push(Chord {
+            name: name.to_string(),
+            keys,
+        });
+    }
+    // Properties are not ordered
+    config.chords.sort_by(|a, b| a.name.cmp(&b.name));
+    // Chords are told apart by the code of their last key
+    for (i, chord) in config.chords.iter().enumerate() {
+        let last = chord.keys.last();
+        if let Some(other) = config.chords[i + 1..].iter().find(|c| c.keys.last() == last) {
+            return Err(format!(
+                "Chords {} and {} end in the same key",
+                chord.name, other.name
+            )
+            .into());
+        }
+    }
+    Ok(config)
+}
+
+/// Parse the `isolation` and `private_network` keys of a section,
+/// falling back to `default` for anything that is not set.
+fn parse_isolation(section: &Properties, default: Isolation) -> Result<Isolation, Box<dyn Error>> {
+    let default_private_network = match default {
+        Isolation::Worker { private_network } => private_network,
+        Isolation::InProcess => false,
+    };
+    let private_network = match section.get("private_network") {
+        Some(v) => v
+            .parse::<bool>()
+            .map_err(|e| format!("Invalid private_network {}: {}", v, e))?,
+        None => default_private_network,
+    };
+    match section.get("isolation").map(|s| s.as_str()) {
+        Some("none") => Ok(Isolation::InProcess),
+        Some("worker") => Ok(Isolation::Worker { private_network }),
+        Some(other) => Err(format!(
+            "Invalid isolation {}; expected none or worker",
+            other
+        )
+        .into()),
+        None => Ok(match default {
+            Isolation::InProcess => Isolation::InProcess,
+            Isolation::Worker { .. } => Isolation::Worker { private_network },
+        }),
+    }
+}
+
+/// Parse the `timeout_ms` and `max_concurrent` keys of a script
+/// plugin's section.
+fn parse_script_config(section: &Properties) -> Result<ScriptConfig, Box<dyn Error>> {
+    let mut config = ScriptConfig::default();
+    if let Some(timeout) = section.get("timeout_ms") {
+        config.timeout = Duration::from_millis(
+            timeout
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid timeout_ms {}: {}", timeout, e))?,
+        );
+    }
+    if let Some(max) = section.get("max_concurrent") {
+        config.max_concurrent = match max.parse::<usize>() {
+            Ok(0) => return Err("max_concurrent must be at least 1".into()),
+            Ok(n) => n,
+            Err(e) => return Err(format!("Invalid max_concurrent {}: {}", max, e).into()),
+        };
+    }
+    Ok(config)
+}
+
+/// Parse a comma separated list of sysfs paths, rejecting anything
+/// that is not an absolute path below `/sys`.
+fn parse_sysfs_paths(key: &str, value: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    value
+        .split(",")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let path = PathBuf::from(s);
+            if path.starts_with("/sys")
+                && path
+                    .components()
+                    .all(|c| c != Component::ParentDir && c != Component::CurDir)
+            {
+                Ok(path)
+            } else {
+                Err(format!("Invalid {} path {}; expected a path below /sys", key, s).into())
+            }
+        })
+        .collect()
+}
+
+/// Parse the `sysfs_read` and `sysfs_write` keys of a WebAssembly
+/// plugin's section.
+fn parse_wasm_config(section: &Properties) -> Result<WasmConfig, Box<dyn Error>> {
+    let mut config = WasmConfig::default();
+    if let Some(read) = section.get("sysfs_read") {
+        config.sysfs_read = parse_sysfs_paths("sysfs_read", read)?;
+    }
+    if let Some(write) = section.get("sysfs_write") {
+        config.sysfs_write = parse_sysfs_paths("sysfs_write", write)?;
+    }
+    Ok(config)
+}
+
+/// Upper bound of `retry_backoff_ms` and of the backoff it doubles
+/// into with every retry
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+pub fn parse_daemon_config(ini: &Ini) -> Result<DaemonConfig, Box<dyn Error>> {
+    let mut config = DaemonConfig {
+        watch_plugins: false,
+        isolation: Isolation::InProcess,
+        fatal_code: None,
+        max_retries: 3,
+        retry_backoff: Duration::from_millis(100),
+    };
+    if let Some(daemon) = ini.section(Some("daemon")) {
+        config.watch_plugins = daemon
+            .get("watch_plugins")
+            .map(|v| v.parse::<bool>().unwrap_or(false))
+            .unwrap_or(false);
+        config.isolation = parse_isolation(daemon, config.isolation)?;
+        if let Some(code) = daemon.get("fatal_code") {
+            config.fatal_code = Some(
+                code.parse::<i32>()
+                    .map_err(|e| format!("Invalid fatal_code {}: {}", code, e))?,
+            );
+        }
+        if let Some(retries) = daemon.get("max_retries") {
+            config.max_retries = retries
+                .parse::<u32>()
+                .map_err(|e| format!("Invalid max_retries {}: {}", retries, e))?;
+        }
+        if let Some(backoff) = daemon.get("retry_backoff_ms") {
+            let ms = backoff
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid retry_backoff_ms {}: {}", backoff, e))?;
+            let backoff = Duration::from_millis(ms);
+            if backoff > MAX_RETRY_BACKOFF {
+                return Err(format!(
+                    "retry_backoff_ms {} is more than {}",
+                    ms,
+                    MAX_RETRY_BACKOFF.as_millis()
+                )
+                .into());
+            }
+            config.retry_backoff = backoff;
+        }
+    }
+    Ok(config)
 }
 
 pub fn parse_config(config_path: &str) -> Result<CfgFile, Box<dyn Error>> {
     let ini = Ini::load_from_file(config_path)?;
-    let acpi_section = parse_acpi_config(&ini);
-    let evdev_section = parse_evdev_config(&ini);
-    let input = parse_timer_config(&ini);
+    let acpi_section = parse_acpi_config(&ini)?;
+    let acpid = parse_acpid_config(&ini)?;
+    let devices = parse_devices_config(&ini)?;
+    let evdev_section = parse_evdev_config(&ini)?;
+    let thermal = parse_thermal_config(&ini)?;
+    let timer = parse_timer_config(&ini)?;
+    let suspend = parse_suspend_config(&ini)?;
+    let throttle = parse_throttle_config(&ini)?;
+    let gestures = parse_gestures_config(&ini)?;
+    let daemon = parse_daemon_config(&ini)?;
+    let plugins = parse_plugins_config(&ini, daemon.isolation)?;
     Ok(CfgFile {
         acpi: acpi_section,
+        acpid,
+        devices,
         evdev: evdev_section,
-        input,
+        thermal,
+        daemon,
+        plugins,
+        timer,
+        suspend,
+        throttle,
+        gestures,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        acpi::{AcpiEvent, BusId, DeviceClass},
+        evdev::{DeviceInfo, InputEvent},
+        thermal::{CoolingDeviceEvent, ThermalEvent, TripEvent},
+    };
+
+    #[test]
+    fn test_parse_plugins_config() {
+        let ini = Ini::load_from_str(
+            "[plugins]
+order = lid, battery
+
+[plugin.battery]
+path = /usr/lib/pwrsurge/libbattery.so
+device_class_whitelist = battery,ac_adapter
+
+[plugin.lid]
+path = /usr/lib/pwrsurge/liblid.so
+event_type_whitelist = 5
+",
+        )
+        .unwrap();
+        let plugins = parse_plugins_config(&ini, Isolation::InProcess).unwrap();
+        assert_eq!(
+            plugins.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+            vec!["lid", "battery"]
+        );
+        assert_eq!(&*plugins[1].path, "/usr/lib/pwrsurge/libbattery.so");
+        assert!(plugins[0].acpi.is_wildcard());
+        assert!(!plugins[0].evdev.is_wildcard());
+        assert!(plugins[1].acpi.contains_device_class("ac_adapter"));
+    }
+
+    #[test]
+    fn test_parse_acpi_config() {
+        let ini = Ini::load_from_str(
+            "[acpi]
+device_class_whitelist = button/*, re:^ac_adapter$
+bus_id_blacklist = PBTN
+acpi_event_type_whitelist = 0x80/0xf0
+acpi_event_data_blacklist = 0
+",
+        )
+        .unwrap();
+        let filter = parse_acpi_config(&ini).unwrap();
+        let event = |class: &str, bus_id: &str, event_type, event_data| AcpiEvent {
+            device_class: DeviceClass(class.to_string()),
+            bus_id: BusId(bus_id.to_string()),
+            event_type,
+            event_data,
+        };
+        assert!(filter.matches(&event("button/lid", "LID0", 0x80, 1)));
+        assert!(filter.matches(&event("ac_adapter", "ACPI0003:00", 0x81, 1)));
+        assert!(!filter.matches(&event("ac_adapter", "ACPI0003:00", 0x80, 0)));
+        assert!(!filter.matches(&event("button/power", "PBTN", 0x80, 1)));
+        assert!(!filter.matches(&event("battery", "PNP0C0A:00", 0x80, 1)));
+
+        assert_eq!(
+            parse_mask_pattern("0x80").unwrap(),
+            MaskPattern {
+                value: 0x80,
+                mask: u32::MAX
+            }
+        );
+        assert_eq!(
+            parse_mask_pattern("1/0x1").unwrap(),
+            MaskPattern { value: 1, mask: 1 }
+        );
+        for invalid in &["", "0x", "1/", "lid", "-1"] {
+            assert!(parse_mask_pattern(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_acpi_config(
+            &Ini::load_from_str("[acpi]\ndevice_class_whitelist = re:(\n").unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_evdev_config() {
+        let ini = Ini::load_from_str(
+            "[evdev]
+rule.lid = type=5 && code=0 -> lid
+rule.power = type=1 && code=116 && value=1
+event_type_whitelist = 4
+
+[plugin.lid]
+rule.open = value=0
+",
+        )
+        .unwrap();
+        let filter = parse_evdev_config(&ini).unwrap();
+        assert!(filter.matches(&InputEvent::synthetic(5, 0, 1)));
+        assert!(filter.matches(&InputEvent::synthetic(1, 116, 1)));
+        assert!(filter.matches(&InputEvent::synthetic(4, 4, 30)));
+        assert!(!filter.matches(&InputEvent::synthetic(1, 0, 1)));
+        assert_eq!(filter.routes().collect::<Vec<_>>(), vec![("lid", "lid")]);
+
+        let plugin = ini.section(Some("plugin.lid")).unwrap();
+        let filter = parse_evdev_section(plugin, false).unwrap();
+        assert!(filter.matches(&InputEvent::synthetic(5, 0, 0)));
+        assert!(!filter.matches(&InputEvent::synthetic(5, 0, 1)));
+
+        let routed = Ini::load_from_str("[plugin.lid]\nrule.lid = type=5 -> lid\n").unwrap();
+        let plugin = routed.section(Some("plugin.lid")).unwrap();
+        assert!(parse_evdev_section(plugin, false).is_err());
+
+        let invalid = Ini::load_from_str("[evdev]\nrule.lid = type=5 &&\n").unwrap();
+        assert!(parse_evdev_config(&invalid).is_err());
+
+        let names = Ini::load_from_str(
+            "[evdev]\nevent_type_whitelist = EV_KEY\nevent_code_whitelist = KEY_POWER, 0x8e\n",
+        )
+        .unwrap();
+        let filter = parse_evdev_config(&names).unwrap();
+        assert!(filter.matches(&InputEvent::synthetic(1, 116, 1)));
+        assert!(filter.matches(&InputEvent::synthetic(1, 142, 1)));
+        assert!(!filter.matches(&InputEvent::synthetic(1, 117, 1)));
+
+        // Used to be dropped silently, leaving a wildcard
+        let typo = Ini::load_from_str("[evdev]\nevent_code_whitelist = KEY_POWR\n").unwrap();
+        assert!(parse_evdev_config(&typo).is_err());
+    }
+
+    #[test]
+    fn test_parse_plugins_config_errors() {
+        let unlisted = Ini::load_from_str("[plugin.lid]\npath = liblid.so\n").unwrap();
+        assert!(parse_plugins_config(&unlisted, Isolation::InProcess).is_err());
+
+        let missing = Ini::load_from_str("[plugins]\norder = lid\n").unwrap();
+        assert!(parse_plugins_config(&missing, Isolation::InProcess).is_err());
+
+        let no_path = Ini::load_from_str("[plugins]\norder = lid\n[plugin.lid]\n").unwrap();
+        assert!(parse_plugins_config(&no_path, Isolation::InProcess).is_err());
+    }
+
+    #[test]
+    fn test_parse_isolation() {
+        let ini = Ini::load_from_str(
+            "[plugins]
+order = lid, battery, ac
+
+[plugin.lid]
+path = liblid.so
+isolation = none
+
+[plugin.battery]
+path = libbattery.so
+private_network = true
+
+[plugin.ac]
+path = libac.so
+",
+        )
+        .unwrap();
+        let default = Isolation::Worker {
+            private_network: false,
+        };
+        let plugins = parse_plugins_config(&ini, default).unwrap();
+        assert_eq!(plugins[0].backend, BackendKind::Library(Isolation::InProcess));
+        assert_eq!(
+            plugins[1].backend,
+            BackendKind::Library(Isolation::Worker {
+                private_network: true
+            })
+        );
+        assert_eq!(plugins[2].backend, BackendKind::Library(default));
+
+        let invalid = Ini::load_from_str("[plugins]\norder = lid\n[plugin.lid]\npath = liblid.so\nisolation = jail\n").unwrap();
+        assert!(parse_plugins_config(&invalid, Isolation::InProcess).is_err());
+    }
+
+    #[test]
+    fn test_parse_script_config() {
+        let ini = Ini::load_from_str(
+            "[plugins]
+order = lid, battery
+
+[plugin.lid]
+script = /etc/pwrsurge/lid.sh
+timeout_ms = 500
+max_concurrent = 1
+
+[plugin.battery]
+script = /etc/pwrsurge/battery.sh
+",
+        )
+        .unwrap();
+        let plugins = parse_plugins_config(&ini, Isolation::InProcess).unwrap();
+        assert_eq!(&*plugins[0].path, "/etc/pwrsurge/lid.sh");
+        assert_eq!(
+            plugins[0].backend,
+            BackendKind::Script(ScriptConfig {
+                timeout: Duration::from_millis(500),
+                max_concurrent: 1,
+            })
+        );
+        assert_eq!(plugins[1].backend, BackendKind::Script(ScriptConfig::default()));
+
+        let both = Ini::load_from_str("[plugins]\norder = lid\n[plugin.lid]\npath = liblid.so\nscript = lid.sh\n").unwrap();
+        assert!(parse_plugins_config(&both, Isolation::InProcess).is_err());
+
+        let zero = Ini::load_from_str("[plugins]\norder = lid\n[plugin.lid]\nscript = lid.sh\nmax_concurrent = 0\n").unwrap();
+        assert!(parse_plugins_config(&zero, Isolation::InProcess).is_err());
+    }
+
+    #[test]
+    fn test_parse_wasm_config() {
+        let ini = Ini::load_from_str(
+            "[plugins]
+order = backlight
+
+[plugin.backlight]
+wasm = /usr/lib/pwrsurge/backlight.wasm
+sysfs_read = /sys/class/power_supply
+sysfs_write = /sys/class/backlight/intel_backlight/brightness
+",
+        )
+        .unwrap();
+        let plugins = parse_plugins_config(&ini, Isolation::InProcess).unwrap();
+        let config = match plugins[0].backend {
+            BackendKind::Wasm(ref config) => config,
+            ref other => panic!("Unexpected backend {:?}", other),
+        };
+        assert!(config.can_read(Path::new("/sys/class/power_supply/AC/online")));
+        assert!(!config.can_write(Path::new("/sys/class/power_supply/AC/online")));
+        assert!(config.can_read(Path::new("/sys/class/backlight/intel_backlight/brightness")));
+        assert!(config.can_write(Path::new("/sys/class/backlight/intel_backlight/brightness")));
+        assert!(!config.can_read(Path::new("/sys/class/power_supply/../../power/state")));
+        assert!(!config.can_read(Path::new("/sys/class/power_supply_extra/x")));
+        assert!(!config.can_read(Path::new("sys/class/power_supply/AC/online")));
+
+        let outside = Ini::load_from_str("[plugins]\norder = x\n[plugin.x]\nwasm = x.wasm\nsysfs_write = /etc\n").unwrap();
+        assert!(parse_plugins_config(&outside, Isolation::InProcess).is_err());
+    }
+
+    #[test]
+    fn test_parse_device_matchers() {
+        let matchers = parse_device_matchers(Some(
+            &"name:Lid Switch, phys:PNP0C0D/*, vendor:046d, key:116, sw:0".to_string(),
+        ))
+        .unwrap();
+        assert_eq!(
+            matchers,
+            vec![
+                DeviceMatcher::Name("Lid Switch".to_string()),
+                DeviceMatcher::Phys("PNP0C0D/*".to_string()),
+                DeviceMatcher::Vendor(0x046d),
+                DeviceMatcher::Key(116),
+                DeviceMatcher::Sw(0),
+            ]
+        );
+        assert_eq!(parse_device_matchers(None).unwrap(), Vec::new());
+        assert!(parse_device_matcher("Lid Switch").is_err());
+        assert!(parse_device_matcher("colour:red").is_err());
+        assert!(parse_device_matcher("key:power").is_err());
+        assert_eq!(
+            parse_device_matchers(Some(&"ev:EV_SW, sw:SW_LID, key:KEY_POWER".to_string())).unwrap(),
+            vec![
+                DeviceMatcher::Ev(5),
+                DeviceMatcher::Sw(0),
+                DeviceMatcher::Key(116)
+            ]
+        );
+        assert!(parse_device_matcher("vendor:xyz").is_err());
+    }
+
+    #[test]
+    fn test_parse_devices_config() {
+        let power_button = DeviceInfo {
+            name: "Power Button".to_string(),
+            handler: "event2".to_string(),
+            ..Default::default()
+        };
+        let lid = DeviceInfo {
+            name: "Lid Switch".to_string(),
+            handler: "event0".to_string(),
+            ..Default::default()
+        };
+        let ini = Ini::load_from_str(
+            "[devices]
+exclude = name:*Mic*
+
+[device.power_button]
+match = name:Power Button
+grab = true
+
+[device.lid]
+match = name:Lid Switch
+",
+        )
+        .unwrap();
+        let filter = parse_devices_config(&ini).unwrap();
+        assert!(filter.matches(&power_button) && filter.matches(&lid));
+        assert!(filter.grab(&power_button));
+        assert!(!filter.grab(&lid));
+
+        assert!(parse_devices_config(&Ini::load_from_str("[device.lid]\ngrab = true\n").unwrap()).is_err());
+        assert!(parse_devices_config(
+            &Ini::load_from_str("[device.lid]\nmatch = sw:0\ngrab = yes\n").unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_timer_config() {
+        let ini = Ini::load_from_str(
+            "[timer]
+reset_on_input = true
+levels = dim, suspend
+active_action = brightnessctl set 100%
+
+[timer.dim]
+ac_timeout = 300
+battery_timeout = 120
+action = brightnessctl set 10%
+
+[timer.suspend]
+battery_timeout = 900
+action = systemctl suspend
+",
+        )
+        .unwrap();
+        let config = parse_timer_config(&ini).unwrap();
+        assert!(config.reset_on_input);
+        assert_eq!(config.active_action.as_deref(), Some("brightnessctl set 100%"));
+        assert_eq!(
+            config.levels,
+            vec![
+                IdleLevel {
+                    name: "dim".to_string(),
+                    ac_timeout: Some(Duration::from_secs(300)),
+                    battery_timeout: Some(Duration::from_secs(120)),
+                    action: Some(Box::from("brightnessctl set 10%")),
+                },
+                IdleLevel {
+                    name: "suspend".to_string(),
+                    ac_timeout: None,
+                    battery_timeout: Some(Duration::from_secs(900)),
+                    action: Some(Box::from("systemctl suspend")),
+                },
+            ]
+        );
+        assert_eq!(config.levels[1].timeout(true), None);
+        assert_eq!(config.levels[1].timeout(false), Some(Duration::from_secs(900)));
+
+        assert_eq!(
+            parse_timer_config(&Ini::load_from_str("[acpi]\n").unwrap()).unwrap(),
+            IdleConfig::default()
+        );
+        assert!(parse_timer_config(&Ini::load_from_str("[timer]\nlevels = dim\n").unwrap()).is_err());
+        assert!(parse_timer_config(
+            &Ini::load_from_str("[timer]\nlevels = dim\n[timer.dim]\naction = true\n").unwrap()
+        )
+        .is_err());
+        assert!(parse_timer_config(
+            &Ini::load_from_str("[timer]\nlevels = dim\n[timer.dim]\nac_timeout = soon\n").unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_daemon_config() {
+        let ini =
+            Ini::load_from_str("[daemon]\nmax_retries = 5\nretry_backoff_ms = 60000\n").unwrap();
+        let config = parse_daemon_config(&ini).unwrap();
+        assert_eq!(config.max_retries, 5);
+        assert_eq!(config.retry_backoff, Duration::from_secs(60));
+        for bad in &[
+            "retry_backoff_ms = 60001",
+            "retry_backoff_ms = 18446744073709551615",
+        ] {
+            let ini = Ini::load_from_str(&format!("[daemon]\n{}\n", bad)).unwrap();
+            assert!(parse_daemon_config(&ini).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_suspend_config() {
+        assert_eq!(
+            parse_suspend_config(&Ini::load_from_str("[acpi]\n").unwrap()).unwrap(),
+            SuspendConfig::default()
+        );
+        let ini = Ini::load_from_str(
+            "[suspend]
+lid_action = suspend_then_hibernate
+hibernate_delay = 7200
+rtc = rtc1
+",
+        )
+        .unwrap();
+        assert_eq!(
+            parse_suspend_config(&ini).unwrap(),
+            SuspendConfig {
+                lid_action: LidAction::SuspendThenHibernate,
+                hibernate_delay: Duration::from_secs(7200),
+                rtc: Box::from("rtc1"),
+            }
+        );
+        for bad in &["lid_action = hibernate", "hibernate_delay = 0", "rtc = ../rtc0"] {
+            let ini = Ini::load_from_str(&format!("[suspend]\n{}\n", bad)).unwrap();
+            assert!(parse_suspend_config(&ini).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_throttle_config() {
+        let ini = Ini::load_from_str(
+            "[throttle.lid]
+events = evdev
+match = type=EV_SW && code=SW_LID
+debounce_ms = 200
+
+[throttle.brightness]
+events = acpi
+device_class_whitelist = video/*
+coalesce_ms = 100
+rate = 2.5
+burst = 5
+
+[throttle.battery]
+events = acpi
+device_class_whitelist = battery
+rate = 1
+",
+        )
+        .unwrap();
+        let config = parse_throttle_config(&ini).unwrap();
+        let names = config.acpi.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["battery", "brightness"]);
+        assert_eq!(config.acpi[0].window, None);
+        assert_eq!(
+            config.acpi[0].rate_limit,
+            Some(RateLimit {
+                rate: 1.0,
+                burst: 1
+            })
+        );
+        assert_eq!(
+            config.acpi[1].window,
+            Some(Window::Coalesce(Duration::from_millis(100)))
+        );
+        assert_eq!(
+            config.acpi[1].rate_limit,
+            Some(RateLimit {
+                rate: 2.5,
+                burst: 5
+            })
+        );
+        let brightness = AcpiEvent {
+            device_class: DeviceClass("video/brightnessup".to_string()),
+            bus_id: BusId("BRTUP".to_string()),
+            event_type: 0x86,
+            event_data: 0,
+        };
+        assert!(config.acpi[1].matcher.matches(&brightness));
+        assert!(!config.acpi[0].matcher.matches(&brightness));
+        assert_eq!(config.evdev.len(), 1);
+        assert_eq!(
+            config.evdev[0].window,
+            Some(Window::Debounce(Duration::from_millis(200)))
+        );
+        assert!(config.evdev[0].matcher.matches(&InputEvent::synthetic(5, 0, 1)));
+        assert!(!config.evdev[0].matcher.matches(&InputEvent::synthetic(1, 0, 1)));
+
+        // Without a match key every evdev event is throttled
+        let ini = Ini::load_from_str("[throttle.all]\nevents = evdev\nrate = 100\n").unwrap();
+        let config = parse_throttle_config(&ini).unwrap();
+        assert!(config.evdev[0].matcher.matches(&InputEvent::synthetic(3, 0, 7)));
+
+        for bad in &[
+            "debounce_ms = 100",
+            "events = thermal\ndebounce_ms = 100",
+            "events = evdev",
+            "events = evdev\nburst = 2",
+            "events = evdev\ndebounce_ms = 100\ncoalesce_ms = 100",
+            "events = evdev\ndebounce_ms = 0",
+            "events = evdev\nrate = 0",
+            "events = evdev\nrate = 1\nburst = 0",
+            "events = evdev\nmatch = type=\nrate = 1",
+        ] {
+            let ini = Ini::load_from_str(&format!("[throttle.bad]\n{}\n", bad)).unwrap();
+            assert!(parse_throttle_config(&ini).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_gestures_config() {
+        assert_eq!(
+            parse_gestures_config(&Ini::load_from_str("[acpi]\n").unwrap()).unwrap(),
+            GestureConfig::default()
+        );
+        let ini = Ini::load_from_str(
+            "[gestures]
+keys = KEY_POWER, 0x74, KEY_F4
+long_press_ms = 3000
+double_press_ms = 0
+chord.lock = KEY_LEFTMETA + KEY_L
+chord.close = KEY_LEFTALT+KEY_F4
+",
+        )
+        .unwrap();
+        assert_eq!(
+            parse_gestures_config(&ini).unwrap(),
+            GestureConfig {
+                keys: vec![116, 116, 62],
+                long_press: Duration::from_secs(3),
+                double_press: None,
+                chords: vec![
+                    Chord {
+                        name: "close".to_string(),
+                        keys: vec![56, 62],
+                    },
+                    Chord {
+                        name: "lock".to_string(),
+                        keys: vec![125, 38],
+                    },
+                ],
+            }
+        );
+        for bad in &[
+            "keys = KEY_POWR",
+            "long_press_ms = 0",
+            "double_press_ms = -1",
+            "chord.power = KEY_POWER",
+            "chord.x = KEY_A + SW_LIDD",
+            "chord.a = KEY_A + KEY_C\nchord.b = KEY_B + KEY_C",
+        ] {
+            let ini = Ini::load_from_str(&format!("[gestures]\n{}\n", bad)).unwrap();
+            assert!(parse_gestures_config(&ini).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_thermal_config() {
+        let trip = |zone_type: &str| {
+            ThermalEvent::TripUp(TripEvent {
+                zone_id: 0,
+                zone_type: zone_type.to_string(),
+                trip_id: 1,
+                trip_type: None,
+                temp: None,
+                hyst: None,
+            })
+        };
+        let ini = Ini::load_from_str(
+            "[thermal]
+thermal_event_whitelist = trip_up, trip_down
+thermal_type_whitelist = x86_pkg_temp
+",
+        )
+        .unwrap();
+        let filter = parse_thermal_config(&ini).unwrap();
+        assert!(filter.matches(&trip("x86_pkg_temp")));
+        assert!(!filter.matches(&trip("acpitz")));
+        assert!(!filter.matches(&ThermalEvent::CdevUpdate(CoolingDeviceEvent {
+            cdev_id: 0,
+            cdev_type: "x86_pkg_temp".to_string(),
+            cur_state: Some(1),
+            max_state: None,
+        })));
+
+        let filter = parse_thermal_config(&Ini::load_from_str("[acpi]\n").unwrap()).unwrap();
+        assert!(filter.matches(&trip("acpitz")));
+        assert!(parse_thermal_config(
+            &Ini::load_from_str("[thermal]\nthermal_event_whitelist = overheat\n").unwrap()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::Notify, task::spawn_blocking, time::sleep};
+
+use crate::{
+    acpi::AcpiEvent,
+    args::{DaemonConfig, PluginSpec, MAX_RETRY_BACKOFF},
+    evdev::InputEvent,
+    filter::{AcpiFilter, EvdevFilter, ThermalFilter},
+    plugin::{Backend, HandlerResult, PluginHandle, CAP_EVDEV_FRAME},
+    power::PowerSupplyState,
+    sleep::ResumeReason,
+    thermal::ThermalEvent,
+};
+
+/// The event was handled; it is not passed to any later plugin.
+pub const RET_HANDLED: i32 = 0;
+/// The event should also be passed to the next plugin.
+pub const RET_PASS: i32 = 1;
+/// The plugin could not handle the event right now; call it again
+/// after a backoff.
+pub const RET_RETRY: i32 = 2;
+
+/// What the daemon does with an event after a plugin returned.
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Handled,
+    Pass,
+    Retry,
+    Error(i32),
+    Fatal(i32),
+}
+
+fn verdict(code: i32, fatal_code: Option<i32>) -> Verdict {
+    match code {
+        c if Some(c) == fatal_code => Verdict::Fatal(c),
+        RET_HANDLED => Verdict::Handled,
+        RET_PASS => Verdict::Pass,
+        RET_RETRY => Verdict::Retry,
+        c => Verdict::Error(c),
+    }
+}
+
+/// How long to wait before the retry following `retries` earlier ones
+fn retry_backoff(initial: Duration, retries: u32) -> Duration {
+    initial
+        .saturating_mul(2u32.pow(retries.min(10)))
+        .min(MAX_RETRY_BACKOFF)
+}
+
+struct Frame<'a>(&'a [InputEvent]);
+
+impl Display for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let events = self.0.iter().map(|event| event.to_string()).collect::<Vec<_>>();
+        write!(f, "[{}]", events.join(", "))
+    }
+}
+
+struct PluginEntry {
+    name: String,
+    handle: Arc<PluginHandle>,
+    acpi_filter: AcpiFilter,
+    evdev_filter: EvdevFilter,
+    thermal_filter: ThermalFilter,
+    errors: AtomicU64,
+}
+
+/// All loaded plugins in the order in which events are dispatched to
+/// them. An event is passed down the list until a plugin returns
+/// `RET_HANDLED`. A plugin failing to handle an event is logged and
+/// does not prevent the event from reaching the plugins after it.
+pub struct Dispatcher {
+    plugins: Vec<PluginEntry>,
+    /// The global `[evdev]` filter, whose rules may route events to
+    /// some of the plugins only
+    evdev_routes: Arc<EvdevFilter>,
+    config: DaemonConfig,
+    fatal: Notify,
+}
+
+impl Dispatcher {
+    pub fn load(
+        specs: Vec<PluginSpec>,
+        config_path: &str,
+        config: DaemonConfig,
+        evdev_routes: Arc<EvdevFilter>,
+    ) -> Result<Self, Box<dyn Error>> {
+        for (rule, plugin) in evdev_routes.routes() {
+            if !specs.iter().any(|spec| spec.name == plugin) {
+                return Err(format!("Rule {} routes events to unknown plugin {}", rule, plugin).into());
+            }
+        }
+        let mut plugins = Vec::new();
+        for spec in specs {
+            let handle = PluginHandle::load(&spec.path, config_path, spec.backend)?;
+            let plugin = handle.get();
+            println!(
+                "Loaded plugin {} from {} (ABI version {}, capabilities {:#x})",
+                spec.name,
+                plugin.path(),
+                plugin.info().abi_version,
+                plugin.info().capabilities,
+            );
+            drop(plugin);
+            plugins.push(PluginEntry {
+                name: spec.name,
+                handle: Arc::new(handle),
+                acpi_filter: spec.acpi,
+                evdev_filter: spec.evdev,
+                thermal_filter: spec.thermal,
+                errors: AtomicU64::new(0),
+            });
+        }
+        Ok(Dispatcher {
+            plugins,
+            evdev_routes,
+            config,
+            fatal: Notify::new(),
+        })
+    }
+
+    pub fn handles(&self) -> impl Iterator<Item = &Arc<PluginHandle>> {
+        self.plugins.iter().map(|entry| &entry.handle)
+    }
+
+    /// Resolves once a plugin has returned the configured fatal code.
+    pub async fn fatal(&self) {
+        self.fatal.notified().await
+    }
+
+    /// Call `call` on each plugin selected by `matches` in order until
+    /// one of them consumes the event.
+    async fn dispatch<E, M, C>(&self, kind: &str, event: &E, matches: M, call: C)
+    where
+        E: Display,
+        M: Fn(&PluginEntry) -> bool,
+        C: Fn(&dyn Backend) -> Option<HandlerResult> + Clone + Send + 'static,
+    {
+        for entry in self.plugins.iter().filter(|entry| matches(entry)) {
+            if self.call(entry, kind, event, call.clone()).await {
+                return;
+            }
+        }
+    }
+
+    /// Call `call` on a plugin, retrying as long as it asks to, and
+    /// return whether it consumed the event. Handlers run on the
+    /// blocking thread pool as they may take a long time to return.
+    async fn call<E, C>(&self, entry: &PluginEntry, kind: &str, event: &E, call: C) -> bool
+    where
+        E: Display,
+        C: Fn(&dyn Backend) -> Option<HandlerResult> + Clone + Send + 'static,
+    {
+        let mut retries = 0;
+        loop {
+            let plugin = entry.handle.get();
+            let call = call.clone();
+            let result = match spawn_blocking(move || call(&*plugin)).await {
+                Ok(result) => result,
+                Err(e) => {
+                    println!(
+                        "Plugin {} panicked handling {} event {}: {}",
+                        entry.name, kind, event, e,
+                    );
+                    return false;
+                }
+            };
+            let code = match result {
+                Some(Ok(code)) => code,
+                Some(Err(e)) => {
+                    println!(
+                        "Plugin {} failed to handle {} event {}: {}",
+                        entry.name, kind, event, e,
+                    );
+                    return false;
+                }
+                None => return false,
+            };
+            match verdict(code, self.config.fatal_code) {
+                Verdict::Handled => return true,
+                Verdict::Pass => return false,
+                Verdict::Retry if retries < self.config.max_retries => {
+                    let backoff = retry_backoff(self.config.retry_backoff, retries);
+                    retries += 1;
+                    sleep(backoff).await;
+                }
+                Verdict::Retry => {
+                    println!(
+                        "Plugin {} still asked to retry {} event {} after {} retries; giving up",
+                        entry.name, kind, event, retries,
+                    );
+                    return false;
+                }
+                Verdict::Error(c) => {
+                    let errors = entry.errors.fetch_add(1, Ordering::Relaxed) + 1;
+                    println!(
+                        "Plugin {} returned {} for {} event {} ({} errors so far)",
+                        entry.name, c, kind, event, errors,
+                    );
+                    return false;
+                }
+                Verdict::Fatal(c) => {
+                    println!(
+                        "Plugin {} returned fatal code {} for {} event {}",
+                        entry.name, c, kind, event,
+                    );
+                    self.fatal.notify_one();
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Whether the event is routed to the plugin and passes its own
+    /// whitelist
+    fn evdev_selects(&self, entry: &PluginEntry, event: &InputEvent) -> bool {
+        self.evdev_routes.routes_to(event, &entry.name) && entry.evdev_filter.matches(event)
+    }
+
+    /// Plugins with a frame handler are passed the whole frame if any
+    /// of its events passes their whitelist, and none of its events on
+    /// their own. The other plugins are passed each event of the frame
+    /// in order. A frame handler consuming the frame keeps all of its
+    /// events from the plugins after it; an evdev handler consuming an
+    /// event only that event.
+    pub async fn dispatch_evdev_frame(&self, frame: Vec<InputEvent>) {
+        let frame = Arc::new(frame);
+        let mut consumed = vec![false; frame.len()];
+        for entry in self.plugins.iter() {
+            if entry.handle.get().info().capabilities & CAP_EVDEV_FRAME != 0 {
+                let selected = frame
+                    .iter()
+                    .zip(&consumed)
+                    .any(|(event, consumed)| !consumed && self.evdev_selects(entry, event));
+                let call_frame = Arc::clone(&frame);
+                let call = move |plugin: &dyn Backend| plugin.handle_evdev_frame(&call_frame);
+                if selected && self.call(entry, "evdev frame", &Frame(&frame), call).await {
+                    return;
+                }
+                continue;
+            }
+            for (&event, consumed) in frame.iter().zip(consumed.iter_mut()) {
+                if !*consumed && self.evdev_selects(entry, &event) {
+                    let call = move |plugin: &dyn Backend| plugin.handle_evdev(&event);
+                    *consumed = self.call(entry, "evdev", &event, call).await;
+                }
+            }
+        }
+    }
+
+    pub async fn dispatch_acpi(&self, event: AcpiEvent) {
+        let event = Arc::new(event);
+        let call_event = Arc::clone(&event);
+        self.dispatch(
+            "ACPI",
+            &*event,
+            |entry| entry.acpi_filter.matches(&event),
+            move |plugin| plugin.handle_acpi(&call_event),
+        )
+        .await
+    }
+
+    /// Idle levels are not subject to any whitelist.
+    pub async fn dispatch_idle(&self, level: u32) {
+        self.dispatch("idle", &level, |_| true, move |plugin| plugin.handle_idle(level))
+            .await
+    }
+
+    /// Resumes are not subject to any whitelist.
+    pub async fn dispatch_resume(&self, reason: ResumeReason) {
+        self.dispatch("resume", &reason, |_| true, move |plugin| plugin.handle_resume(reason))
+            .await
+    }
+
+    /// Called before the daemon suspends the system, without a whitelist.
+    pub async fn dispatch_pre_suspend(&self) {
+        self.dispatch("pre-suspend", &"mem", |_| true, |plugin| plugin.handle_pre_suspend())
+            .await
+    }
+
+    /// Called after the system resumed, without a whitelist.
+    pub async fn dispatch_post_resume(&self, slept: Duration) {
+        self.dispatch(
+            "post-resume",
+            &format!("{}ms", slept.as_millis()),
+            |_| true,
+            move |plugin| plugin.handle_post_resume(slept),
+        )
+        .await
+    }
+
+    pub async fn dispatch_thermal(&self, event: ThermalEvent) {
+        let event = Arc::new(event);
+        let call_event = Arc::clone(&event);
+        self.dispatch(
+            "thermal",
+            &*event,
+            |entry| entry.thermal_filter.matches(&event),
+            move |plugin| plugin.handle_thermal(&call_event),
+        )
+        .await
+    }
+
+    /// Power supply changes are not subject to any whitelist.
+    pub async fn dispatch_power_supply(&self, state: PowerSupplyState) {
+        let state = Arc::new(state);
+        let call_state = Arc::clone(&state);
+        self.dispatch(
+            "power supply",
+            &*state,
+            |_| true,
+            move |plugin| plugin.handle_power_supply(&call_state),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use tokio::runtime::Runtime;
+
+    use crate::{
+        args::Isolation,
+        evdev::EV_KEY,
+        plugin::{PluginInfo, CAP_EVDEV},
+    };
+
+    /// Records the calls of its evdev and frame handlers
+    struct Recorder {
+        name: &'static str,
+        info: PluginInfo,
+        code: i32,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Recorder {
+        fn record(&self, call: String) -> Option<HandlerResult> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(format!("{} {}", self.name, call));
+            Some(Ok(self.code))
+        }
+    }
+
+    impl Backend for Recorder {
+        fn path(&self) -> &str {
+            self.name
+        }
+
+        fn info(&self) -> &PluginInfo {
+            &self.info
+        }
+
+        fn handle_evdev(&self, event: &InputEvent) -> Option<HandlerResult> {
+            self.record(format!("event {}", event.get_event_code()))
+        }
+
+        fn handle_evdev_frame(&self, events: &[InputEvent]) -> Option<HandlerResult> {
+            self.record(format!("frame {}", events.len()))
+        }
+
+        fn handle_acpi(&self, _: &AcpiEvent) -> Option<HandlerResult> {
+            None
+        }
+
+        fn handle_idle(&self, _: u32) -> Option<HandlerResult> {
+            None
+        }
+
+        fn handle_resume(&self, _: ResumeReason) -> Option<HandlerResult> {
+            None
+        }
+
+        fn handle_pre_suspend(&self) -> Option<HandlerResult> {
+            None
+        }
+
+        fn handle_post_resume(&self, _: Duration) -> Option<HandlerResult> {
+            None
+        }
+
+        fn handle_power_supply(&self, _: &PowerSupplyState) -> Option<HandlerResult> {
+            None
+        }
+
+        fn handle_thermal(&self, _: &ThermalEvent) -> Option<HandlerResult> {
+            None
+        }
+    }
+
+    /// Dispatch a frame of two key events to plugins with these names,
+    /// capabilities and return codes and list the handlers called
+    fn dispatch_frame(plugins: &[(&'static str, u32, i32)]) -> Vec<String> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let dispatcher = Dispatcher {
+            plugins: plugins
+                .iter()
+                .map(|&(name, capabilities, code)| {
+                    let recorder = Recorder {
+                        name,
+                        info: PluginInfo {
+                            abi_version: 0,
+                            info_size: 0,
+                            input_event_size: 0,
+                            acpi_event_size: 0,
+                            capabilities,
+                            power_supply_size: 0,
+                            thermal_event_size: 0,
+                        },
+                        code,
+                        calls: Arc::clone(&calls),
+                    };
+                    PluginEntry {
+                        name: name.to_string(),
+                        handle: Arc::new(PluginHandle::from_backend(Arc::new(recorder))),
+                        acpi_filter: AcpiFilter::default(),
+                        evdev_filter: EvdevFilter::new(Vec::new()),
+                        thermal_filter: ThermalFilter::new(Vec::new(), Vec::new()),
+                        errors: AtomicU64::new(0),
+                    }
+                })
+                .collect(),
+            evdev_routes: Arc::new(EvdevFilter::new(Vec::new())),
+            config: DaemonConfig {
+                watch_plugins: false,
+                isolation: Isolation::InProcess,
+                fatal_code: None,
+                max_retries: 0,
+                retry_backoff: Duration::from_millis(100),
+            },
+            fatal: Notify::new(),
+        };
+        let frame = vec![
+            InputEvent::synthetic(EV_KEY, 29, 1),
+            InputEvent::synthetic(EV_KEY, 62, 1),
+        ];
+        Runtime::new()
+            .unwrap()
+            .block_on(dispatcher.dispatch_evdev_frame(frame));
+        let calls = calls.lock().unwrap().clone();
+        calls
+    }
+
+    #[test]
+    fn test_dispatch_evdev_frame() {
+        let frames = |code| ("frames", CAP_EVDEV | CAP_EVDEV_FRAME, code);
+        let events = |code| ("events", CAP_EVDEV, code);
+        // Plugins with a frame handler do not get the events on their own
+        assert_eq!(
+            dispatch_frame(&[frames(RET_PASS), events(RET_PASS)]),
+            vec!["frames frame 2", "events event 29", "events event 62"]
+        );
+        // A consumed frame is not passed on
+        assert_eq!(
+            dispatch_frame(&[frames(RET_HANDLED), events(RET_PASS)]),
+            vec!["frames frame 2"]
+        );
+        // Neither are consumed events, nor is a frame of them
+        assert_eq!(
+            dispatch_frame(&[events(RET_HANDLED), frames(RET_PASS)]),
+            vec!["events event 29", "events event 62"]
+        );
+    }
+
+    #[test]
+    fn test_verdict() {
+        assert_eq!(verdict(0, None), Verdict::Handled);
+        assert_eq!(verdict(1, None), Verdict::Pass);
+        assert_eq!(verdict(2, None), Verdict::Retry);
+        assert_eq!(verdict(-5, None), Verdict::Error(-5));
+        assert_eq!(verdict(3, None), Verdict::Error(3));
+        assert_eq!(verdict(42, Some(42)), Verdict::Fatal(42));
+        assert_eq!(verdict(-1, Some(-1)), Verdict::Fatal(-1));
+        assert_eq!(verdict(0, Some(42)), Verdict::Handled);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let initial = Duration::from_millis(100);
+        assert_eq!(retry_backoff(initial, 0), initial);
+        assert_eq!(retry_backoff(initial, 3), Duration::from_millis(800));
+        assert_eq!(retry_backoff(initial, 10), MAX_RETRY_BACKOFF);
+        assert_eq!(
+            retry_backoff(MAX_RETRY_BACKOFF, u32::MAX),
+            MAX_RETRY_BACKOFF
+        );
+    }
+}
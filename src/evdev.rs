@@ -1,46 +1,203 @@
 use std::{
-    collections::{self, HashMap},
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
     error::Error,
     fmt::{self, Display},
     io::{self, Read},
     mem,
+    os::unix::io::{AsRawFd, RawFd},
     pin::Pin,
     task::{Context, Poll},
 };
 
 use buffering::NoCopy;
-use libc;
 use tokio::{
     fs::File,
     io::{AsyncRead, ReadBuf},
     stream::Stream,
 };
 
+use crate::codes::{code_name, type_name, value_name, Name};
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_SW: u16 = 0x05;
+const EV_MAX: u16 = 0x1f;
+const KEY_MAX: u16 = 0x2ff;
+const SW_MAX: u16 = 0x10;
+pub const SW_LID: u16 = 0x00;
+pub const SYN_REPORT: u16 = 0;
+const SYN_DROPPED: u16 = 3;
+
+/// `ioctl` numbers of `EVIOCGKEY`, `EVIOCGSW` and `EVIOCGBIT` without
+/// their direction and size
+const EVIOCGKEY_NR: u8 = 0x18;
+const EVIOCGSW_NR: u8 = 0x1b;
+const EVIOCGBIT_NR: u8 = 0x20;
+
+/// `_IOW('E', 0x90, int)`
+const EVIOCGRAB: libc::c_ulong = 0x40044590;
+
+/// `_IOC(_IOC_READ, 'E', nr, len)`
+fn eviocg(nr: u8, len: usize) -> libc::c_ulong {
+    2 << 30 | (len as libc::c_ulong) << 16 | (b'E' as libc::c_ulong) << 8 | nr as libc::c_ulong
+}
+
+/// Capability bitmap from a `B:` line of `/proc/bus/input/devices`.
+/// The kernel prints it as hexadecimal words the size of a C `long`,
+/// most significant word first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bitmap(Vec<libc::c_ulong>);
+
+impl Bitmap {
+    const WORD_BITS: usize = mem::size_of::<libc::c_ulong>() * 8;
+
+    fn parse(s: &str) -> Option<Self> {
+        let mut words = s
+            .split_whitespace()
+            .map(|w| libc::c_ulong::from_str_radix(w, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        words.reverse();
+        Some(Bitmap(words))
+    }
+
+    /// Read a bitmap of `max + 1` bits from a device with an `EVIOCG*`
+    /// ioctl.
+    fn from_ioctl(fd: RawFd, nr: u8, max: u16) -> io::Result<Self> {
+        let mut words = vec![0 as libc::c_ulong; max as usize / Self::WORD_BITS + 1];
+        let len = words.len() * mem::size_of::<libc::c_ulong>();
+        if unsafe { libc::ioctl(fd, eviocg(nr, len) as _, words.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Bitmap(words))
+    }
+
+    fn set(&mut self, bit: u16, value: bool) {
+        let bit = bit as usize;
+        let word = bit / Self::WORD_BITS;
+        if word >= self.0.len() {
+            if !value {
+                return;
+            }
+            self.0.resize(word + 1, 0);
+        }
+        let mask = 1 << (bit % Self::WORD_BITS);
+        if value {
+            self.0[word] |= mask;
+        } else {
+            self.0[word] &= !mask;
+        }
+    }
+
+    pub fn has(&self, bit: u16) -> bool {
+        let bit = bit as usize;
+        self.0
+            .get(bit / Self::WORD_BITS)
+            .map(|w| w >> (bit % Self::WORD_BITS) & 1 == 1)
+            .unwrap_or(false)
+    }
+}
+
+/// An input device as described by `/proc/bus/input/devices`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceInfo {
+    pub bus: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    pub name: String,
+    pub phys: String,
+    pub sysfs: String,
+    /// The device's event handler, such as `event3`
+    pub handler: String,
+    /// Supported event types
+    pub ev: Bitmap,
+    /// Supported keys and buttons
+    pub key: Bitmap,
+    /// Supported switches
+    pub sw: Bitmap,
+}
+
+impl DeviceInfo {
+    pub fn path(&self) -> String {
+        format!("/dev/input/{}", self.handler)
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, bus {:04x} vendor {:04x} product {:04x}, phys {})",
+            self.path(),
+            self.name,
+            self.bus,
+            self.vendor,
+            self.product,
+            self.phys
+        )
+    }
+}
+
 #[derive(Debug)]
-struct EvdevEvents(HashMap<String, String>);
+struct EvdevEvents(HashMap<String, DeviceInfo>);
 
 impl EvdevEvents {
+    fn parse_id_line(info: &mut DeviceInfo, line: &str) {
+        for field in line.split_whitespace() {
+            let (key, value) = match field.find('=') {
+                Some(i) => (&field[..i], &field[i + 1..]),
+                None => continue,
+            };
+            let value = match u16::from_str_radix(value, 16) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            match key {
+                "Bus" => info.bus = value,
+                "Vendor" => info.vendor = value,
+                "Product" => info.product = value,
+                "Version" => info.version = value,
+                _ => (),
+            }
+        }
+    }
+
     fn parse_file_chunk(&mut self, file_chunk: String) {
-        let mut name = String::new();
-        let mut handler_name = String::new();
+        let mut info = DeviceInfo::default();
         for line in file_chunk.lines() {
-            let contains_name = line.contains("Name=");
-            let contains_handlers = line.contains("Handlers=");
-            if contains_name {
-                let len = line.len();
-                name = line[r#"N: Name=""#.len()..len - 1].to_string();
-            } else if contains_handlers {
-                let handlers = line["H: Handlers=".len()..].to_string();
-                let handler_iter = handlers.split(" ");
-                for handler in handler_iter {
-                    if handler.contains("event") {
-                        handler_name = handler.to_string();
-                        break;
-                    }
+            if let Some(id) = line.strip_prefix("I: ") {
+                Self::parse_id_line(&mut info, id);
+            } else if let Some(name) = line.strip_prefix("N: Name=") {
+                info.name = name.trim_matches('"').to_string();
+            } else if let Some(phys) = line.strip_prefix("P: Phys=") {
+                info.phys = phys.to_string();
+            } else if let Some(sysfs) = line.strip_prefix("S: Sysfs=") {
+                info.sysfs = sysfs.to_string();
+            } else if let Some(handlers) = line.strip_prefix("H: Handlers=") {
+                if let Some(handler) = handlers.split(" ").find(|h| h.contains("event")) {
+                    info.handler = handler.to_string();
+                }
+            } else if let Some(bitmap) = line.strip_prefix("B: ") {
+                let (key, value) = match bitmap.find('=') {
+                    Some(i) => (&bitmap[..i], &bitmap[i + 1..]),
+                    None => continue,
+                };
+                let field = match key {
+                    "EV" => &mut info.ev,
+                    "KEY" => &mut info.key,
+                    "SW" => &mut info.sw,
+                    _ => continue,
+                };
+                if let Some(bitmap) = Bitmap::parse(value) {
+                    *field = bitmap;
                 }
             }
         }
-        self.0.insert(handler_name, name);
+        // Devices without an evdev handler cannot be read
+        if !info.handler.is_empty() {
+            self.0.insert(info.handler.clone(), info);
+        }
     }
 
     fn parse_events_file(&mut self) -> Result<(), io::Error> {
@@ -61,10 +218,6 @@ impl EvdevEvents {
         evdev_events.parse_events_file()?;
         Ok(evdev_events)
     }
-
-    pub fn iter(&self) -> collections::hash_map::Iter<String, String> {
-        self.0.iter()
-    }
 }
 
 #[derive(NoCopy, Clone, Copy)]
@@ -77,6 +230,42 @@ pub struct InputEventStruct {
     pub event_value: i32,
 }
 
+impl InputEvent {
+    /// An event reporting a state read from the device rather than a
+    /// change. Synthetic events have a zero timestamp, which the kernel
+    /// never reports.
+    pub fn synthetic(event_type: u16, event_code: u16, event_value: i32) -> Self {
+        let mut event = InputEvent::new_buffer([0; mem::size_of::<InputEventStruct>()]);
+        event.set_event_type(event_type);
+        event.set_event_code(event_code);
+        event.set_event_value(event_value);
+        event
+    }
+
+    pub fn is_synthetic(&self) -> bool {
+        let timestamp = self.get_timestamp();
+        timestamp.tv_sec == 0 && timestamp.tv_usec == 0
+    }
+}
+
+impl Display for InputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "type {} code {} value {}",
+            Name(type_name(self.get_event_type()), self.get_event_type()),
+            Name(
+                code_name(self.get_event_type(), self.get_event_code()),
+                self.get_event_code()
+            ),
+            match value_name(self.get_event_type(), self.get_event_value()) {
+                Some(name) => name.to_string(),
+                None => self.get_event_value().to_string(),
+            }
+        )
+    }
+}
+
 #[derive(Debug)]
 struct EvdevError(String);
 
@@ -88,44 +277,218 @@ impl Display for EvdevError {
 
 impl Error for EvdevError {}
 
-pub struct EvdevStream(File);
+/// Events read from a device with a single read
+const READ_EVENTS: usize = 64;
+/// Frames longer than this are discarded like events lost to
+/// `SYN_DROPPED`. Plugins may rely on no frame they are passed being
+/// longer.
+pub const MAX_FRAME_EVENTS: usize = 256;
+
+/// Groups events into frames terminated by `SYN_REPORT` and tracks the
+/// key and switch state the complete frames leave the device in.
+#[derive(Default)]
+struct Framer {
+    frame: Vec<InputEvent>,
+    ready: VecDeque<Vec<InputEvent>>,
+    /// Set from a `SYN_DROPPED` or a frame growing too long until the
+    /// next `SYN_REPORT`
+    dropped: bool,
+    keys: Bitmap,
+    switches: Bitmap,
+}
+
+impl Framer {
+    /// Returns true once the events following a `SYN_DROPPED`, or the
+    /// rest of a frame with more than `MAX_FRAME_EVENTS` events, have
+    /// been discarded, after which the state has to be read from the
+    /// device again.
+    fn push(&mut self, event: InputEvent) -> bool {
+        let syn = event.get_event_type() == EV_SYN;
+        let report = syn && event.get_event_code() == SYN_REPORT;
+        // A frame is only applied to the state once it is complete
+        if syn && event.get_event_code() == SYN_DROPPED
+            || !report && self.frame.len() + 1 == MAX_FRAME_EVENTS
+        {
+            self.frame.clear();
+            self.dropped = true;
+            return false;
+        }
+        if self.dropped {
+            if report {
+                self.dropped = false;
+                return true;
+            }
+            return false;
+        }
+        self.frame.push(event);
+        if report {
+            for event in self.frame.iter() {
+                match (event.get_event_type(), event.get_event_value()) {
+                    (EV_KEY, 0) => self.keys.set(event.get_event_code(), false),
+                    (EV_KEY, 1) => self.keys.set(event.get_event_code(), true),
+                    (EV_SW, value) => self.switches.set(event.get_event_code(), value != 0),
+                    _ => (),
+                }
+            }
+            self.ready.push_back(mem::take(&mut self.frame));
+        }
+        false
+    }
+
+    /// Queue a frame of synthetic events for every switch in
+    /// `supported` whose state differs from `switches`, or every switch
+    /// if `all_switches` is set, and for every key whose state differs
+    /// from `keys`.
+    fn sync(&mut self, supported: &Bitmap, switches: Bitmap, keys: Bitmap, all_switches: bool) {
+        let mut frame = Vec::new();
+        for code in (0..=SW_MAX).filter(|code| supported.has(*code)) {
+            if all_switches || switches.has(code) != self.switches.has(code) {
+                frame.push(InputEvent::synthetic(EV_SW, code, switches.has(code) as i32));
+            }
+        }
+        for code in (0..=KEY_MAX).filter(|code| keys.has(*code) != self.keys.has(*code)) {
+            frame.push(InputEvent::synthetic(EV_KEY, code, keys.has(code) as i32));
+        }
+        self.switches = switches;
+        self.keys = keys;
+        if !frame.is_empty() {
+            frame.push(InputEvent::synthetic(EV_SYN, SYN_REPORT, 0));
+            self.ready.push_back(frame);
+        }
+    }
+}
+
+/// Frames of events read from an evdev device. Each frame ends with a
+/// `SYN_REPORT`. Events lost to a full kernel buffer, and frames with
+/// more than `MAX_FRAME_EVENTS` events, are replaced by a frame of
+/// synthetic events describing how the key and switch state changed in
+/// the meantime.
+pub struct EvdevStream {
+    file: File,
+    grabbed: bool,
+    framer: Framer,
+}
 
 impl EvdevStream {
     pub fn new(file: File) -> Self {
-        EvdevStream(file)
+        EvdevStream {
+            file,
+            grabbed: false,
+            framer: Framer::default(),
+        }
+    }
+
+    /// Make this the only reader of the device until the stream is
+    /// dropped. Fails with `EBUSY` if another process holds the grab.
+    pub fn grab(&mut self) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), EVIOCGRAB as _, 1 as libc::c_int) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.grabbed = true;
+        Ok(())
+    }
+
+    /// Read the key and switch state from the device and queue the
+    /// differences to the tracked state.
+    fn sync(&mut self, all_switches: bool) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let ev = Bitmap::from_ioctl(fd, EVIOCGBIT_NR, EV_MAX)?;
+        let (supported, switches) = if ev.has(EV_SW) {
+            (
+                Bitmap::from_ioctl(fd, EVIOCGBIT_NR + EV_SW as u8, SW_MAX)?,
+                Bitmap::from_ioctl(fd, EVIOCGSW_NR, SW_MAX)?,
+            )
+        } else {
+            (Bitmap::default(), Bitmap::default())
+        };
+        let keys = if ev.has(EV_KEY) {
+            Bitmap::from_ioctl(fd, EVIOCGKEY_NR, KEY_MAX)?
+        } else {
+            Bitmap::default()
+        };
+        self.framer.sync(&supported, switches, keys, all_switches);
+        Ok(())
+    }
+
+    /// Queue a frame of synthetic events for the state of every switch
+    /// and every pressed key, which is returned before any frame read
+    /// from the device. Devices only report changes, so without it a
+    /// lid that was already closed when the device was opened would go
+    /// unnoticed.
+    pub fn initial_state(&mut self) -> io::Result<()> {
+        self.sync(true)
+    }
+}
+
+impl AsRawFd for EvdevStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for EvdevStream {
+    fn drop(&mut self) {
+        if self.grabbed {
+            unsafe { libc::ioctl(self.file.as_raw_fd(), EVIOCGRAB as _, 0 as libc::c_int) };
+        }
     }
 }
 
 impl Stream for EvdevStream {
-    type Item = Result<InputEvent, Box<dyn Error + Send + Sync>>;
+    type Item = Result<Vec<InputEvent>, Box<dyn Error + Send + Sync>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let mut buf = [0; mem::size_of::<InputEventStruct>()];
-        let mut read_buf = ReadBuf::new(&mut buf as &mut [u8]);
-        match <File as AsyncRead>::poll_read(Pin::new(&mut self.0), cx, &mut read_buf) {
-            Poll::Ready(Ok(())) => {
-                if read_buf.filled().len() == mem::size_of::<InputEventStruct>() {
-                } else {
-                    return Err("Did not read enough bytes to fill InputEvent buffer")?
+        const EVENT_SIZE: usize = mem::size_of::<InputEventStruct>();
+        loop {
+            if let Some(frame) = self.framer.ready.pop_front() {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            let mut buf = [0u8; READ_EVENTS * EVENT_SIZE];
+            let mut read_buf = ReadBuf::new(&mut buf);
+            match <File as AsyncRead>::poll_read(Pin::new(&mut self.file), cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => {
+                    let filled = read_buf.filled();
+                    if filled.is_empty() {
+                        return Poll::Ready(None);
+                    }
+                    if filled.len() % EVENT_SIZE != 0 {
+                        return Poll::Ready(Some(Err(Box::new(EvdevError(
+                            "Did not read enough bytes to fill InputEvent buffer".to_string(),
+                        )))));
+                    }
+                    let mut resync = false;
+                    for chunk in filled.chunks_exact(EVENT_SIZE) {
+                        let event = InputEvent::new_buffer(chunk.try_into().expect("Exact chunk"));
+                        resync |= self.framer.push(event);
+                    }
+                    if resync {
+                        println!(
+                            "Events were dropped or a frame was too long; \
+                             reading the device state again"
+                        );
+                        if let Err(e) = self.sync(false) {
+                            return Poll::Ready(Some(Err(Box::new(e))));
+                        }
+                    }
                 }
-                mem::drop(read_buf);
-                Poll::Ready(Some(Ok(InputEvent::new_buffer(buf))))
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(Box::new(e)))),
+                Poll::Pending => return Poll::Pending,
             }
-            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(Box::new(e)))),
-            Poll::Pending => Poll::Pending,
         }
     }
 }
 
-pub fn evdev_files<'a>() -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+/// Every input device with an evdev handler, sorted by handler.
+pub fn evdev_devices() -> Result<Vec<DeviceInfo>, Box<dyn Error + Send + Sync>> {
     let events = EvdevEvents::parse_events()?;
-    let mut event_files = Vec::new();
-    for (event, desc) in events.iter() {
-        println!("Opening {} ({}) for reading...", event, desc);
-        let file_name = format!("/dev/input/{}", event);
-        event_files.push(file_name);
-    }
-    Ok(event_files)
+    let mut devices = events.0.into_values().collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.handler.cmp(&b.handler));
+    Ok(devices)
+}
+
+/// Look up the input device with the event handler `handler`.
+pub fn device_info(handler: &str) -> Option<DeviceInfo> {
+    EvdevEvents::parse_events().ok()?.0.remove(handler)
 }
 
 #[cfg(test)]
@@ -144,10 +507,137 @@ B: EV=21
 B: SW=10"#;
         let mut evevents = EvdevEvents(HashMap::new());
         evevents.parse_file_chunk(file_chunk.to_string());
+        let info = evevents.0.get("event8").unwrap();
+        assert_eq!(info.name, "HDA Intel PCH Mic");
+        assert_eq!(info.phys, "ALSA");
+        assert_eq!(
+            info.sysfs,
+            "/devices/pci0000:00/0000:00:1f.3/sound/card0/input15"
+        );
+        assert!(info.ev.has(0x05));
+        assert!(!info.ev.has(0x01));
+        assert!(info.sw.has(4));
+        assert!(!info.sw.has(0));
+    }
+
+    #[test]
+    fn test_device_chunk_parsing() {
+        let file_chunk = r#"I: Bus=0011 Vendor=0001 Product=0001 Version=ab83
+N: Name="AT Translated Set 2 keyboard"
+P: Phys=isa0060/serio0/input0
+S: Sysfs=/devices/platform/i8042/serio0/input/input3
+U: Uniq=
+H: Handlers=sysrq kbd leds event3 
+B: PROP=0
+B: EV=120013
+B: KEY=1000000000007 ff9f207ac14057ff febeffdfffefffff fffffffffffffffe
+B: MSC=10
+B: LED=7"#;
+        let mut evevents = EvdevEvents(HashMap::new());
+        evevents.parse_file_chunk(file_chunk.to_string());
+        let info = evevents.0.get("event3").unwrap();
+        assert_eq!(info.bus, 0x11);
+        assert_eq!(info.vendor, 1);
+        assert_eq!(info.product, 1);
+        assert_eq!(info.version, 0xab83);
+        assert_eq!(info.path(), "/dev/input/event3");
+        assert!(info.ev.has(0x01));
+        // KEY_ESC, KEY_POWER and KEY_SLEEP
+        assert!(info.key.has(1));
+        assert!(info.key.has(116));
+        assert!(info.key.has(142));
+        assert!(!info.key.has(0));
+        assert!(!info.sw.has(0));
+    }
+
+    #[test]
+    fn test_synthetic_events() {
+        assert_eq!(eviocg(EVIOCGSW_NR, 8), 0x8008451b);
+        assert_eq!(eviocg(EVIOCGKEY_NR, 96), 0x80604518);
+        assert_eq!(eviocg(EVIOCGBIT_NR + EV_SW as u8, 8), 0x80084525);
+
+        let event = InputEvent::synthetic(EV_SW, 0, 1);
+        assert!(event.is_synthetic());
+        assert_eq!(event.get_event_type(), EV_SW);
+        assert_eq!(event.get_event_value(), 1);
+        let mut event = InputEvent::synthetic(EV_KEY, 116, 1);
+        event.set_timestamp(libc::timeval {
+            tv_sec: 1,
+            tv_usec: 0,
+        });
+        assert!(!event.is_synthetic());
+    }
+
+    #[test]
+    fn test_framer() {
+        let event = InputEvent::synthetic;
+        let summary = |frame: &Vec<InputEvent>| {
+            frame
+                .iter()
+                .map(|e| (e.get_event_type(), e.get_event_code(), e.get_event_value()))
+                .collect::<Vec<_>>()
+        };
+        let mut framer = Framer::default();
+        assert!(!framer.push(event(EV_KEY, 116, 1)));
+        assert!(framer.ready.is_empty());
+        assert!(!framer.push(event(EV_SYN, SYN_REPORT, 0)));
+        assert_eq!(
+            framer.ready.pop_front().as_ref().map(summary),
+            Some(vec![(EV_KEY, 116, 1), (EV_SYN, SYN_REPORT, 0)])
+        );
+        assert!(framer.keys.has(116));
+
+        // Everything from the incomplete frame before SYN_DROPPED up to
+        // the next SYN_REPORT is discarded.
+        assert!(!framer.push(event(EV_KEY, 30, 1)));
+        assert!(!framer.push(event(EV_SYN, SYN_DROPPED, 0)));
+        assert!(!framer.push(event(EV_KEY, 116, 0)));
+        assert!(framer.push(event(EV_SYN, SYN_REPORT, 0)));
+        assert!(framer.ready.is_empty());
+
+        let mut supported = Bitmap::default();
+        supported.set(0, true);
+        supported.set(5, true);
+        let mut switches = Bitmap::default();
+        switches.set(0, true);
+        framer.sync(&supported, switches.clone(), Bitmap::default(), false);
         assert_eq!(
-            evevents.0.get(&"event8".to_string()),
-            Some(&"HDA Intel PCH Mic".to_string())
+            framer.ready.pop_front().as_ref().map(summary),
+            Some(vec![(EV_SW, 0, 1), (EV_KEY, 116, 0), (EV_SYN, SYN_REPORT, 0)])
         );
+        framer.sync(&supported, switches.clone(), Bitmap::default(), false);
+        assert!(framer.ready.is_empty());
+        framer.sync(&supported, switches, Bitmap::default(), true);
+        assert_eq!(
+            framer.ready.pop_front().as_ref().map(summary),
+            Some(vec![(EV_SW, 0, 1), (EV_SW, 5, 0), (EV_SYN, SYN_REPORT, 0)])
+        );
+
+        assert!(!framer.push(event(EV_SW, 0, 0)));
+        assert!(!framer.push(event(EV_SYN, SYN_REPORT, 0)));
+        assert!(!framer.switches.has(0));
+        framer.ready.clear();
+
+        // The longest frame still fits, SYN_REPORT included
+        for _ in 1..MAX_FRAME_EVENTS {
+            assert!(!framer.push(event(EV_KEY, 30, 1)));
+        }
+        assert!(!framer.push(event(EV_SYN, SYN_REPORT, 0)));
+        let frame = framer.ready.pop_front().unwrap();
+        assert_eq!(frame.len(), MAX_FRAME_EVENTS);
+        assert_eq!(summary(&frame).last(), Some(&(EV_SYN, SYN_REPORT, 0)));
+        assert!(framer.keys.has(30));
+
+        // A 257 event frame is discarded as a whole, without changing
+        // the state, until the state is read again
+        assert!(!framer.push(event(EV_SW, 0, 1)));
+        for _ in 2..=MAX_FRAME_EVENTS {
+            assert!(!framer.push(event(EV_KEY, 30, 0)));
+        }
+        assert!(framer.push(event(EV_SYN, SYN_REPORT, 0)));
+        assert!(framer.ready.is_empty());
+        assert!(!framer.switches.has(0));
+        assert!(framer.keys.has(30));
     }
 
     #[test]
@@ -156,8 +646,8 @@ B: SW=10"#;
         let mut evdev_events = EvdevEvents(HashMap::new());
         evdev_events.parse_events_file().unwrap();
         assert_eq!(
-            evdev_events.0.get(&"event0".to_string()),
-            Some(&"Lid Switch".to_string())
+            evdev_events.0.get("event0").map(|info| info.name.as_str()),
+            Some("Lid Switch")
         );
     }
 }
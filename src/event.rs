@@ -1,90 +1,371 @@
-use std::{error::Error, sync::Arc};
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use futures_util::{future::select_all, select, FutureExt};
-use libloading::{Library, Symbol};
+use futures_util::{future, select, FutureExt};
 use neli::{
     consts::{socket::*},
     socket::{NlSocketHandle, tokio::NlSocket},
-    utils::{serialize, U32Bitmask, U32BitFlag},
+    utils::{U32Bitmask, U32BitFlag},
 };
 use tokio::{
     fs::File,
     runtime::Runtime,
+    signal::unix::{signal, SignalKind},
     stream::StreamExt,
     spawn,
+    sync::mpsc::{self, UnboundedSender},
+    task::JoinHandle,
+    time::sleep,
 };
 
 use crate::{
     acpi::{acpi_event, AcpiEvent},
-    evdev::{evdev_files, EvdevStream, InputEvent},
-    filter::{AcpiFilter, EvdevFilter},
+    acpid::Acpid,
+    args::{CfgFile, GestureConfig},
+    dispatch::Dispatcher,
+    evdev::{
+        device_info, evdev_devices, EvdevStream, InputEvent, EV_SW, EV_SYN, SW_LID, SYN_REPORT,
+    },
+    filter::{AcpiFilter, DeviceFilter, EvdevFilter, ThermalFilter},
+    gesture::Gestures,
+    hotplug::UeventSocket,
+    idle::Idle,
+    power::{power_supplies, PowerSupplyEvents, SupplyType},
+    reload::reload_loop,
+    rule::Expr,
+    sleep::{acpi_lid_closed, Sleep},
+    thermal::{thermal_event, THERMAL_EVENT_GROUP, THERMAL_FAMILY},
+    throttle::Throttle,
 };
 
-async fn handle_event(handler: Arc<Library>, item: InputEvent) {
-    type InputHandler<'sym> =
-        Symbol<'sym, unsafe extern "C" fn(*const InputEvent) -> i32>;
-    let f = match unsafe { handler.get::<InputHandler>(b"evdev_handler") } {
-        Ok(f) => f,
-        Err(e) => {
-            println!("Failed to load evdev_handler from library: {}", e);
-            return;
-        },
-    };
-    unsafe { f(item.as_buffer() as *const _ as *const InputEvent) };
+/// How often and how long apart opening a newly plugged in device is
+/// attempted
+const HOTPLUG_OPEN_ATTEMPTS: u32 = 10;
+const HOTPLUG_OPEN_DELAY: Duration = Duration::from_millis(100);
+
+/// Open evdev devices keyed by device node, each read by its own task.
+/// The generation lets a task that ended on its own tell whether its
+/// node has since been reused by a newly plugged in device.
+struct Devices {
+    open: Mutex<HashMap<String, (u64, JoinHandle<()>)>>,
+    generation: AtomicU64,
+    handler: Arc<Dispatcher>,
+    evdev_filter: Arc<EvdevFilter>,
+    device_filter: Arc<DeviceFilter>,
+    acpid: Arc<Acpid>,
+    idle: Arc<Idle>,
+    sleep: Arc<Sleep>,
+    throttle: Arc<Throttle<Expr>>,
+    gestures: Arc<GestureConfig>,
 }
 
-async fn event_files(handler: Arc<Library>, evdev_filter: Arc<EvdevFilter>) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut join_handles = vec![];
-    for evdev_file in evdev_files()? {
-        let file = File::open(evdev_file).await?;
-        let handler_clone = Arc::clone(&handler);
-        let evdev_filter_clone = Arc::clone(&evdev_filter);
-        join_handles.push(spawn(async move {
-            let mut evdev_stream = EvdevStream::new(file);
+impl Devices {
+    async fn open(self: &Arc<Self>, path: String, grab: bool) -> io::Result<()> {
+        let mut evdev_stream = EvdevStream::new(File::open(&path).await?);
+        if grab {
+            match evdev_stream.grab() {
+                Ok(()) => println!("Grabbed {}, other readers no longer receive its events", path),
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => println!(
+                    "Could not grab {}: another process already holds the grab; \
+                     reading it without a grab",
+                    path
+                ),
+                Err(e) => println!("Could not grab {}: {}; reading it without a grab", path, e),
+            }
+        }
+        if let Err(e) = evdev_stream.initial_state() {
+            println!("Failed to read the initial state of {}: {}", path, e);
+        }
+        // Frames are dispatched one at a time so that handlers see the
+        // events of a device in order without holding up reading it.
+        let (frames, mut queued) = mpsc::unbounded_channel::<Vec<InputEvent>>();
+        let handler = Arc::clone(&self.handler);
+        spawn(async move {
+            while let Some(frame) = queued.recv().await {
+                handler.dispatch_evdev_frame(frame).await;
+            }
+        });
+        let gestures = if self.gestures.is_empty() {
+            None
+        } else {
+            let devices = Arc::clone(self);
+            let frames = frames.clone();
+            let path = path.clone();
+            Some(Arc::new(Gestures::new(
+                Arc::clone(&self.gestures),
+                Box::new(move |event| devices.dispatch_gesture(&path, event, &frames)),
+            )))
+        };
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let devices = Arc::clone(self);
+        let task_path = path.clone();
+        let mut open = self.open.lock().expect("Lock poisoned");
+        let handle = spawn(async move {
             loop {
                 match evdev_stream.next().await {
-                    Some(Ok(event)) => {
-                        if evdev_filter_clone.contains_code(&event.get_event_code())
-                            && evdev_filter_clone.contains_type(&event.get_event_type())
-                            && evdev_filter_clone.contains_value(&event.get_event_value())
-                            || evdev_filter_clone.is_wildcard()
-                        {
-                            let _ = spawn(handle_event(Arc::clone(&handler_clone), event));
+                    Some(Ok(frame)) => {
+                        let detected = match &gestures {
+                            Some(gestures) => {
+                                frame.iter().flat_map(|event| gestures.handle(event)).collect()
+                            }
+                            None => Vec::new(),
+                        };
+                        let frame = frame
+                            .into_iter()
+                            .filter_map(|event| {
+                                // Synthetic events describe a state, not activity
+                                if event.is_synthetic() {
+                                    return Some(event)
+                                        .filter(|event| devices.evdev_filter.matches(event));
+                                }
+                                if event.get_event_type() == EV_SW
+                                    && event.get_event_code() == SW_LID
+                                    && event.get_event_value() == 1
+                                {
+                                    devices.sleep.lid_closed(&devices.handler);
+                                }
+                                let matches =
+                                    passes_evdev(&devices.evdev_filter, &devices.acpid, &event);
+                                if matches || devices.idle.reset_on_input() {
+                                    devices.idle.activity();
+                                }
+                                if !matches {
+                                    return None;
+                                }
+                                devices.throttle_event(&task_path, event, &frames)
+                            })
+                            .collect::<Vec<_>>();
+                        // Nothing is left to report if every other event
+                        // was filtered out or held back
+                        let reported = frame.iter().any(|event| event.get_event_type() != EV_SYN);
+                        if reported && frames.send(frame).is_err() {
+                            break;
+                        }
+                        for event in detected {
+                            devices.dispatch_gesture(&task_path, event, &frames);
                         }
-                    },
-                    Some(Err(e)) => return Err(e),
-                    None => return Ok(()),
+                    }
+                    Some(Err(e)) => {
+                        println!("Closing {}: {}", task_path, e);
+                        break;
+                    }
+                    None => break,
                 }
             }
-        }));
+            devices.forget(&task_path, generation);
+        });
+        if let Some((_, old)) = open.insert(path, (generation, handle)) {
+            old.abort();
+        }
+        Ok(())
     }
-    let _ = select_all(join_handles).await;
-    Ok(())
-}
 
-async fn handle_acpi_event(lib: Arc<Library>, acpi_event: AcpiEvent) {
-    type AcpiHandler<'sym> =
-        Symbol<'sym, unsafe extern "C" fn(*const AcpiEvent) -> i32>;
-    let f = match unsafe { lib.get::<AcpiHandler>(b"acpi_handler") } {
-        Ok(f) => f,
-        Err(e) => {
-            println!("Failed to load acpi_handler from library: {}", e);
+    /// Returns the event if it can be dispatched with its frame. Events
+    /// held back by a throttle rule are dispatched as frames of their own
+    /// once the rule lets them through.
+    fn throttle_event(
+        &self,
+        path: &str,
+        event: InputEvent,
+        frames: &UnboundedSender<Vec<InputEvent>>,
+    ) -> Option<InputEvent> {
+        let key = format!(
+            "{} {} {}",
+            path,
+            event.get_event_type(),
+            event.get_event_code()
+        );
+        let evdev_filter = Arc::clone(&self.evdev_filter);
+        let frames = frames.clone();
+        self.throttle.submit(key, event, move |event| {
+            let _ = frames.send(own_frame(&evdev_filter, event));
+        })
+    }
+
+    /// Dispatch a gesture detected on a device as a frame of its own
+    /// if it passes the `[evdev]` rules.
+    fn dispatch_gesture(
+        &self,
+        path: &str,
+        event: InputEvent,
+        frames: &UnboundedSender<Vec<InputEvent>>,
+    ) {
+        println!("Gesture on {}: {}", path, event);
+        if !self.evdev_filter.matches(&event) {
             return;
-        },
-    };
-    let acpi_event_buffer = match serialize(&acpi_event, false) {
-        Ok(buf) => buf,
+        }
+        if let Some(event) = self.throttle_event(path, event, frames) {
+            let _ = frames.send(own_frame(&self.evdev_filter, event));
+        }
+    }
+
+    /// Open a device that was just plugged in. udev may still be
+    /// setting up the permissions of its node, so failures are retried
+    /// for a short while.
+    async fn open_new(self: &Arc<Self>, path: String, grab: bool) -> io::Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.open(path.clone(), grab).await {
+                Err(ref e)
+                    if attempt < HOTPLUG_OPEN_ATTEMPTS
+                        && (e.kind() == io::ErrorKind::NotFound
+                            || e.kind() == io::ErrorKind::PermissionDenied) =>
+                {
+                    attempt += 1;
+                    sleep(HOTPLUG_OPEN_DELAY).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Stop reading a device that was unplugged.
+    fn close(&self, path: &str) {
+        if let Some((_, handle)) = self.open.lock().expect("Lock poisoned").remove(path) {
+            println!("Closing {} after it was removed", path);
+            handle.abort();
+        }
+    }
+
+    fn forget(&self, path: &str, generation: u64) {
+        let mut open = self.open.lock().expect("Lock poisoned");
+        if open.get(path).map(|(g, _)| *g == generation).unwrap_or(false) {
+            open.remove(path);
+        }
+    }
+}
+
+async fn event_files(devices: Arc<Devices>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Subscribe before listing the devices so that none plugged in
+    // between the two are missed.
+    let uevents = UeventSocket::new();
+    for info in evdev_devices()? {
+        if !devices.device_filter.matches(&info) {
+            println!("Ignoring {}", info);
+            continue;
+        }
+        println!("Opening {} for reading...", info);
+        if let Err(e) = devices.open(info.path(), devices.device_filter.grab(&info)).await {
+            println!("Failed to open {}: {}", info.path(), e);
+        }
+    }
+    let mut uevents = match uevents {
+        Ok(u) => u,
         Err(e) => {
-            println!("{}", e);
-            return;
+            println!("Device hotplug disabled: {}", e);
+            return future::pending().await;
         }
     };
-    unsafe { f(acpi_event_buffer.as_slice() as *const _ as *const AcpiEvent) };
+    loop {
+        let uevent = uevents.next().await?;
+        if uevent.subsystem() != Some("input") {
+            continue;
+        }
+        let (path, handler) = match uevent.get("DEVNAME") {
+            Some(devname) if devname.starts_with("input/event") => {
+                (format!("/dev/{}", devname), &devname["input/".len()..])
+            }
+            _ => continue,
+        };
+        match uevent.action.as_str() {
+            "add" => {
+                let grab = match device_info(handler) {
+                    Some(info) if devices.device_filter.matches(&info) => {
+                        println!("Opening {} for reading...", info);
+                        devices.device_filter.grab(&info)
+                    }
+                    Some(info) => {
+                        println!("Ignoring {}", info);
+                        continue;
+                    }
+                    // Without its properties the device can only be
+                    // selected if every device is.
+                    None if devices.device_filter.is_wildcard() => {
+                        println!("Opening {} for reading...", path);
+                        false
+                    }
+                    None => {
+                        println!("Ignoring {}: device properties unavailable", path);
+                        continue;
+                    }
+                };
+                if let Err(e) = devices.open_new(path.clone(), grab).await {
+                    println!("Failed to open {}: {}", path, e);
+                }
+            }
+            "remove" => devices.close(&path),
+            _ => (),
+        }
+    }
+}
+
+/// Deliver the state of every power supply at startup and again
+/// whenever it changes.
+async fn power_supply_events(
+    lib: Arc<Dispatcher>,
+    idle: Arc<Idle>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Subscribe before reading the current states so that no change in
+    // between is missed.
+    let mut events = PowerSupplyEvents::new()?;
+    for state in power_supplies() {
+        println!("Power supply {}", state);
+        let lib = Arc::clone(&lib);
+        spawn(async move { lib.dispatch_power_supply(state).await });
+    }
+    loop {
+        let state = events.next().await?;
+        println!("Power supply changed: {}", state);
+        if state.supply_type != SupplyType::Battery {
+            idle.power_changed();
+        }
+        let lib = Arc::clone(&lib);
+        spawn(async move { lib.dispatch_power_supply(state).await });
+    }
+}
+
+/// Pass an ACPI event to acpid, whose rules and clients see every
+/// event, and tell whether it passes the `[acpi]` filter.
+fn passes_acpi(acpi_filter: &AcpiFilter, acpid: &Acpid, event: &AcpiEvent) -> bool {
+    acpid.handle_acpi(event);
+    acpi_filter.matches(event)
+}
+
+/// Pass an input event to acpid, whose rules and clients see every
+/// event, and tell whether it passes the `[evdev]` rules.
+fn passes_evdev(evdev_filter: &EvdevFilter, acpid: &Acpid, event: &InputEvent) -> bool {
+    acpid.handle_evdev(event);
+    evdev_filter.matches(event)
+}
+
+/// A frame holding a single event, which ends with a `SYN_REPORT` like
+/// the frames read from a device if the `[evdev]` rules let it through
+fn own_frame(evdev_filter: &EvdevFilter, event: InputEvent) -> Vec<InputEvent> {
+    let report = InputEvent::synthetic(EV_SYN, SYN_REPORT, 0);
+    let mut frame = vec![event];
+    frame.extend(Some(report).filter(|report| evdev_filter.matches(report)));
+    frame
+}
+
+async fn handle_acpi_event(lib: Arc<Dispatcher>, acpi_event: AcpiEvent) {
+    lib.dispatch_acpi(acpi_event).await;
 }
 
 async fn create_socket_event_loop(
-    lib: Arc<Library>,
+    lib: Arc<Dispatcher>,
     acpi_filter: Arc<AcpiFilter>,
+    acpid: Arc<Acpid>,
+    idle: Arc<Idle>,
+    sleep: Arc<Sleep>,
+    throttle: Arc<Throttle<AcpiFilter>>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut socket = NlSocketHandle::connect(NlFamily::Generic, None, U32Bitmask::empty())?;
     let id = socket.resolve_nl_mcast_group("acpi_event", "acpi_mc_group")?;
@@ -99,9 +380,21 @@ async fn create_socket_event_loop(
                     Err(e) => return Err(Box::new(e)),
                 };
                 println!("{:?}", acpi_event);
-                if acpi_filter.contains_device_class(&acpi_event.device_class.0)
-                    || acpi_filter.is_wildcard() {
-                    let _ = spawn(handle_acpi_event(Arc::clone(&lib), acpi_event));
+                if acpi_event.device_class.0 == "ac_adapter" {
+                    idle.power_changed();
+                }
+                if acpi_event.device_class.0 == "button/lid" && acpi_lid_closed() {
+                    sleep.lid_closed(&lib);
+                }
+                if passes_acpi(&acpi_filter, &acpid, &acpi_event) {
+                    let key = format!("{} {}", acpi_event.device_class.0, acpi_event.bus_id.0);
+                    let deliver_lib = Arc::clone(&lib);
+                    let deliver = move |acpi_event| {
+                        spawn(handle_acpi_event(deliver_lib, acpi_event));
+                    };
+                    if let Some(acpi_event) = throttle.submit(key, acpi_event, deliver) {
+                        spawn(handle_acpi_event(Arc::clone(&lib), acpi_event));
+                    }
                 }
             },
             Some(Err(e)) => return Err(Box::new(e)),
@@ -110,17 +403,121 @@ async fn create_socket_event_loop(
     }
 }
 
-pub fn new_event_loop(
-    lib_path: &str,
-    acpi_filter: Arc<AcpiFilter>,
-    evdev_filter: Arc<EvdevFilter>,
-) -> Result<(), Box<dyn Error>> {
-    let lib = Arc::new(Library::new(lib_path)?);
+/// Subscribe to the thermal generic netlink family. Kernels without
+/// thermal netlink support only lose thermal events.
+async fn create_thermal_event_loop(
+    lib: Arc<Dispatcher>,
+    thermal_filter: ThermalFilter,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut socket = NlSocketHandle::connect(NlFamily::Generic, None, U32Bitmask::empty())?;
+    let id = socket.resolve_nl_mcast_group(THERMAL_FAMILY, THERMAL_EVENT_GROUP)?;
+    socket.add_mcast_membership(U32Bitmask::from(U32BitFlag::new(id)?))?;
+
+    let mut socket = NlSocket::new(socket)?;
+    loop {
+        match socket.next().await {
+            Some(Ok(msg)) => {
+                let event = match thermal_event(msg)? {
+                    Some(event) => event,
+                    None => continue,
+                };
+                println!("Thermal event: {}", event);
+                if thermal_filter.matches(&event) {
+                    let lib = Arc::clone(&lib);
+                    spawn(async move { lib.dispatch_thermal(event).await });
+                }
+            },
+            Some(Err(e)) => return Err(Box::new(e)),
+            None => return Ok(()),
+        }
+    }
+}
+
+pub fn new_event_loop(config: CfgFile, config_path: &str) -> Result<(), Box<dyn Error>> {
+    let CfgFile {
+        acpi,
+        acpid: acpid_config,
+        devices,
+        evdev,
+        thermal,
+        daemon: daemon_config,
+        plugins,
+        timer,
+        suspend,
+        throttle,
+        gestures,
+    } = config;
+    let acpi_throttle = Arc::new(Throttle::new(throttle.acpi));
+    let evdev_throttle = Arc::new(Throttle::new(throttle.evdev));
+    let acpi_filter = Arc::new(acpi);
+    let evdev_filter = Arc::new(evdev);
+    let device_filter = Arc::new(devices);
+    let lib = Arc::new(Dispatcher::load(
+        plugins,
+        config_path,
+        daemon_config,
+        Arc::clone(&evdev_filter),
+    )?);
     let runtime = Runtime::new()?;
-    runtime.block_on(async move {
+    let result = runtime.block_on(async move {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
         let lib_clone = Arc::clone(&lib);
+        let fatal_lib = Arc::clone(&lib);
+        for handle in lib.handles() {
+            let handle = Arc::clone(handle);
+            spawn(async move {
+                let path = handle.path().to_string();
+                if let Err(e) = reload_loop(handle, daemon_config.watch_plugins).await {
+                    println!("Reloading disabled for plugin {}: {}", path, e);
+                }
+            });
+        }
+        let acpid = Arc::new(Acpid::new(&acpid_config)?);
+        if acpid.rules().is_some() {
+            let acpid = Arc::clone(&acpid);
+            let mut sighup = signal(SignalKind::hangup())?;
+            spawn(async move {
+                while sighup.recv().await.is_some() {
+                    match acpid.rules().map(|rules| rules.reload()) {
+                        Some(Ok(count)) => println!("Reloaded {} acpid rules", count),
+                        Some(Err(e)) => println!("Keeping previously loaded acpid rules: {}", e),
+                        None => (),
+                    }
+                }
+            });
+        }
+        let idle = Arc::new(Idle::new(timer));
+        spawn(Arc::clone(&idle).run(Arc::clone(&lib)));
+        let power_lib = Arc::clone(&lib);
+        let power_idle = Arc::clone(&idle);
+        spawn(async move {
+            if let Err(e) = power_supply_events(power_lib, power_idle).await {
+                println!("Power supply events disabled: {}", e);
+            }
+        });
+        let thermal_lib = Arc::clone(&lib);
+        spawn(async move {
+            if let Err(e) = create_thermal_event_loop(thermal_lib, thermal).await {
+                println!("Thermal events disabled: {}", e);
+            }
+        });
+        let sleep = Arc::new(Sleep::new(suspend));
+        spawn(Arc::clone(&sleep).watch_resume(Arc::clone(&lib)));
+        let devices = Arc::new(Devices {
+            open: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            handler: lib_clone,
+            evdev_filter,
+            device_filter,
+            acpid: Arc::clone(&acpid),
+            idle: Arc::clone(&idle),
+            sleep: Arc::clone(&sleep),
+            throttle: evdev_throttle,
+            gestures: Arc::new(gestures),
+        });
         let evdev_handle = spawn(async move {
-            if let Err(e) = event_files(lib_clone, evdev_filter).await {
+            if let Err(e) = event_files(devices).await {
                 println!("{}", e);
             }
         });
@@ -128,6 +525,10 @@ pub fn new_event_loop(
             if let Err(e) = create_socket_event_loop(
                 lib,
                 acpi_filter,
+                acpid,
+                idle,
+                sleep,
+                acpi_throttle,
             ).await {
                 println!("{}", e);
             }
@@ -139,8 +540,103 @@ pub fn new_event_loop(
             _ = netlink_handle.fuse() => {
                 println!("netlink handler exited");
             }
+            _ = sigterm.recv().fuse() => {
+                println!("Received SIGTERM, shutting down");
+            }
+            _ = sigint.recv().fuse() => {
+                println!("Received SIGINT, shutting down");
+            }
+            _ = fatal_lib.fatal().fuse() => {
+                println!("Shutting down after fatal plugin return code");
+            }
         };
+        Ok(())
     });
+    // Dropping the runtime drops every task still holding a reference to
+    // the plugins so that pwrsurge_shutdown runs before the process exits.
+    drop(runtime);
 
-    Ok(())
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::{io::AsyncReadExt, net::UnixStream, time::timeout};
+
+    use crate::{
+        acpi::{BusId, DeviceClass},
+        args::AcpidConfig,
+        evdev::EV_KEY,
+        filter::{EvdevRule, Patterns, StringPattern},
+    };
+
+    #[test]
+    fn test_acpid_sees_filtered_events() {
+        Runtime::new().unwrap().block_on(async {
+            let path = std::env::temp_dir()
+                .join(format!("pwrsurge-acpid-{}.socket", std::process::id()));
+            let acpid = Acpid::new(&AcpidConfig {
+                socket: Some(Box::from(path.to_str().unwrap())),
+                ..AcpidConfig::default()
+            })
+            .unwrap();
+            let mut client = UnixStream::connect(&path).await.unwrap();
+            let subscribed = async {
+                while acpid.socket_clients() == 0 {
+                    sleep(Duration::from_millis(1)).await;
+                }
+            };
+            timeout(Duration::from_secs(5), subscribed).await.unwrap();
+
+            let acpi_filter = AcpiFilter::new(
+                Patterns::new(vec![StringPattern::Glob("battery".to_string())], Vec::new()),
+                Patterns::default(),
+                Patterns::default(),
+                Patterns::default(),
+            );
+            let lid = AcpiEvent {
+                device_class: DeviceClass("button/lid".to_string()),
+                bus_id: BusId("LID".to_string()),
+                event_type: 0x80,
+                event_data: 1,
+            };
+            assert!(!passes_acpi(&acpi_filter, &acpid, &lid));
+            let evdev_filter =
+                EvdevFilter::new(vec![EvdevRule::parse("lid", "type=EV_SW").unwrap()]);
+            let power = InputEvent::synthetic(EV_KEY, 116, 1);
+            assert!(!passes_evdev(&evdev_filter, &acpid, &power));
+
+            let expected = "button/lid LID 00000080 00000001\n\
+                            button/power PBTN 00000080 00000000\n";
+            let mut buf = vec![0; expected.len()];
+            timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+
+            drop(acpid);
+            assert!(!path.exists());
+        })
+    }
+
+    #[test]
+    fn test_own_frame() {
+        let lid = InputEvent::synthetic(EV_SW, SW_LID, 1);
+        let frame = |evdev_filter: &EvdevFilter| {
+            own_frame(evdev_filter, lid)
+                .iter()
+                .map(|e| (e.get_event_type(), e.get_event_code(), e.get_event_value()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            frame(&EvdevFilter::new(Vec::new())),
+            vec![(EV_SW, SW_LID, 1), (EV_SYN, SYN_REPORT, 0)]
+        );
+        // The rules decide whether the frame ends with a SYN_REPORT
+        let switches = EvdevFilter::new(vec![EvdevRule::parse("lid", "type=EV_SW").unwrap()]);
+        assert_eq!(frame(&switches), vec![(EV_SW, SW_LID, 1)]);
+    }
 }
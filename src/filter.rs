@@ -1,57 +1,509 @@
+use regex::Regex;
+
+use crate::{
+    acpi::AcpiEvent,
+    evdev::{DeviceInfo, InputEvent},
+    rule::{Expr, Field, RuleError},
+    thermal::{ThermalEvent, ThermalEventKind},
+};
+
+/// A pattern for a property of an event
+pub trait Pattern {
+    type Value: ?Sized;
+
+    fn matches(&self, value: &Self::Value) -> bool;
+}
+
+/// Matches a string against a shell style pattern, see `glob_match`, or
+/// a regular expression.
+#[derive(Debug)]
+pub enum StringPattern {
+    Glob(String),
+    Regex(Regex),
+}
+
+impl Pattern for StringPattern {
+    type Value = str;
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            StringPattern::Glob(pattern) => glob_match(pattern, value),
+            StringPattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+/// Matches a number whose bits selected by `mask` equal those of `value`
+#[derive(Debug, PartialEq)]
+pub struct MaskPattern {
+    pub value: u32,
+    pub mask: u32,
+}
+
+impl Pattern for MaskPattern {
+    type Value = u32;
+
+    fn matches(&self, value: &u32) -> bool {
+        value & self.mask == self.value & self.mask
+    }
+}
+
+/// Whitelisted and blacklisted patterns for one property of an event.
+/// A value passes if it matches any whitelisted pattern, or there are
+/// none, and no blacklisted pattern.
+pub struct Patterns<P> {
+    whitelist: Vec<P>,
+    blacklist: Vec<P>,
+}
+
+impl<P: Pattern> Patterns<P> {
+    pub fn new(whitelist: Vec<P>, blacklist: Vec<P>) -> Self {
+        Patterns {
+            whitelist,
+            blacklist,
+        }
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.whitelist.is_empty() && self.blacklist.is_empty()
+    }
+
+    pub fn matches(&self, value: &P::Value) -> bool {
+        (self.whitelist.is_empty() || self.whitelist.iter().any(|p| p.matches(value)))
+            && !self.blacklist.iter().any(|p| p.matches(value))
+    }
+}
+
+impl<P> Default for Patterns<P> {
+    fn default() -> Self {
+        Patterns {
+            whitelist: Vec::new(),
+            blacklist: Vec::new(),
+        }
+    }
+}
+
+/// Selects ACPI events by device class, bus ID, type and data. An
+/// event passes if each of them passes its patterns.
+#[derive(Default)]
 pub struct AcpiFilter {
-    device_class_whitelist: Vec<String>,
+    device_class: Patterns<StringPattern>,
+    bus_id: Patterns<StringPattern>,
+    event_type: Patterns<MaskPattern>,
+    event_data: Patterns<MaskPattern>,
 }
 
 impl AcpiFilter {
-    pub fn new(whitelist: Vec<String>) -> Self {
+    pub fn new(
+        device_class: Patterns<StringPattern>,
+        bus_id: Patterns<StringPattern>,
+        event_type: Patterns<MaskPattern>,
+        event_data: Patterns<MaskPattern>,
+    ) -> Self {
         AcpiFilter {
-            device_class_whitelist: whitelist,
+            device_class,
+            bus_id,
+            event_type,
+            event_data,
         }
     }
 
-    pub fn contains_device_class(&self, dev_class: &String) -> bool {
-        self.device_class_whitelist.contains(dev_class)
+    pub fn contains_device_class(&self, dev_class: &str) -> bool {
+        self.device_class.matches(dev_class)
     }
 
     pub fn is_wildcard(&self) -> bool {
-        self.device_class_whitelist.len() == 0
+        self.device_class.is_wildcard()
+            && self.bus_id.is_wildcard()
+            && self.event_type.is_wildcard()
+            && self.event_data.is_wildcard()
+    }
+
+    pub fn matches(&self, event: &AcpiEvent) -> bool {
+        self.is_wildcard()
+            || self.contains_device_class(&event.device_class.0)
+                && self.bus_id.matches(event.bus_id.0.as_str())
+                && self.event_type.matches(&event.event_type)
+                && self.event_data.matches(&event.event_data)
     }
 }
 
+/// A named rule of an evdev filter and the plugins the events matching
+/// it are routed to. Events are routed to every plugin if `plugins` is
+/// empty.
+#[derive(Debug, PartialEq)]
+pub struct EvdevRule {
+    pub name: String,
+    pub expr: Expr,
+    pub plugins: Vec<String>,
+}
+
+impl EvdevRule {
+    /// Parse `EXPR` or `EXPR -> PLUGIN,...`.
+    pub fn parse(name: &str, rule: &str) -> Result<Self, RuleError> {
+        let (expr, plugins) = match rule.find("->") {
+            Some(i) => (&rule[..i], &rule[i + 2..]),
+            None => (rule, ""),
+        };
+        let plugins = plugins
+            .split(",")
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        if rule.contains("->") && plugins.is_empty() {
+            return Err(RuleError(format!("Rule {} routes to no plugin", name)));
+        }
+        Ok(EvdevRule {
+            name: name.to_string(),
+            expr: Expr::parse(expr.trim())?,
+            plugins,
+        })
+    }
+
+    /// A rule with the semantics of the flat type, code and value
+    /// whitelists: each list that is not empty must contain the
+    /// respective field. Returns `None` if all of them are empty.
+    pub fn from_whitelists(types: &[u16], codes: &[u16], values: &[i32]) -> Option<Self> {
+        let any_of = |field, values: Vec<i64>| {
+            Expr::Or(
+                values
+                    .into_iter()
+                    .map(|v| Expr::Range {
+                        field,
+                        min: v,
+                        max: v,
+                    })
+                    .collect(),
+            )
+        };
+        let mut exprs = Vec::new();
+        if !types.is_empty() {
+            exprs.push(any_of(Field::Type, types.iter().map(|&v| v.into()).collect()));
+        }
+        if !codes.is_empty() {
+            exprs.push(any_of(Field::Code, codes.iter().map(|&v| v.into()).collect()));
+        }
+        if !values.is_empty() {
+            exprs.push(any_of(Field::Value, values.iter().map(|&v| v.into()).collect()));
+        }
+        if exprs.is_empty() {
+            return None;
+        }
+        Some(EvdevRule {
+            name: "whitelist".to_string(),
+            expr: Expr::And(exprs),
+            plugins: Vec::new(),
+        })
+    }
+}
+
+/// Selects evdev events with rules. An event passes if any rule matches
+/// it, or there are no rules.
 pub struct EvdevFilter {
-    evdev_type_whitelist: Vec<u16>,
-    evdev_code_whitelist: Vec<u16>,
-    evdev_value_whitelist: Vec<i32>,
+    rules: Vec<EvdevRule>,
 }
 
 impl EvdevFilter {
+    pub fn new(rules: Vec<EvdevRule>) -> Self {
+        EvdevFilter { rules }
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn matches(&self, event: &InputEvent) -> bool {
+        self.is_wildcard() || self.rules.iter().any(|rule| rule.expr.matches(event))
+    }
+
+    /// Whether the event is routed to the plugin `name`: some rule that
+    /// matches it routes to all plugins or to this one.
+    pub fn routes_to(&self, event: &InputEvent, name: &str) -> bool {
+        self.is_wildcard()
+            || self.rules.iter().any(|rule| {
+                rule.expr.matches(event)
+                    && (rule.plugins.is_empty() || rule.plugins.iter().any(|p| p == name))
+            })
+    }
+
+    /// Plugins that rules route to
+    pub fn routes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rules.iter().flat_map(|rule| {
+            rule.plugins
+                .iter()
+                .map(move |plugin| (rule.name.as_str(), plugin.as_str()))
+        })
+    }
+}
+
+pub struct ThermalFilter {
+    event_whitelist: Vec<ThermalEventKind>,
+    type_whitelist: Vec<String>,
+}
+
+impl ThermalFilter {
+    pub fn new(event_whitelist: Vec<ThermalEventKind>, type_whitelist: Vec<String>) -> Self {
+        ThermalFilter {
+            event_whitelist,
+            type_whitelist,
+        }
+    }
+
+    /// The type is that of the thermal zone or cooling device.
+    pub fn matches(&self, event: &ThermalEvent) -> bool {
+        (self.event_whitelist.is_empty() || self.event_whitelist.contains(&event.kind()))
+            && (self.type_whitelist.is_empty()
+                || self.type_whitelist.iter().any(|t| t == event.device_type()))
+    }
+}
+
+/// Match `text` against a shell style pattern where `*` matches any
+/// sequence of characters and `?` any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it matched up to
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// A property of an input device
+#[derive(Debug, PartialEq)]
+pub enum DeviceMatcher {
+    Name(String),
+    Phys(String),
+    Sysfs(String),
+    Bus(u16),
+    Vendor(u16),
+    Product(u16),
+    /// Device supports the event type
+    Ev(u16),
+    /// Device has the key or button
+    Key(u16),
+    /// Device has the switch
+    Sw(u16),
+}
+
+impl DeviceMatcher {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        match self {
+            DeviceMatcher::Name(pattern) => glob_match(pattern, &info.name),
+            DeviceMatcher::Phys(pattern) => glob_match(pattern, &info.phys),
+            DeviceMatcher::Sysfs(pattern) => glob_match(pattern, &info.sysfs),
+            DeviceMatcher::Bus(bus) => info.bus == *bus,
+            DeviceMatcher::Vendor(vendor) => info.vendor == *vendor,
+            DeviceMatcher::Product(product) => info.product == *product,
+            DeviceMatcher::Ev(ev) => info.ev.has(*ev),
+            DeviceMatcher::Key(key) => info.key.has(*key),
+            DeviceMatcher::Sw(sw) => info.sw.has(*sw),
+        }
+    }
+}
+
+/// Selects the input devices that are opened. A device is opened if it
+/// matches any of the include matchers, or there are none, and none of
+/// the exclude matchers. Opened devices matching any of the grab
+/// matchers are read exclusively.
+pub struct DeviceFilter {
+    include: Vec<DeviceMatcher>,
+    exclude: Vec<DeviceMatcher>,
+    grab: Vec<DeviceMatcher>,
+}
+
+impl DeviceFilter {
     pub fn new(
-        type_whitelist: Vec<u16>,
-        code_whitelist: Vec<u16>,
-        value_whitelist: Vec<i32>,
+        include: Vec<DeviceMatcher>,
+        exclude: Vec<DeviceMatcher>,
+        grab: Vec<DeviceMatcher>,
     ) -> Self {
-        EvdevFilter {
-            evdev_type_whitelist: type_whitelist,
-            evdev_code_whitelist: code_whitelist,
-            evdev_value_whitelist: value_whitelist,
+        DeviceFilter {
+            include,
+            exclude,
+            grab,
         }
     }
 
-    pub fn contains_type(&self, ty: &u16) -> bool {
-        self.evdev_type_whitelist.contains(ty) || self.evdev_type_whitelist.is_empty()
+    pub fn is_wildcard(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
     }
 
-    pub fn contains_code(&self, code: &u16) -> bool {
-        self.evdev_code_whitelist.contains(code) || self.evdev_code_whitelist.is_empty()
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|m| m.matches(info)))
+            && !self.exclude.iter().any(|m| m.matches(info))
     }
 
-    pub fn contains_value(&self, value: &i32) -> bool {
-        self.evdev_value_whitelist.contains(value) || self.evdev_value_whitelist.is_empty()
+    pub fn grab(&self, info: &DeviceInfo) -> bool {
+        self.grab.iter().any(|m| m.matches(info))
     }
+}
 
-    pub fn is_wildcard(&self) -> bool {
-        self.evdev_type_whitelist.is_empty()
-            && self.evdev_code_whitelist.is_empty()
-            && self.evdev_value_whitelist.is_empty()
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::acpi::{BusId, DeviceClass};
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("Lid Switch", "Lid Switch"));
+        assert!(!glob_match("Lid Switch", "Lid Switch 2"));
+        assert!(glob_match("*Mic*", "HDA Intel PCH Mic"));
+        assert!(glob_match("PNP0C0D/*", "PNP0C0D/button/input0"));
+        assert!(glob_match("event?", "event3"));
+        assert!(!glob_match("event?", "event13"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn test_acpi_filter() {
+        let event = |class: &str, bus_id: &str, event_type, event_data| AcpiEvent {
+            device_class: DeviceClass(class.to_string()),
+            bus_id: BusId(bus_id.to_string()),
+            event_type,
+            event_data,
+        };
+        let lid = event("button/lid", "LID0", 0x80, 1);
+        let power = event("button/power", "PBTN", 0x80, 1);
+        let ac = event("ac_adapter", "ACPI0003:00", 0x80, 0);
+
+        let filter = AcpiFilter::default();
+        assert!(filter.is_wildcard());
+        assert!(filter.matches(&lid) && filter.matches(&ac));
+
+        let filter = AcpiFilter::new(
+            Patterns::new(
+                vec![
+                    StringPattern::Glob("button/*".to_string()),
+                    StringPattern::Regex(Regex::new("^ac_").unwrap()),
+                ],
+                Vec::new(),
+            ),
+            Patterns::new(Vec::new(), vec![StringPattern::Glob("PBTN".to_string())]),
+            Patterns::new(
+                vec![MaskPattern {
+                    value: 0x80,
+                    mask: 0xf0,
+                }],
+                Vec::new(),
+            ),
+            Patterns::new(
+                Vec::new(),
+                vec![MaskPattern {
+                    value: 0,
+                    mask: u32::MAX,
+                }],
+            ),
+        );
+        assert!(filter.matches(&lid));
+        assert!(!filter.matches(&power));
+        assert!(!filter.matches(&ac));
+        assert!(filter.matches(&event("ac_adapter", "ACPI0003:00", 0x81, 1)));
+        assert!(!filter.matches(&event("button/lid", "LID0", 0x90, 1)));
+        assert!(!filter.matches(&event("battery", "PNP0C0A:00", 0x80, 1)));
+    }
+
+    #[test]
+    fn test_evdev_filter() {
+        let lid = InputEvent::synthetic(5, 0, 1);
+        let power = InputEvent::synthetic(1, 116, 1);
+        let mixed = InputEvent::synthetic(5, 116, 1);
+
+        let filter = EvdevFilter::new(Vec::new());
+        assert!(filter.matches(&lid) && filter.routes_to(&lid, "any"));
+
+        let filter = EvdevFilter::new(vec![
+            EvdevRule::from_whitelists(&[1, 5], &[116, 0], &[]).unwrap()
+        ]);
+        assert!(filter.matches(&lid) && filter.matches(&power));
+        assert!(filter.matches(&mixed));
+
+        let filter = EvdevFilter::new(vec![
+            EvdevRule::parse("lid", "type=5 && code=0 && value=1 -> lid").unwrap(),
+            EvdevRule::parse("power", "type=1 && code=116").unwrap(),
+        ]);
+        assert!(filter.matches(&lid) && filter.matches(&power));
+        assert!(!filter.matches(&mixed));
+        assert!(filter.routes_to(&lid, "lid"));
+        assert!(!filter.routes_to(&lid, "battery"));
+        assert!(filter.routes_to(&power, "battery"));
+        assert_eq!(filter.routes().collect::<Vec<_>>(), vec![("lid", "lid")]);
+
+        assert!(EvdevRule::from_whitelists(&[], &[], &[]).is_none());
+        assert!(EvdevRule::parse("lid", "type=5 ->").is_err());
+        assert_eq!(
+            EvdevRule::parse("lid", "type=5 -> lid, logger").unwrap().plugins,
+            vec!["lid".to_string(), "logger".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_device_filter() {
+        let mut lid = DeviceInfo {
+            name: "Lid Switch".to_string(),
+            phys: "PNP0C0D/button/input0".to_string(),
+            handler: "event0".to_string(),
+            ..Default::default()
+        };
+        let mic = DeviceInfo {
+            name: "HDA Intel PCH Mic".to_string(),
+            phys: "ALSA".to_string(),
+            handler: "event8".to_string(),
+            ..Default::default()
+        };
+
+        let filter = DeviceFilter::new(Vec::new(), Vec::new(), Vec::new());
+        assert!(filter.is_wildcard());
+        assert!(filter.matches(&lid) && filter.matches(&mic));
+        assert!(!filter.grab(&lid));
+
+        let filter = DeviceFilter::new(
+            Vec::new(),
+            vec![DeviceMatcher::Name("*Mic".to_string())],
+            Vec::new(),
+        );
+        assert!(filter.matches(&lid));
+        assert!(!filter.matches(&mic));
+
+        let filter = DeviceFilter::new(
+            vec![DeviceMatcher::Phys("PNP0C0D/*".to_string())],
+            Vec::new(),
+            vec![DeviceMatcher::Name("Lid Switch".to_string())],
+        );
+        assert!(filter.matches(&lid));
+        assert!(!filter.matches(&mic));
+        assert!(filter.grab(&lid));
+        assert!(!filter.grab(&mic));
+
+        lid.bus = 0x19;
+        let filter = DeviceFilter::new(
+            vec![DeviceMatcher::Bus(0x19)],
+            vec![DeviceMatcher::Name("Lid*".to_string())],
+            Vec::new(),
+        );
+        assert!(!filter.matches(&lid));
     }
 }
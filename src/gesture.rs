@@ -0,0 +1,277 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{spawn, time::sleep};
+
+use crate::{
+    args::GestureConfig,
+    evdev::{InputEvent, EV_KEY},
+};
+
+/// Type of the gesture events. It is above `EV_MAX`, so the kernel never
+/// reports events of this type.
+pub const EV_GESTURE: u16 = 0x20;
+
+/// Values of `EV_GESTURE` events, whose code is the key they were
+/// detected on
+pub const GESTURE_SHORT_PRESS: i32 = 1;
+pub const GESTURE_LONG_PRESS: i32 = 2;
+pub const GESTURE_DOUBLE_PRESS: i32 = 3;
+/// The code of a chord is that of the last key of its definition
+pub const GESTURE_CHORD: i32 = 4;
+
+#[derive(Default)]
+struct KeyState {
+    /// Bumped on every press and release, so that a timer can tell
+    /// whether the key changed since it was started
+    generation: u64,
+    held: bool,
+    /// The current press was already reported as a long or double press
+    /// or as part of a chord
+    reported: bool,
+    /// Released after a short press that becomes a double press if the
+    /// key is pressed again in time
+    released: bool,
+}
+
+#[derive(Default)]
+struct State {
+    keys: HashMap<u16, KeyState>,
+    /// Keys of any chord that are held down
+    held: HashSet<u16>,
+}
+
+type Emit = Box<dyn Fn(InputEvent) + Send + Sync>;
+
+/// Detects gestures in the key events of one device. Gestures completed
+/// by an event are returned when it is handled; those completed by time
+/// passing, such as a long press of a key that is still held, are passed
+/// to `emit`.
+pub struct Gestures {
+    config: Arc<GestureConfig>,
+    state: Mutex<State>,
+    emit: Emit,
+}
+
+fn gesture(code: u16, kind: i32, timestamp: libc::timeval) -> InputEvent {
+    let mut event = InputEvent::synthetic(EV_GESTURE, code, kind);
+    event.set_timestamp(timestamp);
+    event
+}
+
+/// The current time on the clock evdev timestamps are taken from
+fn now() -> libc::timeval {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    libc::timeval {
+        tv_sec: now.as_secs() as libc::time_t,
+        tv_usec: now.subsec_micros() as libc::suseconds_t,
+    }
+}
+
+impl Gestures {
+    pub fn new(config: Arc<GestureConfig>, emit: Emit) -> Self {
+        Gestures {
+            config,
+            state: Mutex::new(State::default()),
+            emit,
+        }
+    }
+
+    pub fn handle(self: &Arc<Self>, event: &InputEvent) -> Vec<InputEvent> {
+        if event.get_event_type() != EV_KEY || event.is_synthetic() {
+            return Vec::new();
+        }
+        let code = event.get_event_code();
+        // Autorepeats do not change what is held down
+        let pressed = match event.get_event_value() {
+            0 => false,
+            1 => true,
+            _ => return Vec::new(),
+        };
+        let timestamp = event.get_timestamp();
+        let mut gestures = Vec::new();
+        let mut state = self.state.lock().expect("Lock poisoned");
+        let mut chorded = Vec::new();
+        if pressed {
+            state.held.insert(code);
+            // A chord completes when its last key is pressed while all
+            // the others are held down
+            for chord in &self.config.chords {
+                if chord.keys.last() == Some(&code)
+                    && chord.keys.iter().all(|key| state.held.contains(key))
+                {
+                    gestures.push(gesture(code, GESTURE_CHORD, timestamp));
+                    chorded.extend(&chord.keys);
+                }
+            }
+        } else {
+            state.held.remove(&code);
+        }
+        let mut timer = None;
+        if self.config.keys.contains(&code) {
+            let key = state.keys.entry(code).or_default();
+            key.generation += 1;
+            key.held = pressed;
+            if pressed && key.released {
+                key.released = false;
+                key.reported = true;
+                gestures.push(gesture(code, GESTURE_DOUBLE_PRESS, timestamp));
+            } else if pressed {
+                key.reported = false;
+                timer = Some((self.config.long_press, key.generation));
+            } else if !key.reported {
+                match self.config.double_press {
+                    Some(window) => {
+                        key.released = true;
+                        timer = Some((window, key.generation));
+                    }
+                    None => gestures.push(gesture(code, GESTURE_SHORT_PRESS, timestamp)),
+                }
+            }
+        }
+        // Keys pressed as part of a chord are not also pressed on their own
+        for code in chorded {
+            if let Some(key) = state.keys.get_mut(&code) {
+                key.reported = key.held;
+            }
+        }
+        drop(state);
+        if let Some((duration, generation)) = timer {
+            let gestures = Arc::clone(self);
+            spawn(async move {
+                sleep(duration).await;
+                gestures.expire(code, generation);
+            });
+        }
+        gestures
+    }
+
+    /// Report a long press of a key that is still held or a short press
+    /// that was not followed by a second one.
+    fn expire(&self, code: u16, generation: u64) {
+        let mut state = self.state.lock().expect("Lock poisoned");
+        let key = match state.keys.get_mut(&code) {
+            Some(key) if key.generation == generation && !(key.held && key.reported) => key,
+            _ => return,
+        };
+        let kind = if key.held {
+            key.reported = true;
+            GESTURE_LONG_PRESS
+        } else {
+            key.released = false;
+            GESTURE_SHORT_PRESS
+        };
+        drop(state);
+        (self.emit)(gesture(code, kind, now()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::{runtime::Runtime, sync::mpsc};
+
+    use crate::args::Chord;
+
+    const KEY_LEFTCTRL: u16 = 29;
+    const KEY_F4: u16 = 62;
+    const KEY_POWER: u16 = 116;
+
+    fn config(double_press: Option<Duration>) -> GestureConfig {
+        GestureConfig {
+            keys: vec![KEY_POWER, KEY_F4],
+            long_press: Duration::from_millis(200),
+            double_press,
+            chords: vec![Chord {
+                name: "close".to_string(),
+                keys: vec![KEY_LEFTCTRL, KEY_F4],
+            }],
+        }
+    }
+
+    /// Feed key events this many milliseconds apart and collect the
+    /// values and codes of the gestures returned and emitted later
+    fn run(config: GestureConfig, keys: &[(u64, u16, i32)]) -> Vec<(i32, u16)> {
+        Runtime::new().unwrap().block_on(async {
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            let emitted = sender.clone();
+            let gestures = Arc::new(Gestures::new(
+                Arc::new(config),
+                Box::new(move |event| {
+                    let _ = emitted.send(event);
+                }),
+            ));
+            for &(delay, code, value) in keys {
+                sleep(Duration::from_millis(delay)).await;
+                let mut event = InputEvent::synthetic(EV_KEY, code, value);
+                event.set_timestamp(now());
+                for gesture in gestures.handle(&event) {
+                    let _ = sender.send(gesture);
+                }
+            }
+            sleep(Duration::from_millis(400)).await;
+            drop(sender);
+            drop(gestures);
+            let mut detected = Vec::new();
+            while let Ok(event) = receiver.try_recv() {
+                assert_eq!(event.get_event_type(), EV_GESTURE);
+                assert!(!event.is_synthetic());
+                detected.push((event.get_event_value(), event.get_event_code()));
+            }
+            detected
+        })
+    }
+
+    #[test]
+    fn test_gestures() {
+        let double = Some(Duration::from_millis(100));
+        let tap = [(0, KEY_POWER, 1), (10, KEY_POWER, 0)];
+        assert_eq!(run(config(double), &tap), vec![(GESTURE_SHORT_PRESS, KEY_POWER)]);
+        // Reported on release without a double press window
+        assert_eq!(run(config(None), &tap), vec![(GESTURE_SHORT_PRESS, KEY_POWER)]);
+
+        let hold = [(0, KEY_POWER, 1), (10, KEY_POWER, 2), (300, KEY_POWER, 0)];
+        assert_eq!(run(config(double), &hold), vec![(GESTURE_LONG_PRESS, KEY_POWER)]);
+
+        let double_tap = [
+            (0, KEY_POWER, 1),
+            (10, KEY_POWER, 0),
+            (10, KEY_POWER, 1),
+            (10, KEY_POWER, 0),
+        ];
+        assert_eq!(
+            run(config(double), &double_tap),
+            vec![(GESTURE_DOUBLE_PRESS, KEY_POWER)]
+        );
+
+        let chord = [
+            (0, KEY_LEFTCTRL, 1),
+            (10, KEY_F4, 1),
+            (10, KEY_F4, 0),
+            (10, KEY_LEFTCTRL, 0),
+        ];
+        assert_eq!(run(config(double), &chord), vec![(GESTURE_CHORD, KEY_F4)]);
+        // Pressing the keys of a chord in another order is no chord
+        let out_of_order = [
+            (0, KEY_F4, 1),
+            (10, KEY_LEFTCTRL, 1),
+            (10, KEY_LEFTCTRL, 0),
+            (10, KEY_F4, 0),
+        ];
+        assert_eq!(
+            run(config(double), &out_of_order),
+            vec![(GESTURE_SHORT_PRESS, KEY_F4)]
+        );
+
+        // Untracked keys are ignored
+        assert_eq!(run(config(double), &[(0, 30, 1), (300, 30, 0)]), vec![]);
+    }
+}
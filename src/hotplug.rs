@@ -0,0 +1,116 @@
+use std::{collections::HashMap, error::Error, io, str};
+
+use neli::{
+    consts::socket::NlFamily,
+    socket::NlSocket,
+    utils::{U32BitFlag, U32Bitmask},
+};
+use tokio::io::unix::AsyncFd;
+
+/// Multicast group of the uevents sent by the kernel itself, as opposed
+/// to the ones udev rebroadcasts after processing them
+const UEVENT_KERNEL_GROUP: u32 = 1;
+
+/// Large enough for any uevent; the kernel limits their environment to
+/// 2048 bytes.
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+/// A kernel uevent announcing a change to a device
+#[derive(Debug, PartialEq)]
+pub struct Uevent {
+    pub action: String,
+    pub devpath: String,
+    pub vars: HashMap<String, String>,
+}
+
+impl Uevent {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(|s| s.as_str())
+    }
+
+    pub fn subsystem(&self) -> Option<&str> {
+        self.get("SUBSYSTEM")
+    }
+}
+
+/// Parse a uevent in the kernel's format: a `ACTION@DEVPATH` header
+/// followed by `KEY=VALUE` pairs, all terminated by null bytes.
+fn parse_uevent(buf: &[u8]) -> Option<Uevent> {
+    let mut fields = buf
+        .split(|b| *b == 0)
+        .filter(|f| !f.is_empty())
+        .filter_map(|f| str::from_utf8(f).ok());
+    let header = fields.next()?;
+    let at = header.find('@')?;
+    let vars = fields
+        .filter_map(|f| {
+            let eq = f.find('=')?;
+            Some((f[..eq].to_string(), f[eq + 1..].to_string()))
+        })
+        .collect();
+    Some(Uevent {
+        action: header[..at].to_string(),
+        devpath: header[at + 1..].to_string(),
+        vars,
+    })
+}
+
+/// Netlink socket subscribed to kernel uevents
+pub struct UeventSocket(AsyncFd<NlSocket>);
+
+impl UeventSocket {
+    pub fn new() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let socket = NlSocket::connect(
+            NlFamily::KobjectUevent,
+            None,
+            U32Bitmask::from(U32BitFlag::new(UEVENT_KERNEL_GROUP)?),
+        )?;
+        socket.nonblock()?;
+        Ok(UeventSocket(AsyncFd::new(socket)?))
+    }
+
+    /// Wait for the next well formed uevent.
+    pub async fn next(&mut self) -> io::Result<Uevent> {
+        let mut buf = vec![0; UEVENT_BUFFER_SIZE];
+        loop {
+            let mut guard = self.0.readable().await?;
+            match self.0.get_ref().recv(&mut buf[..], 0) {
+                Ok(n) => {
+                    if let Some(uevent) = parse_uevent(&buf[..n]) {
+                        return Ok(uevent);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => guard.clear_ready(),
+                // The kernel dropped uevents because the socket buffer
+                // was full; carry on with the ones that follow.
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    println!("Missed uevents: {}", e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_uevent() {
+        let buf = b"add@/devices/platform/i8042/serio0/input/input3/event3\0\
+ACTION=add\0DEVPATH=/devices/platform/i8042/serio0/input/input3/event3\0\
+SUBSYSTEM=input\0MAJOR=13\0MINOR=67\0DEVNAME=input/event3\0SEQNUM=2512\0";
+        let uevent = parse_uevent(buf).unwrap();
+        assert_eq!(uevent.action, "add");
+        assert_eq!(
+            uevent.devpath,
+            "/devices/platform/i8042/serio0/input/input3/event3"
+        );
+        assert_eq!(uevent.subsystem(), Some("input"));
+        assert_eq!(uevent.get("DEVNAME"), Some("input/event3"));
+        assert_eq!(uevent.get("MISSING"), None);
+
+        assert_eq!(parse_uevent(b"libudev\0\xfe\xed\xca\xfe"), None);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures_util::{future, select, FutureExt};
+use tokio::{
+    task::spawn_blocking,
+    sync::Notify,
+    time::{self, Instant},
+};
+
+use crate::{args::IdleConfig, dispatch::Dispatcher, power::on_ac_power};
+
+fn run_action(what: String, action: Box<str>) {
+    spawn_blocking(move || {
+        match Command::new("/bin/sh").arg("-c").arg(&*action).status() {
+            Ok(status) if status.success() => (),
+            Ok(status) => println!("Action for {} ({}) exited with {}", what, action, status),
+            Err(e) => println!("Failed to run action for {}: {}", what, e),
+        }
+    });
+}
+
+/// Tracks the time since the last activity and enters the configured
+/// idle levels in order as their timeouts pass. Entering a level calls
+/// `idle_handler` with its 1 based position in `levels` and runs its
+/// action; the first activity afterwards calls `idle_handler` with 0
+/// and starts over.
+pub struct Idle {
+    config: IdleConfig,
+    last_activity: Mutex<Instant>,
+    /// Set while at least one level has been entered
+    idle: AtomicBool,
+    woken: Notify,
+    power_changed: Notify,
+}
+
+impl Idle {
+    pub fn new(config: IdleConfig) -> Self {
+        Idle {
+            config,
+            last_activity: Mutex::new(Instant::now()),
+            idle: AtomicBool::new(false),
+            woken: Notify::new(),
+            power_changed: Notify::new(),
+        }
+    }
+
+    pub fn reset_on_input(&self) -> bool {
+        self.config.reset_on_input
+    }
+
+    /// Restart the idle timeouts.
+    pub fn activity(&self) {
+        *self.last_activity.lock().expect("Lock poisoned") = Instant::now();
+        if self.idle.swap(false, Ordering::Relaxed) {
+            self.woken.notify_one();
+        }
+    }
+
+    /// Check the power source again, which selects between the AC and
+    /// battery timeouts.
+    pub fn power_changed(&self) {
+        self.power_changed.notify_one();
+    }
+
+    pub async fn run(self: Arc<Self>, lib: Arc<Dispatcher>) {
+        if self.config.levels.is_empty() {
+            return future::pending().await;
+        }
+        let mut on_ac = on_ac_power();
+        println!(
+            "Idle timers running on {} power",
+            if on_ac { "AC" } else { "battery" }
+        );
+        // Number of levels entered since the last activity
+        let mut entered = 0;
+        loop {
+            let last_activity = *self.last_activity.lock().expect("Lock poisoned");
+            let next = self
+                .config
+                .levels
+                .iter()
+                .enumerate()
+                .skip(entered)
+                .find_map(|(i, level)| level.timeout(on_ac).map(|timeout| (i, timeout)));
+            let timeout = async {
+                match next {
+                    Some((_, timeout)) => time::sleep_until(last_activity + timeout).await,
+                    None => future::pending().await,
+                }
+            };
+            select! {
+                _ = timeout.fuse() => {
+                    let (i, timeout) = next.expect("Only set with a timeout");
+                    // Activity since the timeout started only moves the
+                    // deadline.
+                    let now_last = *self.last_activity.lock().expect("Lock poisoned");
+                    if now_last + timeout > Instant::now() {
+                        continue;
+                    }
+                    let level = &self.config.levels[i];
+                    println!("Idle for {}s, entering idle level {}", timeout.as_secs(), level.name);
+                    entered = i + 1;
+                    self.idle.store(true, Ordering::Relaxed);
+                    if let Some(ref action) = level.action {
+                        run_action(format!("idle level {}", level.name), action.clone());
+                    }
+                    lib.dispatch_idle(entered as u32).await;
+                }
+                _ = self.woken.notified().fuse() => {
+                    println!("Activity after idle level {}", self.config.levels[entered - 1].name);
+                    entered = 0;
+                    if let Some(ref action) = self.config.active_action {
+                        run_action("activity".to_string(), action.clone());
+                    }
+                    lib.dispatch_idle(0).await;
+                }
+                _ = self.power_changed.notified().fuse() => {
+                    let now_on_ac = on_ac_power();
+                    if now_on_ac != on_ac {
+                        on_ac = now_on_ac;
+                        println!(
+                            "Switched to {} power timeouts",
+                            if on_ac { "AC" } else { "battery" }
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -46,19 +46,42 @@ extern crate ini;
 extern crate libc;
 extern crate libloading;
 extern crate neli;
+extern crate regex;
 extern crate tokio;
+#[cfg(feature = "wasm")]
+extern crate wasmtime;
 
 mod acpi;
+mod acpid;
 mod args;
+mod codes;
+mod dispatch;
 mod evdev;
 mod event;
 mod filter;
+mod gesture;
+mod hotplug;
+mod idle;
+mod plugin;
+mod power;
+mod reload;
+mod rule;
+mod script;
+mod sleep;
+mod thermal;
+mod throttle;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod worker;
 
 use std::process;
-use std::sync::Arc;
 
 /// Main function
 pub fn main() {
+    if let Some(code) = worker::run_if_worker() {
+        process::exit(code);
+    }
+
     let args = match args::parse_args() {
         Ok(a) => a,
         Err(e) => {
@@ -67,11 +90,7 @@ pub fn main() {
         }
     };
 
-    match event::new_event_loop(
-        &args.lib_path,
-        Arc::new(args.config_file.acpi),
-        Arc::new(args.config_file.evdev),
-    ) {
+    match event::new_event_loop(args.config_file, &args.config_path) {
         Ok(a) => a,
         Err(e) => {
             println!("{}", e);
@@ -0,0 +1,830 @@
+use std::{
+    error::Error,
+    ffi::CString,
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, Write},
+    mem,
+    os::{
+        raw::{c_char, c_void},
+        unix::io::{AsRawFd, FromRawFd},
+    },
+    ptr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use libloading::Library;
+use neli::{utils::serialize, Nl};
+
+use crate::{
+    acpi::AcpiEvent,
+    args::{BackendKind, Isolation, WasmConfig},
+    evdev::{InputEvent, InputEventStruct},
+    power::{PowerSupplyState, PowerSupplyStruct},
+    script::Script,
+    sleep::ResumeReason,
+    thermal::{ThermalEvent, ThermalEventStruct},
+    worker::Worker,
+};
+#[cfg(feature = "wasm")]
+use crate::wasm::WasmPlugin;
+
+/// Version of the handler ABI implemented by this daemon. Bump this
+/// whenever the signature of a handler or the layout of a struct
+/// passed to a handler changes.
+pub const ABI_VERSION: u32 = 2;
+
+/// Plugin exports `evdev_handler`.
+pub const CAP_EVDEV: u32 = 1 << 0;
+/// Plugin exports `acpi_handler`.
+pub const CAP_ACPI: u32 = 1 << 1;
+/// Plugin exports `idle_handler`.
+pub const CAP_IDLE: u32 = 1 << 2;
+/// Plugin exports `power_supply_handler`.
+pub const CAP_POWER_SUPPLY: u32 = 1 << 3;
+/// Plugin exports `thermal_handler`.
+pub const CAP_THERMAL: u32 = 1 << 4;
+/// Plugin exports `evdev_frame_handler`.
+pub const CAP_EVDEV_FRAME: u32 = 1 << 5;
+/// Plugin exports `resume_handler`.
+pub const CAP_RESUME: u32 = 1 << 6;
+/// Plugin exports `pre_suspend`.
+pub const CAP_PRE_SUSPEND: u32 = 1 << 7;
+/// Plugin exports `post_resume`.
+pub const CAP_POST_RESUME: u32 = 1 << 8;
+
+/// Size of `PluginInfo` before `power_supply_size` was appended, the
+/// smallest structure a plugin may return
+const MIN_INFO_SIZE: usize = 20;
+
+/// Structure returned by the required `pwrsurge_plugin_info` symbol.
+/// New fields may only ever be appended; `info_size` tells the daemon
+/// how much of the structure the plugin knows about.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PluginInfo {
+    pub abi_version: u32,
+    pub info_size: u32,
+    pub input_event_size: u32,
+    pub acpi_event_size: u32,
+    pub capabilities: u32,
+    pub power_supply_size: u32,
+    pub thermal_event_size: u32,
+}
+
+#[derive(Debug)]
+pub struct PluginError(pub String);
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for PluginError {}
+
+/// Structure passed to the optional `pwrsurge_init` symbol. The strings
+/// are only valid for the duration of the call.
+#[repr(C)]
+pub struct PluginConfig {
+    pub config_size: u32,
+    pub abi_version: u32,
+    pub lib_path: *const c_char,
+    pub config_path: *const c_char,
+}
+
+pub type HandlerResult = Result<i32, Box<dyn Error + Send + Sync>>;
+
+/// Something events are delivered to: either a library loaded into the
+/// daemon or a worker process hosting one.
+pub trait Backend: Send + Sync {
+    fn path(&self) -> &str;
+
+    fn info(&self) -> &PluginInfo;
+
+    /// Returns `None` if the plugin does not handle evdev events.
+    fn handle_evdev(&self, event: &InputEvent) -> Option<HandlerResult>;
+
+    /// Returns `None` if the plugin does not handle frames of evdev
+    /// events. Frames end with a `SYN_REPORT` unless it did not pass
+    /// the whitelist.
+    fn handle_evdev_frame(&self, events: &[InputEvent]) -> Option<HandlerResult>;
+
+    /// Returns `None` if the plugin does not handle ACPI events.
+    fn handle_acpi(&self, event: &AcpiEvent) -> Option<HandlerResult>;
+
+    /// Returns `None` if the plugin does not handle idle levels. Level 0
+    /// means the system became active again.
+    fn handle_idle(&self, level: u32) -> Option<HandlerResult>;
+
+    /// Returns `None` if the plugin does not handle resumes.
+    fn handle_resume(&self, reason: ResumeReason) -> Option<HandlerResult>;
+
+    /// Returns `None` if the plugin does not want to be told before the
+    /// daemon suspends the system.
+    fn handle_pre_suspend(&self) -> Option<HandlerResult>;
+
+    /// Returns `None` if the plugin does not want to be told how long
+    /// the system slept.
+    fn handle_post_resume(&self, slept: Duration) -> Option<HandlerResult>;
+
+    /// Returns `None` if the plugin does not handle power supply changes.
+    fn handle_power_supply(&self, state: &PowerSupplyState) -> Option<HandlerResult>;
+
+    /// Returns `None` if the plugin does not handle thermal events.
+    fn handle_thermal(&self, event: &ThermalEvent) -> Option<HandlerResult>;
+}
+
+type PluginInfoFn = unsafe extern "C" fn() -> *const PluginInfo;
+type InitFn = unsafe extern "C" fn(*const PluginConfig) -> *mut c_void;
+type ShutdownFn = unsafe extern "C" fn(*mut c_void);
+type EvdevHandlerFn = unsafe extern "C" fn(*const InputEvent, *mut c_void) -> i32;
+type EvdevFrameHandlerFn = unsafe extern "C" fn(*const InputEvent, usize, *mut c_void) -> i32;
+type AcpiHandlerFn = unsafe extern "C" fn(*const u8, *mut c_void) -> i32;
+type IdleHandlerFn = unsafe extern "C" fn(u32, *mut c_void) -> i32;
+type ResumeHandlerFn = unsafe extern "C" fn(u32, *mut c_void) -> i32;
+type PreSuspendFn = unsafe extern "C" fn(*mut c_void) -> i32;
+type PostResumeFn = unsafe extern "C" fn(u64, *mut c_void) -> i32;
+type PowerSupplyHandlerFn = unsafe extern "C" fn(*const PowerSupplyStruct, *mut c_void) -> i32;
+type ThermalHandlerFn = unsafe extern "C" fn(*const ThermalEventStruct, *mut c_void) -> i32;
+
+/// A handler library that passed the ABI check at load time.
+///
+/// `pwrsurge_shutdown` is called with the context returned by
+/// `pwrsurge_init` when the plugin is dropped.
+pub struct Plugin {
+    path: Box<str>,
+    info: PluginInfo,
+    ctx: *mut c_void,
+    shutdown: Option<ShutdownFn>,
+    evdev_handler: Option<EvdevHandlerFn>,
+    evdev_frame_handler: Option<EvdevFrameHandlerFn>,
+    acpi_handler: Option<AcpiHandlerFn>,
+    idle_handler: Option<IdleHandlerFn>,
+    resume_handler: Option<ResumeHandlerFn>,
+    pre_suspend: Option<PreSuspendFn>,
+    post_resume: Option<PostResumeFn>,
+    power_supply_handler: Option<PowerSupplyHandlerFn>,
+    thermal_handler: Option<ThermalHandlerFn>,
+    // Must be dropped after the function pointers above are last used;
+    // it is only held so the symbols stay mapped.
+    _lib: Library,
+    _file: File,
+}
+
+fn check_header(path: &str, abi_version: u32, info_size: u32) -> Result<(), PluginError> {
+    if abi_version != ABI_VERSION {
+        return Err(PluginError(format!(
+            "Plugin {} was built for handler ABI version {} but this pwrsurge \
+             implements version {}; rebuild the plugin against this version of pwrsurge",
+            path, abi_version, ABI_VERSION,
+        )));
+    }
+    if (info_size as usize) < MIN_INFO_SIZE {
+        return Err(PluginError(format!(
+            "Plugin {} reports a pwrsurge_plugin_info structure of {} bytes; expected at least {}",
+            path, info_size, MIN_INFO_SIZE,
+        )));
+    }
+    Ok(())
+}
+
+fn check_info(path: &str, info: &PluginInfo) -> Result<(), PluginError> {
+    let input_event_size = mem::size_of::<InputEventStruct>();
+    if info.capabilities & (CAP_EVDEV | CAP_EVDEV_FRAME) != 0
+        && info.input_event_size as usize != input_event_size
+    {
+        return Err(PluginError(format!(
+            "Plugin {} expects input_event to be {} bytes but the daemon passes {} bytes; \
+             check that the plugin was built for the same architecture",
+            path, info.input_event_size, input_event_size,
+        )));
+    }
+    let acpi_event_size = AcpiEvent::type_size().expect("Constant size");
+    if info.capabilities & CAP_ACPI != 0 && info.acpi_event_size as usize != acpi_event_size {
+        return Err(PluginError(format!(
+            "Plugin {} expects acpi_event to be {} bytes but the daemon passes {} bytes",
+            path, info.acpi_event_size, acpi_event_size,
+        )));
+    }
+    let power_supply_size = mem::size_of::<PowerSupplyStruct>();
+    if info.capabilities & CAP_POWER_SUPPLY != 0
+        && info.power_supply_size as usize != power_supply_size
+    {
+        return Err(PluginError(format!(
+            "Plugin {} expects pwrsurge_power_supply to be {} bytes but the daemon passes {} bytes",
+            path, info.power_supply_size, power_supply_size,
+        )));
+    }
+    let thermal_event_size = mem::size_of::<ThermalEventStruct>();
+    if info.capabilities & CAP_THERMAL != 0 && info.thermal_event_size as usize != thermal_event_size
+    {
+        return Err(PluginError(format!(
+            "Plugin {} expects pwrsurge_thermal_event to be {} bytes but the daemon passes {} bytes",
+            path, info.thermal_event_size, thermal_event_size,
+        )));
+    }
+    let unknown = info.capabilities
+        & !(CAP_EVDEV
+            | CAP_ACPI
+            | CAP_IDLE
+            | CAP_POWER_SUPPLY
+            | CAP_THERMAL
+            | CAP_EVDEV_FRAME
+            | CAP_RESUME
+            | CAP_PRE_SUSPEND
+            | CAP_POST_RESUME);
+    if unknown != 0 {
+        return Err(PluginError(format!(
+            "Plugin {} requests unsupported capabilities {:#x}",
+            path, unknown,
+        )));
+    }
+    Ok(())
+}
+
+/// ELF headers, `EI_CLASS` and `EI_DATA` of libraries that can be
+/// loaded by this process
+#[cfg(target_pointer_width = "64")]
+type ElfEhdr = libc::Elf64_Ehdr;
+#[cfg(target_pointer_width = "64")]
+type ElfPhdr = libc::Elf64_Phdr;
+#[cfg(target_pointer_width = "64")]
+const ELF_CLASS: u8 = 2;
+#[cfg(target_pointer_width = "32")]
+type ElfEhdr = libc::Elf32_Ehdr;
+#[cfg(target_pointer_width = "32")]
+type ElfPhdr = libc::Elf32_Phdr;
+#[cfg(target_pointer_width = "32")]
+const ELF_CLASS: u8 = 1;
+const ELF_DATA: u8 = if cfg!(target_endian = "little") { 1 } else { 2 };
+
+/// Tags of the dynamic section entries needed to find run paths
+const DT_NULL: usize = 0;
+const DT_STRTAB: usize = 5;
+const DT_RPATH: usize = 15;
+const DT_RUNPATH: usize = 29;
+
+fn read_struct<T>(contents: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(mem::size_of::<T>())?;
+    if end > contents.len() {
+        return None;
+    }
+    Some(unsafe { (contents[offset..].as_ptr() as *const T).read_unaligned() })
+}
+
+/// Returns the `DT_RPATH` and `DT_RUNPATH` entries of a shared library
+/// built for this architecture. Anything else has none, and is left for
+/// the dynamic loader to reject.
+fn run_paths(contents: &[u8]) -> Vec<&[u8]> {
+    let ehdr = match read_struct::<ElfEhdr>(contents, 0) {
+        Some(ehdr)
+            if ehdr.e_ident[..4] == *b"\x7fELF"
+                && ehdr.e_ident[4] == ELF_CLASS
+                && ehdr.e_ident[5] == ELF_DATA =>
+        {
+            ehdr
+        }
+        _ => return Vec::new(),
+    };
+    let phdrs = (0..ehdr.e_phnum as usize)
+        .map_while(|i| {
+            let offset = ehdr.e_phoff as usize + i * ehdr.e_phentsize as usize;
+            read_struct::<ElfPhdr>(contents, offset)
+        })
+        .collect::<Vec<_>>();
+    let dynamic = match phdrs.iter().find(|p| p.p_type == libc::PT_DYNAMIC) {
+        Some(dynamic) => dynamic,
+        None => return Vec::new(),
+    };
+    // Each entry is a tag followed by a value or address of the size of
+    // a pointer
+    let entry_size = 2 * mem::size_of::<usize>();
+    let mut strtab = None;
+    let mut paths = Vec::new();
+    for i in 0..dynamic.p_filesz as usize / entry_size {
+        let offset = dynamic.p_offset as usize + i * entry_size;
+        let entry = match read_struct::<[usize; 2]>(contents, offset) {
+            Some(entry) => entry,
+            None => break,
+        };
+        match entry {
+            [DT_NULL, _] => break,
+            [DT_STRTAB, addr] => strtab = Some(addr),
+            [DT_RPATH, offset] | [DT_RUNPATH, offset] => paths.push(offset),
+            _ => (),
+        }
+    }
+    // The string table is found by its address once loaded
+    let strtab = match strtab.and_then(|addr| {
+        phdrs
+            .iter()
+            .filter(|p| p.p_type == libc::PT_LOAD)
+            .find(|p| (p.p_vaddr as usize..(p.p_vaddr + p.p_filesz) as usize).contains(&addr))
+            .map(|p| addr - p.p_vaddr as usize + p.p_offset as usize)
+    }) {
+        Some(strtab) => strtab,
+        None => return Vec::new(),
+    };
+    paths
+        .into_iter()
+        .filter_map(|offset| {
+            let string = contents.get(strtab.checked_add(offset)?..)?;
+            let len = string.iter().position(|b| *b == 0)?;
+            Some(&string[..len])
+        })
+        .collect()
+}
+
+fn uses_origin(run_path: &[u8]) -> bool {
+    run_path.windows(7).any(|w| w == b"$ORIGIN") || run_path.windows(9).any(|w| w == b"${ORIGIN}")
+}
+
+/// Copy the library at `path` into an anonymous memory file and load it
+/// from there. The dynamic loader identifies libraries by path, so
+/// loading a replaced library from its original path while the old one
+/// is still mapped would hand back the old library. This also means a
+/// library that is swapped out after it was read is never executed.
+/// `$ORIGIN` in the run path of a library loaded this way no longer
+/// refers to the directory of `path`, so such libraries are rejected.
+fn open_library(path: &str) -> Result<(Library, File), Box<dyn Error>> {
+    let contents = fs::read(path)?;
+    if let Some(run_path) = run_paths(&contents).into_iter().find(|p| uses_origin(p)) {
+        return Err(Box::new(PluginError(format!(
+            "its run path {} uses $ORIGIN, which does not work for plugins \
+             loaded from memory",
+            String::from_utf8_lossy(run_path),
+        ))));
+    }
+    let name = CString::new("pwrsurge-plugin")?;
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(Box::new(io::Error::last_os_error()));
+    }
+    let mut file = unsafe { File::from_raw_fd(fd as i32) };
+    file.write_all(&contents)?;
+    let lib = Library::new(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+    Ok((lib, file))
+}
+
+// The context pointer is owned by the plugin, which must tolerate its
+// handlers being called concurrently from multiple threads.
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    /// Load the library at `path`, validate it against the ABI this
+    /// daemon implements and call `pwrsurge_init` if it is exported.
+    pub fn load(path: &str, config_path: &str) -> Result<Self, Box<dyn Error>> {
+        let (lib, file) = open_library(path).map_err(|e| {
+            PluginError(format!("Failed to load plugin {}: {}", path, e))
+        })?;
+        let info = unsafe {
+            let info_fn = lib
+                .get::<PluginInfoFn>(b"pwrsurge_plugin_info")
+                .map_err(|e| {
+                    PluginError(format!(
+                        "Plugin {} does not export pwrsurge_plugin_info ({}); \
+                         plugins must describe the ABI they were built for",
+                        path, e,
+                    ))
+                })?;
+            let info_ptr = info_fn();
+            if info_ptr.is_null() {
+                return Err(Box::new(PluginError(format!(
+                    "pwrsurge_plugin_info in plugin {} returned NULL",
+                    path
+                ))));
+            }
+            // Only the first two fields are guaranteed to exist in every
+            // version of the structure.
+            check_header(path, (*info_ptr).abi_version, (*info_ptr).info_size)?;
+            // Fields appended after the plugin was built stay zero
+            let mut info = PluginInfo::default();
+            ptr::copy_nonoverlapping(
+                info_ptr as *const u8,
+                &mut info as *mut PluginInfo as *mut u8,
+                ((*info_ptr).info_size as usize).min(mem::size_of::<PluginInfo>()),
+            );
+            info
+        };
+        check_info(path, &info)?;
+
+        let evdev_handler = if info.capabilities & CAP_EVDEV != 0 {
+            Some(*unsafe { lib.get::<EvdevHandlerFn>(b"evdev_handler") }.map_err(|e| {
+                PluginError(format!(
+                    "Plugin {} advertises evdev support but evdev_handler could not be loaded: {}",
+                    path, e,
+                ))
+            })?)
+        } else {
+            None
+        };
+        let evdev_frame_handler = if info.capabilities & CAP_EVDEV_FRAME != 0 {
+            Some(*unsafe { lib.get::<EvdevFrameHandlerFn>(b"evdev_frame_handler") }.map_err(
+                |e| {
+                    PluginError(format!(
+                        "Plugin {} advertises evdev frame support but evdev_frame_handler \
+                         could not be loaded: {}",
+                        path, e,
+                    ))
+                },
+            )?)
+        } else {
+            None
+        };
+        let acpi_handler = if info.capabilities & CAP_ACPI != 0 {
+            Some(*unsafe { lib.get::<AcpiHandlerFn>(b"acpi_handler") }.map_err(|e| {
+                PluginError(format!(
+                    "Plugin {} advertises ACPI support but acpi_handler could not be loaded: {}",
+                    path, e,
+                ))
+            })?)
+        } else {
+            None
+        };
+        let idle_handler = if info.capabilities & CAP_IDLE != 0 {
+            Some(*unsafe { lib.get::<IdleHandlerFn>(b"idle_handler") }.map_err(|e| {
+                PluginError(format!(
+                    "Plugin {} advertises idle support but idle_handler could not be loaded: {}",
+                    path, e,
+                ))
+            })?)
+        } else {
+            None
+        };
+        let resume_handler = if info.capabilities & CAP_RESUME != 0 {
+            Some(*unsafe { lib.get::<ResumeHandlerFn>(b"resume_handler") }.map_err(|e| {
+                PluginError(format!(
+                    "Plugin {} advertises resume support but resume_handler could not be \
+                     loaded: {}",
+                    path, e,
+                ))
+            })?)
+        } else {
+            None
+        };
+        let pre_suspend = if info.capabilities & CAP_PRE_SUSPEND != 0 {
+            Some(*unsafe { lib.get::<PreSuspendFn>(b"pre_suspend") }.map_err(|e| {
+                PluginError(format!(
+                    "Plugin {} advertises pre-suspend support but pre_suspend could not be \
+                     loaded: {}",
+                    path, e,
+                ))
+            })?)
+        } else {
+            None
+        };
+        let post_resume = if info.capabilities & CAP_POST_RESUME != 0 {
+            Some(*unsafe { lib.get::<PostResumeFn>(b"post_resume") }.map_err(|e| {
+                PluginError(format!(
+                    "Plugin {} advertises post-resume support but post_resume could not be \
+                     loaded: {}",
+                    path, e,
+                ))
+            })?)
+        } else {
+            None
+        };
+
+        let power_supply_handler = if info.capabilities & CAP_POWER_SUPPLY != 0 {
+            Some(*unsafe { lib.get::<PowerSupplyHandlerFn>(b"power_supply_handler") }.map_err(
+                |e| {
+                    PluginError(format!(
+                        "Plugin {} advertises power supply support but power_supply_handler \
+                         could not be loaded: {}",
+                        path, e,
+                    ))
+                },
+            )?)
+        } else {
+            None
+        };
+
+        let thermal_handler = if info.capabilities & CAP_THERMAL != 0 {
+            Some(*unsafe { lib.get::<ThermalHandlerFn>(b"thermal_handler") }.map_err(|e| {
+                PluginError(format!(
+                    "Plugin {} advertises thermal support but thermal_handler could not be \
+                     loaded: {}",
+                    path, e,
+                ))
+            })?)
+        } else {
+            None
+        };
+
+        let init = unsafe { lib.get::<InitFn>(b"pwrsurge_init") }
+            .ok()
+            .map(|f| *f);
+        let shutdown = unsafe { lib.get::<ShutdownFn>(b"pwrsurge_shutdown") }
+            .ok()
+            .map(|f| *f);
+        let ctx = match init {
+            Some(f) => {
+                let lib_path = CString::new(path)?;
+                let config_path = CString::new(config_path)?;
+                let config = PluginConfig {
+                    config_size: mem::size_of::<PluginConfig>() as u32,
+                    abi_version: ABI_VERSION,
+                    lib_path: lib_path.as_ptr(),
+                    config_path: config_path.as_ptr(),
+                };
+                unsafe { f(&config) }
+            }
+            None => ptr::null_mut(),
+        };
+
+        Ok(Plugin {
+            path: Box::from(path),
+            info,
+            ctx,
+            shutdown,
+            evdev_handler,
+            evdev_frame_handler,
+            acpi_handler,
+            idle_handler,
+            resume_handler,
+            pre_suspend,
+            post_resume,
+            power_supply_handler,
+            thermal_handler,
+            _lib: lib,
+            _file: file,
+        })
+    }
+
+    /// Call `power_supply_handler` with a state that was already
+    /// converted, as done by workers.
+    pub fn handle_power_supply_struct(&self, state: &PowerSupplyStruct) -> Option<HandlerResult> {
+        self.power_supply_handler
+            .map(|f| Ok(unsafe { f(state, self.ctx) }))
+    }
+
+    /// Call `thermal_handler` with an event that was already converted,
+    /// as done by workers.
+    pub fn handle_thermal_struct(&self, event: &ThermalEventStruct) -> Option<HandlerResult> {
+        self.thermal_handler
+            .map(|f| Ok(unsafe { f(event, self.ctx) }))
+    }
+}
+
+impl Backend for Plugin {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    fn handle_evdev(&self, event: &InputEvent) -> Option<HandlerResult> {
+        self.evdev_handler
+            .map(|f| Ok(unsafe { f(event.as_buffer() as *const _ as *const InputEvent, self.ctx) }))
+    }
+
+    fn handle_evdev_frame(&self, events: &[InputEvent]) -> Option<HandlerResult> {
+        // InputEvent is a union over InputEventStruct, so the slice
+        // already has the layout of a C array of input_event.
+        self.evdev_frame_handler
+            .map(|f| Ok(unsafe { f(events.as_ptr(), events.len(), self.ctx) }))
+    }
+
+    fn handle_acpi(&self, event: &AcpiEvent) -> Option<HandlerResult> {
+        let f = self.acpi_handler?;
+        let buffer = match serialize(event, false) {
+            Ok(buf) => buf,
+            Err(e) => return Some(Err(Box::new(e))),
+        };
+        Some(Ok(unsafe { f(buffer.as_slice().as_ptr(), self.ctx) }))
+    }
+
+    fn handle_idle(&self, level: u32) -> Option<HandlerResult> {
+        self.idle_handler.map(|f| Ok(unsafe { f(level, self.ctx) }))
+    }
+
+    fn handle_resume(&self, reason: ResumeReason) -> Option<HandlerResult> {
+        self.resume_handler.map(|f| Ok(unsafe { f(reason as u32, self.ctx) }))
+    }
+
+    fn handle_pre_suspend(&self) -> Option<HandlerResult> {
+        self.pre_suspend.map(|f| Ok(unsafe { f(self.ctx) }))
+    }
+
+    fn handle_post_resume(&self, slept: Duration) -> Option<HandlerResult> {
+        self.post_resume
+            .map(|f| Ok(unsafe { f(slept.as_millis() as u64, self.ctx) }))
+    }
+
+    fn handle_power_supply(&self, state: &PowerSupplyState) -> Option<HandlerResult> {
+        self.handle_power_supply_struct(&state.to_struct())
+    }
+
+    fn handle_thermal(&self, event: &ThermalEvent) -> Option<HandlerResult> {
+        self.handle_thermal_struct(&event.to_struct())
+    }
+}
+
+fn load_backend(
+    path: &str,
+    config_path: &str,
+    kind: &BackendKind,
+) -> Result<Arc<dyn Backend>, Box<dyn Error>> {
+    Ok(match *kind {
+        BackendKind::Library(Isolation::InProcess) => Arc::new(Plugin::load(path, config_path)?),
+        BackendKind::Library(Isolation::Worker { private_network }) => {
+            Arc::new(Worker::spawn(path, config_path, private_network)?)
+        }
+        BackendKind::Script(config) => Arc::new(Script::load(path, config)?),
+        BackendKind::Wasm(ref config) => load_wasm(path, config)?,
+    })
+}
+
+#[cfg(feature = "wasm")]
+fn load_wasm(path: &str, config: &WasmConfig) -> Result<Arc<dyn Backend>, Box<dyn Error>> {
+    Ok(Arc::new(WasmPlugin::load(path, config.clone())?))
+}
+
+#[cfg(not(feature = "wasm"))]
+fn load_wasm(path: &str, _: &WasmConfig) -> Result<Arc<dyn Backend>, Box<dyn Error>> {
+    Err(Box::new(PluginError(format!(
+        "Cannot load {}: pwrsurge was built without the wasm feature",
+        path
+    ))))
+}
+
+/// The currently loaded version of a plugin. Event handlers take a
+/// snapshot with `get`, so a reload never unloads a library out from
+/// under a running handler; the previous version is shut down and
+/// unloaded once its last in-flight call returns.
+pub struct PluginHandle {
+    path: Box<str>,
+    config_path: Box<str>,
+    kind: BackendKind,
+    current: RwLock<Arc<dyn Backend>>,
+}
+
+impl PluginHandle {
+    pub fn load(path: &str, config_path: &str, kind: BackendKind) -> Result<Self, Box<dyn Error>> {
+        let plugin = load_backend(path, config_path, &kind)?;
+        Ok(PluginHandle {
+            path: Box::from(path),
+            config_path: Box::from(config_path),
+            kind,
+            current: RwLock::new(plugin),
+        })
+    }
+
+    /// Wrap a backend that cannot be reloaded
+    #[cfg(test)]
+    pub fn from_backend(backend: Arc<dyn Backend>) -> Self {
+        PluginHandle {
+            path: Box::from(backend.path()),
+            config_path: Box::from(""),
+            kind: BackendKind::Library(Isolation::InProcess),
+            current: RwLock::new(backend),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get(&self) -> Arc<dyn Backend> {
+        Arc::clone(&self.current.read().expect("Lock poisoned"))
+    }
+
+    /// Load and validate the library again and swap it in. On failure
+    /// the currently loaded version stays in place.
+    pub fn reload(&self) -> Result<Arc<dyn Backend>, Box<dyn Error>> {
+        let plugin = load_backend(&self.path, &self.config_path, &self.kind)?;
+        let old = mem::replace(
+            &mut *self.current.write().expect("Lock poisoned"),
+            Arc::clone(&plugin),
+        );
+        drop(old);
+        Ok(plugin)
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        if let Some(f) = self.shutdown {
+            println!("Shutting down plugin {}", self.path);
+            unsafe { f(self.ctx) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info() -> PluginInfo {
+        PluginInfo {
+            abi_version: ABI_VERSION,
+            info_size: mem::size_of::<PluginInfo>() as u32,
+            input_event_size: mem::size_of::<InputEventStruct>() as u32,
+            acpi_event_size: AcpiEvent::type_size().unwrap() as u32,
+            capabilities: CAP_EVDEV | CAP_ACPI,
+            power_supply_size: mem::size_of::<PowerSupplyStruct>() as u32,
+            thermal_event_size: mem::size_of::<ThermalEventStruct>() as u32,
+        }
+    }
+
+    #[test]
+    fn test_check_info() {
+        let info = info();
+        check_header("test", info.abi_version, info.info_size).unwrap();
+        check_info("test", &info).unwrap();
+    }
+
+    #[test]
+    fn test_check_info_mismatch() {
+        let mut info = info();
+        assert!(check_header("test", ABI_VERSION + 1, info.info_size).is_err());
+        assert!(check_header("test", ABI_VERSION, 8).is_err());
+        // Plugins built before power_supply_size was appended
+        check_header("test", ABI_VERSION, MIN_INFO_SIZE as u32).unwrap();
+
+        info.input_event_size += 8;
+        assert!(check_info("test", &info).is_err());
+        // Sizes of structs for capabilities the plugin does not use are ignored
+        info.capabilities = CAP_ACPI;
+        check_info("test", &info).unwrap();
+
+        info.capabilities = CAP_IDLE;
+        check_info("test", &info).unwrap();
+
+        info.capabilities = CAP_POWER_SUPPLY;
+        check_info("test", &info).unwrap();
+        info.power_supply_size = 0;
+        assert!(check_info("test", &info).is_err());
+
+        info.capabilities = CAP_THERMAL;
+        check_info("test", &info).unwrap();
+        info.thermal_event_size += 4;
+        assert!(check_info("test", &info).is_err());
+
+        // Frame handlers are passed input_event too
+        info.capabilities = CAP_EVDEV_FRAME;
+        assert!(check_info("test", &info).is_err());
+
+        info.capabilities = 1 << 31;
+        assert!(check_info("test", &info).is_err());
+    }
+
+    fn bytes<T>(value: &T) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    /// A library with a single segment mapped at address 0x1000 that
+    /// has the given run path
+    fn library(tag: usize, run_path: &[u8]) -> Vec<u8> {
+        let ehdr_size = mem::size_of::<ElfEhdr>();
+        let phdr_size = mem::size_of::<ElfPhdr>();
+        let dynamic_offset = ehdr_size + 2 * phdr_size;
+        let strtab_offset = dynamic_offset + 3 * mem::size_of::<[usize; 2]>();
+        let len = strtab_offset + run_path.len() + 2;
+
+        let mut ehdr: ElfEhdr = unsafe { mem::zeroed() };
+        ehdr.e_ident[..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', ELF_CLASS, ELF_DATA]);
+        ehdr.e_phoff = ehdr_size as _;
+        ehdr.e_phentsize = phdr_size as _;
+        ehdr.e_phnum = 2;
+        let mut load: ElfPhdr = unsafe { mem::zeroed() };
+        load.p_type = libc::PT_LOAD;
+        load.p_vaddr = 0x1000;
+        load.p_filesz = len as _;
+        let mut dynamic: ElfPhdr = unsafe { mem::zeroed() };
+        dynamic.p_type = libc::PT_DYNAMIC;
+        dynamic.p_offset = dynamic_offset as _;
+        dynamic.p_filesz = (strtab_offset - dynamic_offset) as _;
+
+        let mut contents = bytes(&ehdr).to_vec();
+        contents.extend(bytes(&load));
+        contents.extend(bytes(&dynamic));
+        contents.extend(bytes(&[DT_STRTAB, 0x1000 + strtab_offset]));
+        contents.extend(bytes(&[tag, 1]));
+        contents.extend(bytes(&[DT_NULL, 0]));
+        contents.push(0);
+        contents.extend(run_path);
+        contents.push(0);
+        contents
+    }
+
+    #[test]
+    fn test_run_paths() {
+        let lib = library(DT_RUNPATH, b"$ORIGIN/../lib");
+        assert_eq!(run_paths(&lib), vec![&b"$ORIGIN/../lib"[..]]);
+        assert!(uses_origin(run_paths(&lib)[0]));
+        let lib = library(DT_RPATH, b"/usr/lib/pwrsurge:${ORIGIN}");
+        assert!(uses_origin(run_paths(&lib)[0]));
+        let lib = library(DT_RUNPATH, b"/usr/lib/pwrsurge");
+        assert!(!uses_origin(run_paths(&lib)[0]));
+        // Strings elsewhere in the library do not matter
+        let lib = library(DT_NULL, b"$ORIGIN");
+        assert!(run_paths(&lib).is_empty());
+        assert!(run_paths(b"$ORIGIN").is_empty());
+    }
+}
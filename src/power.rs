@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    fs, mem, slice,
+    time::Duration,
+};
+
+use crate::hotplug::UeventSocket;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Size of the name field of `PowerSupplyStruct`, including the
+/// terminating null byte
+const NAME_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SupplyType {
+    Unknown = 0,
+    Battery = 1,
+    Mains = 2,
+    Usb = 3,
+    Ups = 4,
+    Wireless = 5,
+}
+
+impl SupplyType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SupplyType::Unknown => "Unknown",
+            SupplyType::Battery => "Battery",
+            SupplyType::Mains => "Mains",
+            SupplyType::Usb => "USB",
+            SupplyType::Ups => "UPS",
+            SupplyType::Wireless => "Wireless",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "Battery" => SupplyType::Battery,
+            "Mains" => SupplyType::Mains,
+            "UPS" => SupplyType::Ups,
+            "Wireless" => SupplyType::Wireless,
+            // USB, USB_C, USB_PD and the other USB charger types
+            s if s.starts_with("USB") => SupplyType::Usb,
+            _ => SupplyType::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SupplyStatus {
+    Unknown = 0,
+    Charging = 1,
+    Discharging = 2,
+    NotCharging = 3,
+    Full = 4,
+}
+
+impl SupplyStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SupplyStatus::Unknown => "Unknown",
+            SupplyStatus::Charging => "Charging",
+            SupplyStatus::Discharging => "Discharging",
+            SupplyStatus::NotCharging => "Not charging",
+            SupplyStatus::Full => "Full",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "Charging" => SupplyStatus::Charging,
+            "Discharging" => SupplyStatus::Discharging,
+            "Not charging" => SupplyStatus::NotCharging,
+            "Full" => SupplyStatus::Full,
+            _ => SupplyStatus::Unknown,
+        }
+    }
+}
+
+/// State of a battery, AC adapter or other power supply parsed from
+/// its `POWER_SUPPLY_*` properties. Energy is in µWh and charge in
+/// µAh, as reported by the kernel. Properties the driver does not
+/// report are `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerSupplyState {
+    pub name: String,
+    pub supply_type: SupplyType,
+    pub status: SupplyStatus,
+    /// Powers a peripheral such as a mouse rather than the system
+    pub peripheral: bool,
+    pub online: Option<bool>,
+    /// Percent of full
+    pub capacity: Option<u8>,
+    pub energy_now: Option<u64>,
+    pub energy_full: Option<u64>,
+    pub charge_now: Option<u64>,
+    pub charge_full: Option<u64>,
+    pub time_to_empty: Option<Duration>,
+}
+
+impl PowerSupplyState {
+    /// Build the state from `POWER_SUPPLY_*` properties as found in a
+    /// uevent or the `uevent` file of the supply. Capacity and time to
+    /// empty are derived from the other properties if the driver does
+    /// not report them.
+    pub fn from_properties(props: &HashMap<String, String>) -> Option<Self> {
+        let get = |key: &str| props.get(&format!("POWER_SUPPLY_{}", key)).map(|s| s.as_str());
+        let number = |key: &str| get(key).and_then(|s| s.parse::<u64>().ok());
+        let name = get("NAME")?.to_string();
+        let status = get("STATUS").map(SupplyStatus::parse).unwrap_or(SupplyStatus::Unknown);
+        let energy_now = number("ENERGY_NOW");
+        let energy_full = number("ENERGY_FULL");
+        let charge_now = number("CHARGE_NOW");
+        let charge_full = number("CHARGE_FULL");
+        let capacity = number("CAPACITY")
+            .or_else(|| percent(energy_now?, energy_full?))
+            .or_else(|| percent(charge_now?, charge_full?))
+            .map(|c| c.min(100) as u8);
+        let time_to_empty = number("TIME_TO_EMPTY_NOW")
+            .map(Duration::from_secs)
+            .or_else(|| {
+                if status != SupplyStatus::Discharging {
+                    return None;
+                }
+                hours_left(energy_now?, number("POWER_NOW")?)
+                    .or_else(|| hours_left(charge_now?, number("CURRENT_NOW")?))
+            });
+        Some(PowerSupplyState {
+            name,
+            supply_type: get("TYPE").map(SupplyType::parse).unwrap_or(SupplyType::Unknown),
+            status,
+            peripheral: get("SCOPE") == Some("Device"),
+            online: get("ONLINE").map(|s| s == "1"),
+            capacity,
+            energy_now,
+            energy_full,
+            charge_now,
+            charge_full,
+            time_to_empty,
+        })
+    }
+
+    /// The layout passed to `power_supply_handler`
+    pub fn to_struct(&self) -> PowerSupplyStruct {
+        let mut name = [0; NAME_SIZE];
+        let len = self.name.len().min(NAME_SIZE - 1);
+        name[..len].copy_from_slice(&self.name.as_bytes()[..len]);
+        let int = |v: Option<u64>| v.map(|v| v as i64).unwrap_or(-1);
+        PowerSupplyStruct {
+            name,
+            supply_type: self.supply_type as u32,
+            status: self.status as u32,
+            online: self.online.map(i32::from).unwrap_or(-1),
+            capacity: self.capacity.map(i32::from).unwrap_or(-1),
+            energy_now: int(self.energy_now),
+            energy_full: int(self.energy_full),
+            charge_now: int(self.charge_now),
+            charge_full: int(self.charge_full),
+            time_to_empty: int(self.time_to_empty.map(|d| d.as_secs())),
+        }
+    }
+}
+
+fn percent(now: u64, full: u64) -> Option<u64> {
+    now.saturating_mul(100).checked_div(full)
+}
+
+fn hours_left(now: u64, rate: u64) -> Option<Duration> {
+    now.saturating_mul(3600)
+        .checked_div(rate)
+        .map(Duration::from_secs)
+}
+
+impl Display for PowerSupplyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {}",
+            self.name,
+            self.supply_type.as_str(),
+            self.status.as_str()
+        )?;
+        if let Some(online) = self.online {
+            write!(f, ", {}", if online { "online" } else { "offline" })?;
+        }
+        if let Some(capacity) = self.capacity {
+            write!(f, ", {}%", capacity)?;
+        }
+        if let Some(time_to_empty) = self.time_to_empty {
+            write!(f, ", {} min left", time_to_empty.as_secs() / 60)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// `PowerSupplyState` as passed to plugins. Unknown values are -1. The
+/// fields are ordered so the structure has no padding, which makes it
+/// the same on every 64 bit target.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerSupplyStruct {
+    pub name: [u8; NAME_SIZE],
+    pub supply_type: u32,
+    pub status: u32,
+    pub online: i32,
+    pub capacity: i32,
+    pub energy_now: i64,
+    pub energy_full: i64,
+    pub charge_now: i64,
+    pub charge_full: i64,
+    /// Seconds
+    pub time_to_empty: i64,
+}
+
+impl PowerSupplyStruct {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, mem::size_of::<Self>()) }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != mem::size_of::<Self>() {
+            return None;
+        }
+        Some(unsafe { (bytes.as_ptr() as *const Self).read_unaligned() })
+    }
+}
+
+fn parse_uevent_file(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let eq = line.find('=')?;
+            Some((line[..eq].to_string(), line[eq + 1..].to_string()))
+        })
+        .collect()
+}
+
+/// Current state of every power supply
+pub fn power_supplies() -> Vec<PowerSupplyState> {
+    let mut supplies = fs::read_dir(POWER_SUPPLY_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| fs::read_to_string(entry.path().join("uevent")).ok())
+                .filter_map(|contents| {
+                    PowerSupplyState::from_properties(&parse_uevent_file(&contents))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    supplies.sort_by(|a, b| a.name.cmp(&b.name));
+    supplies
+}
+
+/// Whether the system is currently running on AC power. If the power
+/// supplies cannot be read, AC power is assumed.
+pub fn on_ac_power() -> bool {
+    supplies_on_ac(&power_supplies())
+}
+
+/// The system runs on AC power unless it has a battery and none of its
+/// other power supplies is online. Systems without a battery, such as
+/// desktops, are always on AC power.
+fn supplies_on_ac(supplies: &[PowerSupplyState]) -> bool {
+    let system = supplies.iter().filter(|s| !s.peripheral).collect::<Vec<_>>();
+    !system.iter().any(|s| s.supply_type == SupplyType::Battery)
+        || system
+            .iter()
+            .any(|s| s.supply_type != SupplyType::Battery && s.online == Some(true))
+}
+
+/// Kernel uevents announcing changes to power supplies
+pub struct PowerSupplyEvents(UeventSocket);
+
+impl PowerSupplyEvents {
+    pub fn new() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(PowerSupplyEvents(UeventSocket::new()?))
+    }
+
+    /// Wait for the next change to a power supply.
+    pub async fn next(&mut self) -> Result<PowerSupplyState, Box<dyn Error + Send + Sync>> {
+        loop {
+            let uevent = self.0.next().await?;
+            if uevent.subsystem() != Some("power_supply") || uevent.action != "change" {
+                continue;
+            }
+            if let Some(state) = PowerSupplyState::from_properties(&uevent.vars) {
+                return Ok(state);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn props(s: &str) -> HashMap<String, String> {
+        parse_uevent_file(s)
+    }
+
+    #[test]
+    fn test_battery_state() {
+        let state = PowerSupplyState::from_properties(&props(
+            "POWER_SUPPLY_NAME=BAT0
+POWER_SUPPLY_TYPE=Battery
+POWER_SUPPLY_STATUS=Discharging
+POWER_SUPPLY_PRESENT=1
+POWER_SUPPLY_POWER_NOW=10000000
+POWER_SUPPLY_ENERGY_FULL=50000000
+POWER_SUPPLY_ENERGY_NOW=25000000
+",
+        ))
+        .unwrap();
+        assert_eq!(state.name, "BAT0");
+        assert_eq!(state.supply_type, SupplyType::Battery);
+        assert_eq!(state.status, SupplyStatus::Discharging);
+        assert_eq!(state.online, None);
+        assert_eq!(state.capacity, Some(50));
+        assert_eq!(state.energy_now, Some(25_000_000));
+        assert_eq!(state.charge_now, None);
+        assert_eq!(state.time_to_empty, Some(Duration::from_secs(9000)));
+
+        let s = state.to_struct();
+        assert_eq!(&s.name[..5], b"BAT0\0");
+        assert_eq!((s.supply_type, s.status, s.online, s.capacity), (1, 2, -1, 50));
+        assert_eq!((s.charge_now, s.time_to_empty), (-1, 9000));
+        assert_eq!(PowerSupplyStruct::from_bytes(s.as_bytes()), Some(s));
+    }
+
+    #[test]
+    fn test_mains_state() {
+        let state = PowerSupplyState::from_properties(&props(
+            "POWER_SUPPLY_NAME=AC\nPOWER_SUPPLY_TYPE=Mains\nPOWER_SUPPLY_ONLINE=1\n",
+        ))
+        .unwrap();
+        assert_eq!(state.supply_type, SupplyType::Mains);
+        assert_eq!(state.online, Some(true));
+        assert_eq!(state.capacity, None);
+        assert_eq!(state.time_to_empty, None);
+        assert_eq!(PowerSupplyState::from_properties(&props("POWER_SUPPLY_TYPE=Mains\n")), None);
+    }
+
+    fn supply(supply_type: &str, scope: Option<&str>, online: bool) -> PowerSupplyState {
+        let mut s = format!(
+            "POWER_SUPPLY_NAME={0}\nPOWER_SUPPLY_TYPE={0}\nPOWER_SUPPLY_ONLINE={1}\n",
+            supply_type, online as u8
+        );
+        if let Some(scope) = scope {
+            s.push_str(&format!("POWER_SUPPLY_SCOPE={}\n", scope));
+        }
+        PowerSupplyState::from_properties(&props(&s)).unwrap()
+    }
+
+    #[test]
+    fn test_supplies_on_ac() {
+        // Desktop
+        assert!(supplies_on_ac(&[]));
+        assert!(supplies_on_ac(&[supply("Battery", Some("Device"), false)]));
+        // Laptop
+        assert!(supplies_on_ac(&[
+            supply("Mains", None, true),
+            supply("Battery", None, false),
+        ]));
+        assert!(!supplies_on_ac(&[
+            supply("Mains", None, false),
+            supply("Battery", None, false),
+        ]));
+        assert!(supplies_on_ac(&[
+            supply("Mains", None, false),
+            supply("USB_C", None, true),
+            supply("Battery", None, false),
+        ]));
+        // A charging wireless mouse does not power the system
+        assert!(!supplies_on_ac(&[
+            supply("Battery", None, false),
+            supply("USB", Some("Device"), true),
+        ]));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    error::Error,
+    ffi::{CString, OsStr},
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+    },
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::{future, select, FutureExt};
+use tokio::{
+    io::unix::AsyncFd,
+    signal::unix::{signal, SignalKind},
+    task::spawn_blocking,
+    time::sleep,
+};
+
+use crate::plugin::PluginHandle;
+
+/// Time to wait after the library changed before reloading it so that
+/// a build writing the file in several steps has finished.
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// Returns the name of every entry in an inotify read buffer whose mask
+/// intersects `mask`.
+fn parse_inotify_names(buf: &[u8], mask: u32) -> Vec<&OsStr> {
+    let header_len = mem::size_of::<libc::inotify_event>();
+    let mut names = Vec::new();
+    let mut pos = 0;
+    while pos + header_len <= buf.len() {
+        let event =
+            unsafe { (buf[pos..].as_ptr() as *const libc::inotify_event).read_unaligned() };
+        let name_start = pos + header_len;
+        let name_end = name_start + event.len as usize;
+        if name_end > buf.len() {
+            break;
+        }
+        if event.mask & mask != 0 {
+            let name = &buf[name_start..name_end];
+            let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            names.push(OsStr::from_bytes(&name[..len]));
+        }
+        pos = name_end;
+    }
+    names
+}
+
+struct Inotify(File);
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// Watches the directory containing the plugin for the library being
+/// rewritten or replaced by a rename.
+struct LibraryWatcher {
+    fd: AsyncFd<Inotify>,
+    file_name: Box<OsStr>,
+}
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+
+impl LibraryWatcher {
+    fn new(lib_path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let path = Path::new(lib_path);
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format!("{} does not name a file", lib_path))?;
+        let dir = match path.parent() {
+            Some(p) if p != Path::new("") => p,
+            _ => Path::new("."),
+        };
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+        let inotify = Inotify(unsafe { File::from_raw_fd(fd) });
+        let dir_cstring = CString::new(dir.as_os_str().as_bytes())?;
+        if unsafe { libc::inotify_add_watch(fd, dir_cstring.as_ptr(), WATCH_MASK) } < 0 {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+        Ok(LibraryWatcher {
+            fd: AsyncFd::new(inotify)?,
+            file_name: Box::from(file_name),
+        })
+    }
+
+    /// Read inotify events into `buf`, returning whether any of them
+    /// concerned the library.
+    fn read_events(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut changed = false;
+        loop {
+            match (&self.fd.get_ref().0).read(buf) {
+                Ok(0) => return Ok(changed),
+                Ok(n) => {
+                    changed |= parse_inotify_names(&buf[..n], WATCH_MASK)
+                        .into_iter()
+                        .any(|name| name == &*self.file_name);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(changed),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn changed(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            guard.clear_ready();
+            drop(guard);
+            if self.read_events(&mut buf)? {
+                sleep(SETTLE_TIME).await;
+                // Swallow the events caused by the rest of the write
+                self.read_events(&mut buf)?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn watcher_changed(watcher: &mut Option<LibraryWatcher>) -> io::Result<()> {
+    match watcher {
+        Some(w) => w.changed().await,
+        None => future::pending().await,
+    }
+}
+
+/// Reload the plugin on `SIGHUP` and, if `watch` is set, whenever the
+/// library file changes on disk.
+pub async fn reload_loop(
+    handle: Arc<PluginHandle>,
+    watch: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut watcher = if watch {
+        Some(LibraryWatcher::new(handle.path())?)
+    } else {
+        None
+    };
+    loop {
+        select! {
+            _ = sighup.recv().fuse() => {
+                println!("Received SIGHUP, reloading plugin {}", handle.path());
+            }
+            res = watcher_changed(&mut watcher).fuse() => {
+                res?;
+                println!("Plugin {} changed on disk, reloading", handle.path());
+            }
+        };
+        // Loading the library and dropping the old one, which may wait
+        // for a worker process to exit, block
+        let reload = Arc::clone(&handle);
+        match spawn_blocking(move || reload.reload().map_err(|e| e.to_string())).await {
+            Ok(Ok(plugin)) => println!(
+                "Reloaded plugin {} (ABI version {}, capabilities {:#x})",
+                plugin.path(),
+                plugin.info().abi_version,
+                plugin.info().capabilities,
+            ),
+            Ok(Err(e)) => println!("Keeping previously loaded plugin: {}", e),
+            Err(e) => println!("Reloading plugin {} failed: {}", handle.path(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(mask: u32, name: &[u8], padded_len: usize) -> Vec<u8> {
+        let header = libc::inotify_event {
+            wd: 1,
+            mask,
+            cookie: 0,
+            len: padded_len as u32,
+        };
+        let mut buf = unsafe {
+            std::slice::from_raw_parts(
+                &header as *const _ as *const u8,
+                mem::size_of::<libc::inotify_event>(),
+            )
+        }
+        .to_vec();
+        let mut name = name.to_vec();
+        name.resize(padded_len, 0);
+        buf.extend(name);
+        buf
+    }
+
+    #[test]
+    fn test_parse_inotify_names() {
+        let mut buf = event(libc::IN_CLOSE_WRITE, b"libevents.so", 16);
+        buf.extend(event(libc::IN_CREATE, b"other.so", 16));
+        buf.extend(event(libc::IN_MOVED_TO, b"libfoo.so", 16));
+        assert_eq!(
+            parse_inotify_names(&buf, WATCH_MASK),
+            vec![OsStr::new("libevents.so"), OsStr::new("libfoo.so")]
+        );
+    }
+}
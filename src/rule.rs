@@ -0,0 +1,395 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+use crate::{
+    codes::{parse_code, parse_type, parse_value},
+    evdev::InputEvent,
+};
+
+#[derive(Debug)]
+pub struct RuleError(pub String);
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for RuleError {}
+
+/// A property of an evdev event that rules compare
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    Type,
+    Code,
+    Value,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "type" => Some(Field::Type),
+            "code" => Some(Field::Code),
+            "value" => Some(Field::Value),
+            _ => None,
+        }
+    }
+
+    /// Range of the values the field can take
+    fn bounds(self) -> (i64, i64) {
+        match self {
+            Field::Type | Field::Code => (0, u16::MAX.into()),
+            Field::Value => (i32::MIN.into(), i32::MAX.into()),
+        }
+    }
+
+    /// Types and codes may also be given by name, such as `EV_SW` or
+    /// `SW_LID`.
+    fn parse(self, s: &str) -> Result<i64, String> {
+        match self {
+            Field::Type => parse_type(s).map(i64::from),
+            Field::Code => parse_code(s).map(i64::from),
+            Field::Value => parse_value(s).map(i64::from),
+        }
+    }
+
+    fn get(self, event: &InputEvent) -> i64 {
+        match self {
+            Field::Type => event.get_event_type().into(),
+            Field::Code => event.get_event_code().into(),
+            Field::Value => event.get_event_value().into(),
+        }
+    }
+}
+
+/// A compiled rule expression. Every comparison is compiled to an
+/// inclusive range of the field, so `code!=0` becomes the negation of
+/// `0 <= code <= 0` and `value>0` becomes `1 <= value <= i32::MAX`.
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+    Range { field: Field, min: i64, max: i64 },
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+impl Expr {
+    /// Parse an expression such as `type=5 && code=0 && value=1`. See
+    /// the README for the syntax.
+    pub fn parse(source: &str) -> Result<Self, RuleError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            source,
+            tokens,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(_) => Err(parser.error("expected && or ||")),
+        }
+    }
+
+    pub fn matches(&self, event: &InputEvent) -> bool {
+        match self {
+            Expr::Range { field, min, max } => (*min..=*max).contains(&field.get(event)),
+            Expr::Not(expr) => !expr.matches(event),
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.matches(event)),
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.matches(event)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token<'a> {
+    /// A field name or a value
+    Word(&'a str),
+    Op(Op),
+    /// `..` between the bounds of a range
+    To,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+/// Split `source` into tokens and the byte offsets they start at
+fn tokenize(source: &str) -> Result<Vec<(usize, Token<'_>)>, RuleError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let two = bytes.get(i..i + 2);
+        let (token, len) = match bytes[i] {
+            c if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            _ if two == Some(b"&&") => (Token::And, 2),
+            _ if two == Some(b"||") => (Token::Or, 2),
+            _ if two == Some(b"..") => (Token::To, 2),
+            _ if two == Some(b"==") => (Token::Op(Op::Eq), 2),
+            _ if two == Some(b"!=") => (Token::Op(Op::Ne), 2),
+            _ if two == Some(b"<=") => (Token::Op(Op::Le), 2),
+            _ if two == Some(b">=") => (Token::Op(Op::Ge), 2),
+            b'=' => (Token::Op(Op::Eq), 1),
+            b'<' => (Token::Op(Op::Lt), 1),
+            b'>' => (Token::Op(Op::Gt), 1),
+            b'!' => (Token::Not, 1),
+            b'(' => (Token::Open, 1),
+            b')' => (Token::Close, 1),
+            c if c.is_ascii_alphanumeric() || c == b'_' || c == b'-' => {
+                let len = bytes[i..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_' || **c == b'-')
+                    .count();
+                (Token::Word(&source[i..i + len]), len)
+            }
+            _ => {
+                let c = source[i..].chars().next().unwrap_or_default();
+                return Err(RuleError(format!(
+                    "Invalid rule {}: unexpected {} at column {}",
+                    source,
+                    c,
+                    i + 1,
+                )));
+            }
+        };
+        tokens.push((i, token));
+        i += len;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser; `||` binds looser than `&&`, which binds
+/// looser than `!`.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(usize, Token<'a>)>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> RuleError {
+        match self.tokens.get(self.pos) {
+            Some((offset, _)) => RuleError(format!(
+                "Invalid rule {}: {} at column {}",
+                self.source,
+                msg,
+                offset + 1,
+            )),
+            None => RuleError(format!(
+                "Invalid rule {}: {} at the end",
+                self.source, msg
+            )),
+        }
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).map(|(_, token)| *token)
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, RuleError> {
+        let mut exprs = vec![self.and()?];
+        while self.peek() == Some(Token::Or) {
+            self.pos += 1;
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Expr::Or(exprs)
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, RuleError> {
+        let mut exprs = vec![self.unary()?];
+        while self.peek() == Some(Token::And) {
+            self.pos += 1;
+            exprs.push(self.unary()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Expr::And(exprs)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, RuleError> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(Token::Open) => {
+                self.pos += 1;
+                let expr = self.or()?;
+                match self.peek() {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    _ => Err(self.error("expected )")),
+                }
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, RuleError> {
+        let field = match self.peek() {
+            Some(Token::Word(name)) => Field::from_name(name)
+                .ok_or_else(|| self.error("expected type, code or value"))?,
+            _ => return Err(self.error("expected type, code or value")),
+        };
+        self.pos += 1;
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("expected a comparison"));
+            }
+        };
+        let value = self.value(field)?;
+        let (min, max) = field.bounds();
+        let range = |min, max| Expr::Range { field, min, max };
+        Ok(match op {
+            Op::Eq | Op::Ne => {
+                let end = if self.peek() == Some(Token::To) {
+                    self.pos += 1;
+                    let end = self.value(field)?;
+                    if end < value {
+                        self.pos -= 1;
+                        return Err(self.error("range ends before it starts"));
+                    }
+                    end
+                } else {
+                    value
+                };
+                match op {
+                    Op::Eq => range(value, end),
+                    _ => Expr::Not(Box::new(range(value, end))),
+                }
+            }
+            Op::Lt => range(min, value - 1),
+            Op::Le => range(min, value),
+            Op::Gt => range(value + 1, max),
+            Op::Ge => range(value, max),
+        })
+    }
+
+    fn value(&mut self, field: Field) -> Result<i64, RuleError> {
+        let word = match self.peek() {
+            Some(Token::Word(word)) => word,
+            _ => return Err(self.error("expected a value")),
+        };
+        let value = field.parse(word).map_err(|e| self.error(&e))?;
+        self.pos += 1;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(ty: u16, code: u16, value: i32) -> InputEvent {
+        InputEvent::synthetic(ty, code, value)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Expr::parse("type=5 && code==0x0").unwrap(),
+            Expr::And(vec![
+                Expr::Range {
+                    field: Field::Type,
+                    min: 5,
+                    max: 5
+                },
+                Expr::Range {
+                    field: Field::Code,
+                    min: 0,
+                    max: 0
+                },
+            ])
+        );
+        assert_eq!(
+            Expr::parse("!(value>=-1)").unwrap(),
+            Expr::Not(Box::new(Expr::Range {
+                field: Field::Value,
+                min: -1,
+                max: i32::MAX.into()
+            }))
+        );
+        for invalid in &[
+            "",
+            "type",
+            "type=",
+            "key=1",
+            "type=1 &&",
+            "type=1 code=2",
+            "(type=1",
+            "type=70000",
+            "code=5..3",
+            "type<1..2",
+            "type=1 & code=2",
+        ] {
+            assert!(Expr::parse(invalid).is_err(), "{}", invalid);
+        }
+        let e = Expr::parse("type=1 && cde=2").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid rule type=1 && cde=2: expected type, code or value at column 11"
+        );
+        let e = Expr::parse("type=EV_SW && code=SW_LDI").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid rule type=EV_SW && code=SW_LDI: SW_LDI is neither a known event code \
+             nor a number at column 20"
+        );
+        assert_eq!(
+            Expr::parse("type=EV_SW && code=SW_LID").unwrap(),
+            Expr::parse("type=5 && code=0").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let lid = Expr::parse("type=5 && code=0 && value=1").unwrap();
+        assert!(lid.matches(&event(5, 0, 1)));
+        assert!(!lid.matches(&event(5, 0, 0)));
+        // Not the independent whitelists of the same numbers
+        assert!(!lid.matches(&event(1, 0, 1)));
+
+        let keys = Expr::parse("type=1 && (code=116 || code=224..225) && value!=2").unwrap();
+        assert!(keys.matches(&event(1, 116, 1)));
+        assert!(keys.matches(&event(1, 225, 0)));
+        assert!(!keys.matches(&event(1, 226, 1)));
+        assert!(!keys.matches(&event(1, 116, 2)));
+
+        let presses = Expr::parse("!type=0 && value>0 && value<2").unwrap();
+        assert!(presses.matches(&event(1, 30, 1)));
+        assert!(!presses.matches(&event(0, 0, 1)));
+        assert!(!presses.matches(&event(1, 30, 2)));
+    }
+}
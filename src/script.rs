@@ -0,0 +1,496 @@
+use std::{
+    error::Error,
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    mem,
+    os::unix::{
+        fs::PermissionsExt,
+        process::{CommandExt, ExitStatusExt},
+    },
+    process::{Command, Stdio},
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use neli::Nl;
+
+use crate::{
+    acpi::AcpiEvent,
+    args::ScriptConfig,
+    codes::{code_name, type_name, value_name},
+    dispatch::{RET_HANDLED, RET_PASS, RET_RETRY},
+    evdev::{InputEvent, InputEventStruct},
+    plugin::{
+        Backend, HandlerResult, PluginError, PluginInfo, ABI_VERSION, CAP_ACPI, CAP_EVDEV,
+        CAP_IDLE, CAP_POST_RESUME, CAP_POWER_SUPPLY, CAP_PRE_SUSPEND, CAP_RESUME, CAP_THERMAL,
+    },
+    power::{PowerSupplyState, PowerSupplyStruct},
+    sleep::ResumeReason,
+    thermal::{ThermalEvent, ThermalEventStruct},
+};
+
+/// How often a running script is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Exit code of a script that passes the event on to the next plugin
+const EXIT_PASS: i32 = 100;
+/// Exit code of a script asking to be run again later, `EX_TEMPFAIL`
+/// of `sysexits.h`
+const EXIT_RETRY: i32 = 75;
+
+/// Escape `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Value of an event field, exported to the script as both an
+/// environment variable and a JSON member.
+enum Field {
+    Str(String),
+    Int(i64),
+}
+
+/// The fields of an event in the order they are exported. The name is
+/// the JSON key; the environment variable is `PWRSURGE_` followed by
+/// the upper case name.
+fn acpi_fields(event: &AcpiEvent) -> Vec<(&'static str, Field)> {
+    vec![
+        ("source", Field::Str("acpi".to_string())),
+        ("device_class", Field::Str(event.device_class.0.clone())),
+        ("bus_id", Field::Str(event.bus_id.0.clone())),
+        ("event_type", Field::Int(event.event_type.into())),
+        ("event_data", Field::Int(event.event_data.into())),
+    ]
+}
+
+fn evdev_fields(event: &InputEvent) -> Vec<(&'static str, Field)> {
+    let timestamp = event.get_timestamp();
+    let mut fields = vec![
+        ("source", Field::Str("evdev".to_string())),
+        ("type", Field::Int(event.get_event_type().into())),
+        ("code", Field::Int(event.get_event_code().into())),
+        ("value", Field::Int(event.get_event_value().into())),
+        ("time_sec", Field::Int(timestamp.tv_sec)),
+        ("time_usec", Field::Int(timestamp.tv_usec)),
+    ];
+    if let Some(name) = type_name(event.get_event_type()) {
+        fields.push(("type_name", Field::Str(name.to_string())));
+    }
+    if let Some(name) = code_name(event.get_event_type(), event.get_event_code()) {
+        fields.push(("code_name", Field::Str(name.to_string())));
+    }
+    if let Some(name) = value_name(event.get_event_type(), event.get_event_value()) {
+        fields.push(("value_name", Field::Str(name.to_string())));
+    }
+    if event.is_synthetic() {
+        fields.push(("synthetic", Field::Int(1)));
+    }
+    fields
+}
+
+fn idle_fields(level: u32) -> Vec<(&'static str, Field)> {
+    vec![
+        ("source", Field::Str("idle".to_string())),
+        ("level", Field::Int(level.into())),
+    ]
+}
+
+fn resume_fields(reason: ResumeReason) -> Vec<(&'static str, Field)> {
+    vec![
+        ("source", Field::Str("resume".to_string())),
+        ("reason", Field::Str(reason.name().to_string())),
+    ]
+}
+
+fn post_resume_fields(slept: Duration) -> Vec<(&'static str, Field)> {
+    vec![
+        ("source", Field::Str("post_resume".to_string())),
+        ("sleep_ms", Field::Int(slept.as_millis() as i64)),
+    ]
+}
+
+/// Properties the driver does not report are left out.
+fn power_supply_fields(state: &PowerSupplyState) -> Vec<(&'static str, Field)> {
+    let mut fields = vec![
+        ("source", Field::Str("power_supply".to_string())),
+        ("name", Field::Str(state.name.clone())),
+        ("supply_type", Field::Str(state.supply_type.as_str().to_string())),
+        ("status", Field::Str(state.status.as_str().to_string())),
+    ];
+    let numbers = [
+        ("online", state.online.map(i64::from)),
+        ("capacity", state.capacity.map(i64::from)),
+        ("energy_now", state.energy_now.map(|v| v as i64)),
+        ("energy_full", state.energy_full.map(|v| v as i64)),
+        ("charge_now", state.charge_now.map(|v| v as i64)),
+        ("charge_full", state.charge_full.map(|v| v as i64)),
+        ("time_to_empty", state.time_to_empty.map(|d| d.as_secs() as i64)),
+    ];
+    for (name, value) in numbers.iter() {
+        if let Some(value) = value {
+            fields.push((name, Field::Int(*value)));
+        }
+    }
+    fields
+}
+
+/// Fields that do not apply to the event are left out.
+fn thermal_fields(event: &ThermalEvent) -> Vec<(&'static str, Field)> {
+    let mut fields = vec![
+        ("source", Field::Str("thermal".to_string())),
+        ("event", Field::Str(event.kind().name().to_string())),
+    ];
+    match event {
+        ThermalEvent::TripUp(trip) | ThermalEvent::TripDown(trip) | ThermalEvent::TripChange(trip) => {
+            fields.push(("zone_id", Field::Int(trip.zone_id.into())));
+            fields.push(("zone_type", Field::Str(trip.zone_type.clone())));
+            fields.push(("trip_id", Field::Int(trip.trip_id.into())));
+            if let Some(trip_type) = trip.trip_type {
+                fields.push(("trip_type", Field::Str(trip_type.name().to_string())));
+            }
+            if let Some(temp) = trip.temp {
+                fields.push(("temp", Field::Int(temp.into())));
+            }
+            if let Some(hyst) = trip.hyst {
+                fields.push(("hyst", Field::Int(hyst.into())));
+            }
+        }
+        ThermalEvent::CdevAdd(cdev) | ThermalEvent::CdevDelete(cdev) | ThermalEvent::CdevUpdate(cdev) => {
+            fields.push(("cdev_id", Field::Int(cdev.cdev_id.into())));
+            fields.push(("cdev_type", Field::Str(cdev.cdev_type.clone())));
+            if let Some(state) = cdev.cur_state {
+                fields.push(("cur_state", Field::Int(state.into())));
+            }
+            if let Some(state) = cdev.max_state {
+                fields.push(("max_state", Field::Int(state.into())));
+            }
+        }
+    }
+    fields
+}
+
+fn fields_to_json(fields: &[(&str, Field)]) -> String {
+    let members = fields
+        .iter()
+        .map(|(name, value)| match value {
+            Field::Str(s) => format!("{}:{}", json_string(name), json_string(s)),
+            Field::Int(i) => format!("{}:{}", json_string(name), i),
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}\n", members.join(","))
+}
+
+/// Counting semaphore bounding the number of scripts running at once
+struct Slots {
+    used: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+struct SlotGuard<'a>(&'a Slots);
+
+impl Slots {
+    fn acquire(&self) -> SlotGuard<'_> {
+        let mut used = self.used.lock().expect("Lock poisoned");
+        while *used >= self.max {
+            used = self.freed.wait(used).expect("Lock poisoned");
+        }
+        *used += 1;
+        SlotGuard(self)
+    }
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.used.lock().expect("Lock poisoned") -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// An executable run once for every event it is dispatched. The event
+/// is exported as `PWRSURGE_*` environment variables and written to
+/// the script's standard input as a single line of JSON. The exit code
+/// of the script is treated like the return code of a library handler.
+pub struct Script {
+    path: Box<str>,
+    info: PluginInfo,
+    config: ScriptConfig,
+    slots: Slots,
+}
+
+impl Script {
+    pub fn load(path: &str, config: ScriptConfig) -> Result<Self, Box<dyn Error>> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+            return Err(Box::new(PluginError(format!(
+                "Script {} is not an executable file",
+                path
+            ))));
+        }
+        Ok(Script {
+            path: Box::from(path),
+            info: PluginInfo {
+                abi_version: ABI_VERSION,
+                info_size: mem::size_of::<PluginInfo>() as u32,
+                input_event_size: mem::size_of::<InputEventStruct>() as u32,
+                acpi_event_size: AcpiEvent::type_size().expect("Constant size") as u32,
+                capabilities: CAP_EVDEV
+                    | CAP_ACPI
+                    | CAP_IDLE
+                    | CAP_POWER_SUPPLY
+                    | CAP_THERMAL
+                    | CAP_RESUME
+                    | CAP_PRE_SUSPEND
+                    | CAP_POST_RESUME,
+                power_supply_size: mem::size_of::<PowerSupplyStruct>() as u32,
+                thermal_event_size: mem::size_of::<ThermalEventStruct>() as u32,
+            },
+            config,
+            slots: Slots {
+                used: Mutex::new(0),
+                freed: Condvar::new(),
+                max: config.max_concurrent,
+            },
+        })
+    }
+
+    fn run(&self, fields: Vec<(&'static str, Field)>) -> HandlerResult {
+        let _slot = self.slots.acquire();
+
+        let mut cmd = Command::new(&*self.path);
+        cmd.stdin(Stdio::piped());
+        for (name, value) in fields.iter() {
+            let var = format!("PWRSURGE_{}", name.to_uppercase());
+            match value {
+                Field::Str(s) => cmd.env(var, s),
+                Field::Int(i) => cmd.env(var, i.to_string()),
+            };
+        }
+        // In a session of its own, so that a timeout also kills the
+        // processes the script started
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn()?;
+        let start = Instant::now();
+        if let Some(mut stdin) = child.stdin.take() {
+            // A script that ignores its input may exit before reading it
+            match stdin.write_all(fields_to_json(&fields).as_bytes()) {
+                Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+                res => res?,
+            }
+        }
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if start.elapsed() >= self.config.timeout {
+                unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) };
+                let _ = child.wait();
+                return Err(format!(
+                    "Script {} timed out after {}ms and was killed",
+                    self.path,
+                    self.config.timeout.as_millis()
+                )
+                .into());
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        match status.code() {
+            Some(code) => {
+                println!(
+                    "Script {} exited with code {} after {}ms",
+                    self.path,
+                    code,
+                    start.elapsed().as_millis()
+                );
+                match code {
+                    0 => Ok(RET_HANDLED),
+                    EXIT_PASS => Ok(RET_PASS),
+                    EXIT_RETRY => Ok(RET_RETRY),
+                    code => Err(format!("Script {} failed with exit code {}", self.path, code).into()),
+                }
+            }
+            None => Err(format!(
+                "Script {} was killed by signal {}",
+                self.path,
+                status.signal().unwrap_or(0)
+            )
+            .into()),
+        }
+    }
+}
+
+impl Backend for Script {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    fn handle_evdev(&self, event: &InputEvent) -> Option<HandlerResult> {
+        Some(self.run(evdev_fields(event)))
+    }
+
+    /// Scripts are run once per event rather than once per frame.
+    fn handle_evdev_frame(&self, _events: &[InputEvent]) -> Option<HandlerResult> {
+        None
+    }
+
+    fn handle_acpi(&self, event: &AcpiEvent) -> Option<HandlerResult> {
+        Some(self.run(acpi_fields(event)))
+    }
+
+    fn handle_idle(&self, level: u32) -> Option<HandlerResult> {
+        Some(self.run(idle_fields(level)))
+    }
+
+    fn handle_resume(&self, reason: ResumeReason) -> Option<HandlerResult> {
+        Some(self.run(resume_fields(reason)))
+    }
+
+    fn handle_pre_suspend(&self) -> Option<HandlerResult> {
+        Some(self.run(vec![("source", Field::Str("pre_suspend".to_string()))]))
+    }
+
+    fn handle_post_resume(&self, slept: Duration) -> Option<HandlerResult> {
+        Some(self.run(post_resume_fields(slept)))
+    }
+
+    fn handle_power_supply(&self, state: &PowerSupplyState) -> Option<HandlerResult> {
+        Some(self.run(power_supply_fields(state)))
+    }
+
+    fn handle_thermal(&self, event: &ThermalEvent) -> Option<HandlerResult> {
+        Some(self.run(thermal_fields(event)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::acpi::{BusId, DeviceClass};
+
+    /// Write an executable shell script to a temporary file
+    fn script(name: &str, body: &str) -> String {
+        let path = std::env::temp_dir().join(format!("pwrsurge-{}-{}.sh", name, std::process::id()));
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let config = ScriptConfig::default();
+        for (code, expected) in &[
+            (0, Some(RET_HANDLED)),
+            (EXIT_PASS, Some(RET_PASS)),
+            (EXIT_RETRY, Some(RET_RETRY)),
+            (1, None),
+            (2, None),
+        ] {
+            let path = script(&format!("exit{}", code), &format!("exit {}", code));
+            let result = Script::load(&path, config).unwrap().run(Vec::new());
+            fs::remove_file(&path).unwrap();
+            assert_eq!(result.ok(), *expected, "exit {}", code);
+        }
+    }
+
+    #[test]
+    fn test_timeout_kills_group() {
+        let pid_path = std::env::temp_dir().join(format!("pwrsurge-timeout-{}.pid", std::process::id()));
+        let path = script(
+            "timeout",
+            &format!("sleep 30 &\necho $! > {}\nwait", pid_path.display()),
+        );
+        let config = ScriptConfig {
+            timeout: Duration::from_millis(500),
+            max_concurrent: 1,
+        };
+        assert!(Script::load(&path, config).unwrap().run(Vec::new()).is_err());
+        fs::remove_file(&path).unwrap();
+        let pid = fs::read_to_string(&pid_path).unwrap();
+        fs::remove_file(&pid_path).unwrap();
+        // Killed, although it may not have been reaped yet
+        thread::sleep(Duration::from_millis(100));
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        assert!(stat.is_empty() || stat.contains(") Z "), "{}", stat);
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("battery"), "\"battery\"");
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn test_acpi_json() {
+        let event = AcpiEvent {
+            device_class: DeviceClass("ac_adapter".to_string()),
+            bus_id: BusId("ACPI0003:00".to_string()),
+            event_type: 0x80,
+            event_data: 1,
+        };
+        assert_eq!(
+            fields_to_json(&acpi_fields(&event)),
+            "{\"source\":\"acpi\",\"device_class\":\"ac_adapter\",\"bus_id\":\"ACPI0003:00\",\
+             \"event_type\":128,\"event_data\":1}\n"
+        );
+    }
+
+    #[test]
+    fn test_evdev_json() {
+        let event = InputEvent::synthetic(5, 0, 1);
+        assert_eq!(
+            fields_to_json(&evdev_fields(&event)),
+            "{\"source\":\"evdev\",\"type\":5,\"code\":0,\"value\":1,\"time_sec\":0,\
+             \"time_usec\":0,\"type_name\":\"EV_SW\",\"code_name\":\"SW_LID\",\"synthetic\":1}\n"
+        );
+    }
+
+    #[test]
+    fn test_power_supply_json() {
+        let props = [
+            ("POWER_SUPPLY_NAME", "BAT0"),
+            ("POWER_SUPPLY_TYPE", "Battery"),
+            ("POWER_SUPPLY_STATUS", "Not charging"),
+            ("POWER_SUPPLY_CAPACITY", "80"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let state = PowerSupplyState::from_properties(&props).unwrap();
+        assert_eq!(
+            fields_to_json(&power_supply_fields(&state)),
+            "{\"source\":\"power_supply\",\"name\":\"BAT0\",\"supply_type\":\"Battery\",\
+             \"status\":\"Not charging\",\"capacity\":80}\n"
+        );
+    }
+}
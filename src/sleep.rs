@@ -0,0 +1,282 @@
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{spawn, task::spawn_blocking, time::interval};
+
+use crate::{
+    args::{LidAction, SuspendConfig},
+    dispatch::Dispatcher,
+    power::on_ac_power,
+};
+
+const POWER_STATE: &str = "/sys/power/state";
+const ACPI_LID_DIR: &str = "/proc/acpi/button/lid";
+/// How often the clocks are compared to notice a suspend the daemon did
+/// not start itself
+const RESUME_POLL: Duration = Duration::from_secs(5);
+/// Reading the two clocks is not atomic, so their difference wobbles
+/// slightly without the system ever sleeping
+const MIN_SLEEP: Duration = Duration::from_secs(1);
+
+/// Why the system resumed, as passed to `resume_handler`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResumeReason {
+    /// Anything but the wake alarm, such as opening the lid
+    Other = 0,
+    /// The wake alarm set before suspending
+    RtcAlarm = 1,
+    /// Resumed from hibernation
+    Hibernation = 2,
+}
+
+impl ResumeReason {
+    pub fn from_raw(raw: [u8; 4]) -> Option<Self> {
+        match u32::from_ne_bytes(raw) {
+            0 => Some(ResumeReason::Other),
+            1 => Some(ResumeReason::RtcAlarm),
+            2 => Some(ResumeReason::Hibernation),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ResumeReason::Other => "other",
+            ResumeReason::RtcAlarm => "rtc_alarm",
+            ResumeReason::Hibernation => "hibernation",
+        }
+    }
+}
+
+impl Display for ResumeReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Seconds since the epoch, the unit of `wakealarm`
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The alarm set for `alarm` woke the system up at `now` if it was due.
+/// RTCs only count whole seconds, so the alarm may still appear to be a
+/// second away.
+fn wake_reason(alarm: Option<u64>, now: u64) -> ResumeReason {
+    match alarm {
+        Some(alarm) if now + 1 >= alarm => ResumeReason::RtcAlarm,
+        _ => ResumeReason::Other,
+    }
+}
+
+fn clock(id: libc::clockid_t) -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(id, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Total time the system spent suspended since it booted.
+/// `CLOCK_BOOTTIME` keeps counting while suspended and hibernated,
+/// `CLOCK_MONOTONIC` does not.
+fn suspended_time() -> Duration {
+    clock(libc::CLOCK_BOOTTIME).saturating_sub(clock(libc::CLOCK_MONOTONIC))
+}
+
+/// How long the system slept if the suspended time grew from `last` to
+/// `now`
+fn slept_between(last: Duration, now: Duration) -> Option<Duration> {
+    match now.checked_sub(last) {
+        Some(slept) if slept >= MIN_SLEEP => Some(slept),
+        _ => None,
+    }
+}
+
+/// Whether ACPI reports any lid as closed. ACPI lid events do not say
+/// which way the lid moved.
+pub fn acpi_lid_closed() -> bool {
+    let entries = match fs::read_dir(ACPI_LID_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(Result::ok).any(|entry| {
+        fs::read_to_string(entry.path().join("state"))
+            .map(|state| state.contains("closed"))
+            .unwrap_or(false)
+    })
+}
+
+/// Suspends the system when the lid is closed, as configured in the
+/// `[suspend]` section.
+pub struct Sleep {
+    config: SuspendConfig,
+    /// Set while suspended, so that a lid close reported through both
+    /// ACPI and evdev only suspends once
+    busy: AtomicBool,
+    /// Suspended time when a resume was last reported
+    suspended: Mutex<Duration>,
+}
+
+impl Sleep {
+    pub fn new(config: SuspendConfig) -> Self {
+        Sleep {
+            config,
+            busy: AtomicBool::new(false),
+            suspended: Mutex::new(suspended_time()),
+        }
+    }
+
+    /// How long the system slept since this was last called, if at all
+    fn slept(&self) -> Option<Duration> {
+        let now = suspended_time();
+        let mut last = self.suspended.lock().expect("Lock poisoned");
+        let slept = slept_between(*last, now)?;
+        *last = now;
+        Some(slept)
+    }
+
+    /// Call `post_resume` after suspends the daemon did not start
+    /// itself, such as `systemctl suspend` or an idle level's action.
+    pub async fn watch_resume(self: Arc<Self>, lib: Arc<Dispatcher>) {
+        let mut interval = interval(RESUME_POLL);
+        loop {
+            interval.tick().await;
+            // Suspends started by the daemon are reported once it is done
+            if self.busy.load(Ordering::SeqCst) {
+                continue;
+            }
+            if let Some(slept) = self.slept() {
+                println!("Resumed after sleeping for {}s", slept.as_secs());
+                lib.dispatch_post_resume(slept).await;
+            }
+        }
+    }
+
+    fn wakealarm(&self) -> String {
+        format!("/sys/class/rtc/{}/wakealarm", self.config.rtc)
+    }
+
+    /// Program the RTC to wake the system up after `delay`. Returns the
+    /// time of the alarm in seconds since the epoch.
+    fn set_alarm(&self, delay: Duration) -> io::Result<u64> {
+        let path = self.wakealarm();
+        // A pending alarm has to be cleared before another one can be set
+        fs::write(&path, "0")?;
+        fs::write(&path, format!("+{}", delay.as_secs()))?;
+        let alarm = fs::read_to_string(&path)?;
+        alarm.trim().parse::<u64>().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid wakealarm {}: {}", alarm.trim(), e),
+            )
+        })
+    }
+
+    /// Suspend to RAM and return once the system resumed. With
+    /// `alarm_after`, the RTC wakes the system up after that long.
+    fn suspend(&self, alarm_after: Option<Duration>) -> io::Result<ResumeReason> {
+        let alarm = alarm_after.map(|delay| self.set_alarm(delay)).transpose()?;
+        let result = fs::write(POWER_STATE, "mem");
+        let reason = wake_reason(alarm, now_secs());
+        if alarm.is_some() && reason != ResumeReason::RtcAlarm {
+            if let Err(e) = fs::write(self.wakealarm(), "0") {
+                println!("Failed to clear the wake alarm: {}", e);
+            }
+        }
+        result?;
+        Ok(reason)
+    }
+
+    /// Suspend, and hibernate if woken up by the wake alarm on battery
+    /// power. On AC power, the system is suspended again instead.
+    fn suspend_then_hibernate(&self) -> io::Result<ResumeReason> {
+        let delay = self.config.hibernate_delay;
+        loop {
+            match self.suspend(Some(delay))? {
+                ResumeReason::RtcAlarm if on_ac_power() => {
+                    println!("Woken up by the wake alarm on AC power, suspending again")
+                }
+                ResumeReason::RtcAlarm => {
+                    println!(
+                        "Suspended on battery power for {}s, hibernating",
+                        delay.as_secs()
+                    );
+                    fs::write(POWER_STATE, "disk")?;
+                    return Ok(ResumeReason::Hibernation);
+                }
+                reason => return Ok(reason),
+            }
+        }
+    }
+
+    /// Carry out `lid_action`. Plugins are told before the system is
+    /// suspended, and how long it slept and why it woke up once it
+    /// stays up.
+    pub fn lid_closed(self: &Arc<Self>, lib: &Arc<Dispatcher>) {
+        let action = self.config.lid_action;
+        if action == LidAction::Ignore || self.busy.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let sleep = Arc::clone(self);
+        let lib = Arc::clone(lib);
+        spawn(async move {
+            println!("Lid closed, suspending");
+            lib.dispatch_pre_suspend().await;
+            // Only count the time slept from here on
+            sleep.slept();
+            let task_sleep = Arc::clone(&sleep);
+            let result = spawn_blocking(move || match action {
+                LidAction::SuspendThenHibernate => task_sleep.suspend_then_hibernate(),
+                _ => task_sleep.suspend(None),
+            })
+            .await;
+            let slept = sleep.slept().unwrap_or_default();
+            sleep.busy.store(false, Ordering::SeqCst);
+            // Also called if suspending failed, to undo pre_suspend
+            lib.dispatch_post_resume(slept).await;
+            match result {
+                Ok(Ok(reason)) => {
+                    println!("Resumed, reason: {}", reason);
+                    lib.dispatch_resume(reason).await;
+                }
+                Ok(Err(e)) => println!("Failed to suspend: {}", e),
+                Err(e) => println!("Suspend task failed: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_slept_between() {
+        let secs = Duration::from_secs;
+        assert_eq!(slept_between(secs(10), secs(10)), None);
+        assert_eq!(slept_between(secs(10), secs(10) + Duration::from_micros(3)), None);
+        assert_eq!(slept_between(secs(10), secs(9)), None);
+        assert_eq!(slept_between(secs(10), secs(3610)), Some(secs(3600)));
+    }
+
+    #[test]
+    fn test_wake_reason() {
+        assert_eq!(wake_reason(None, 1000), ResumeReason::Other);
+        assert_eq!(wake_reason(Some(1000), 500), ResumeReason::Other);
+        assert_eq!(wake_reason(Some(1000), 999), ResumeReason::RtcAlarm);
+        assert_eq!(wake_reason(Some(1000), 1000), ResumeReason::RtcAlarm);
+        assert_eq!(wake_reason(Some(1000), 1300), ResumeReason::RtcAlarm);
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt::{self, Display},
+    fs, mem, slice,
+};
+
+use neli::{
+    consts::nl::GenlId,
+    err::NlError,
+    genl::Genlmsghdr,
+    nl::{NlPayload, Nlmsghdr},
+};
+
+/// Generic netlink family and multicast group of thermal events
+pub const THERMAL_FAMILY: &str = "thermal";
+pub const THERMAL_EVENT_GROUP: &str = "event";
+
+// Commands of the thermal family (enum thermal_genl_event)
+const EVENT_TZ_TRIP_UP: u8 = 5;
+const EVENT_TZ_TRIP_DOWN: u8 = 6;
+const EVENT_TZ_TRIP_CHANGE: u8 = 7;
+const EVENT_CDEV_ADD: u8 = 10;
+const EVENT_CDEV_DELETE: u8 = 11;
+const EVENT_CDEV_STATE_UPDATE: u8 = 12;
+
+// Attributes of the thermal family (enum thermal_genl_attr)
+const ATTR_TZ_ID: u16 = 2;
+const ATTR_TZ_TEMP: u16 = 3;
+const ATTR_TZ_TRIP_ID: u16 = 5;
+const ATTR_TZ_TRIP_TYPE: u16 = 6;
+const ATTR_TZ_TRIP_TEMP: u16 = 7;
+const ATTR_TZ_TRIP_HYST: u16 = 8;
+const ATTR_CDEV_ID: u16 = 15;
+const ATTR_CDEV_CUR_STATE: u16 = 16;
+const ATTR_CDEV_MAX_STATE: u16 = 17;
+const ATTR_CDEV_NAME: u16 = 18;
+
+/// The kernel's THERMAL_TEMP_INVALID, passed to plugins for unknown
+/// temperatures
+const TEMP_INVALID: i32 = -274_000;
+
+/// Size of the type field of `ThermalEventStruct`, the kernel's
+/// THERMAL_NAME_LENGTH
+const TYPE_SIZE: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThermalEventKind {
+    TripUp = EVENT_TZ_TRIP_UP as isize,
+    TripDown = EVENT_TZ_TRIP_DOWN as isize,
+    TripChange = EVENT_TZ_TRIP_CHANGE as isize,
+    CdevAdd = EVENT_CDEV_ADD as isize,
+    CdevDelete = EVENT_CDEV_DELETE as isize,
+    CdevUpdate = EVENT_CDEV_STATE_UPDATE as isize,
+}
+
+impl ThermalEventKind {
+    const ALL: [ThermalEventKind; 6] = [
+        ThermalEventKind::TripUp,
+        ThermalEventKind::TripDown,
+        ThermalEventKind::TripChange,
+        ThermalEventKind::CdevAdd,
+        ThermalEventKind::CdevDelete,
+        ThermalEventKind::CdevUpdate,
+    ];
+
+    /// Name used in the config file and passed to scripts
+    pub fn name(self) -> &'static str {
+        match self {
+            ThermalEventKind::TripUp => "trip_up",
+            ThermalEventKind::TripDown => "trip_down",
+            ThermalEventKind::TripChange => "trip_change",
+            ThermalEventKind::CdevAdd => "cdev_add",
+            ThermalEventKind::CdevDelete => "cdev_delete",
+            ThermalEventKind::CdevUpdate => "cdev_update",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TripType {
+    Active = 0,
+    Passive = 1,
+    Hot = 2,
+    Critical = 3,
+}
+
+impl TripType {
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(TripType::Active),
+            1 => Some(TripType::Passive),
+            2 => Some(TripType::Hot),
+            3 => Some(TripType::Critical),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TripType::Active => "active",
+            TripType::Passive => "passive",
+            TripType::Hot => "hot",
+            TripType::Critical => "critical",
+        }
+    }
+}
+
+/// A trip point of a thermal zone that was crossed or changed.
+/// Temperatures are in millidegrees Celsius.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TripEvent {
+    pub zone_id: u32,
+    /// Type of the zone such as `x86_pkg_temp` or `acpitz`
+    pub zone_type: String,
+    pub trip_id: u32,
+    /// Only reported when a trip point changed
+    pub trip_type: Option<TripType>,
+    /// Temperature of the zone when a trip point was crossed, or of
+    /// the trip point when it changed
+    pub temp: Option<i32>,
+    pub hyst: Option<i32>,
+}
+
+/// A cooling device such as a fan or processor that was added, removed
+/// or changed its cooling state
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoolingDeviceEvent {
+    pub cdev_id: u32,
+    /// Type of the device such as `Processor` or `Fan`
+    pub cdev_type: String,
+    pub cur_state: Option<u32>,
+    pub max_state: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ThermalEvent {
+    TripUp(TripEvent),
+    TripDown(TripEvent),
+    TripChange(TripEvent),
+    CdevAdd(CoolingDeviceEvent),
+    CdevDelete(CoolingDeviceEvent),
+    CdevUpdate(CoolingDeviceEvent),
+}
+
+impl ThermalEvent {
+    pub fn kind(&self) -> ThermalEventKind {
+        match self {
+            ThermalEvent::TripUp(_) => ThermalEventKind::TripUp,
+            ThermalEvent::TripDown(_) => ThermalEventKind::TripDown,
+            ThermalEvent::TripChange(_) => ThermalEventKind::TripChange,
+            ThermalEvent::CdevAdd(_) => ThermalEventKind::CdevAdd,
+            ThermalEvent::CdevDelete(_) => ThermalEventKind::CdevDelete,
+            ThermalEvent::CdevUpdate(_) => ThermalEventKind::CdevUpdate,
+        }
+    }
+
+    /// Type of the thermal zone or cooling device
+    pub fn device_type(&self) -> &str {
+        match self {
+            ThermalEvent::TripUp(trip)
+            | ThermalEvent::TripDown(trip)
+            | ThermalEvent::TripChange(trip) => &trip.zone_type,
+            ThermalEvent::CdevAdd(cdev)
+            | ThermalEvent::CdevDelete(cdev)
+            | ThermalEvent::CdevUpdate(cdev) => &cdev.cdev_type,
+        }
+    }
+
+    /// The layout passed to `thermal_handler`
+    pub fn to_struct(&self) -> ThermalEventStruct {
+        let mut device_type = [0; TYPE_SIZE];
+        let len = self.device_type().len().min(TYPE_SIZE - 1);
+        device_type[..len].copy_from_slice(&self.device_type().as_bytes()[..len]);
+        let mut s = ThermalEventStruct {
+            event: self.kind() as u32,
+            id: 0,
+            device_type,
+            trip_id: -1,
+            trip_type: -1,
+            temp: TEMP_INVALID,
+            hyst: TEMP_INVALID,
+            cur_state: -1,
+            max_state: -1,
+        };
+        match self {
+            ThermalEvent::TripUp(trip)
+            | ThermalEvent::TripDown(trip)
+            | ThermalEvent::TripChange(trip) => {
+                s.id = trip.zone_id;
+                s.trip_id = trip.trip_id as i32;
+                s.trip_type = trip.trip_type.map(|t| t as i32).unwrap_or(-1);
+                s.temp = trip.temp.unwrap_or(TEMP_INVALID);
+                s.hyst = trip.hyst.unwrap_or(TEMP_INVALID);
+            }
+            ThermalEvent::CdevAdd(cdev)
+            | ThermalEvent::CdevDelete(cdev)
+            | ThermalEvent::CdevUpdate(cdev) => {
+                s.id = cdev.cdev_id;
+                s.cur_state = cdev.cur_state.map(|v| v as i32).unwrap_or(-1);
+                s.max_state = cdev.max_state.map(|v| v as i32).unwrap_or(-1);
+            }
+        }
+        s
+    }
+}
+
+impl Display for ThermalEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThermalEvent::TripUp(trip)
+            | ThermalEvent::TripDown(trip)
+            | ThermalEvent::TripChange(trip) => {
+                write!(
+                    f,
+                    "{} zone {} ({}) trip {}",
+                    self.kind().name(),
+                    trip.zone_id,
+                    trip.zone_type,
+                    trip.trip_id
+                )?;
+                if let Some(temp) = trip.temp {
+                    write!(f, " at {}.{:03}°C", temp / 1000, (temp % 1000).abs())?;
+                }
+                Ok(())
+            }
+            ThermalEvent::CdevAdd(cdev)
+            | ThermalEvent::CdevDelete(cdev)
+            | ThermalEvent::CdevUpdate(cdev) => {
+                write!(
+                    f,
+                    "{} cooling device {} ({})",
+                    self.kind().name(),
+                    cdev.cdev_id,
+                    cdev.cdev_type
+                )?;
+                if let Some(state) = cdev.cur_state {
+                    write!(f, " state {}", state)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// `ThermalEvent` as passed to plugins. Fields that do not apply to
+/// the event are -1, and temperatures -274000.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThermalEventStruct {
+    pub event: u32,
+    /// Thermal zone or cooling device
+    pub id: u32,
+    pub device_type: [u8; TYPE_SIZE],
+    pub trip_id: i32,
+    pub trip_type: i32,
+    pub temp: i32,
+    pub hyst: i32,
+    pub cur_state: i32,
+    pub max_state: i32,
+}
+
+impl ThermalEventStruct {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, mem::size_of::<Self>()) }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != mem::size_of::<Self>() {
+            return None;
+        }
+        Some(unsafe { (bytes.as_ptr() as *const Self).read_unaligned() })
+    }
+}
+
+fn sysfs_type(dir: &str) -> String {
+    fs::read_to_string(format!("/sys/class/thermal/{}/type", dir))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn attr_u32(attrs: &HashMap<u16, Vec<u8>>, attr: u16) -> Option<u32> {
+    attrs
+        .get(&attr)
+        .and_then(|payload| payload.get(..4))
+        .map(|bytes| u32::from_ne_bytes(bytes.try_into().expect("Constant size")))
+}
+
+fn attr_string(attrs: &HashMap<u16, Vec<u8>>, attr: u16) -> Option<String> {
+    attrs.get(&attr).map(|payload| {
+        let end = payload.iter().position(|b| *b == 0).unwrap_or(payload.len());
+        String::from_utf8_lossy(&payload[..end]).into_owned()
+    })
+}
+
+/// Decode the attributes of a thermal event. `zone_type` and
+/// `cdev_type` look up the type of a thermal zone or cooling device by
+/// its ID. Events of other kinds, and events missing the attributes
+/// identifying their zone or device, yield `None`.
+fn decode_event(
+    cmd: u8,
+    attrs: &HashMap<u16, Vec<u8>>,
+    zone_type: impl Fn(u32) -> String,
+    cdev_type: impl Fn(u32) -> String,
+) -> Option<ThermalEvent> {
+    let trip = || {
+        let zone_id = attr_u32(attrs, ATTR_TZ_ID)?;
+        Some(TripEvent {
+            zone_id,
+            zone_type: zone_type(zone_id),
+            trip_id: attr_u32(attrs, ATTR_TZ_TRIP_ID)?,
+            trip_type: attr_u32(attrs, ATTR_TZ_TRIP_TYPE).and_then(TripType::from_raw),
+            temp: attr_u32(attrs, ATTR_TZ_TEMP)
+                .or_else(|| attr_u32(attrs, ATTR_TZ_TRIP_TEMP))
+                .map(|t| t as i32),
+            hyst: attr_u32(attrs, ATTR_TZ_TRIP_HYST).map(|h| h as i32),
+        })
+    };
+    let cdev = || {
+        let cdev_id = attr_u32(attrs, ATTR_CDEV_ID)?;
+        Some(CoolingDeviceEvent {
+            cdev_id,
+            cdev_type: attr_string(attrs, ATTR_CDEV_NAME).unwrap_or_else(|| cdev_type(cdev_id)),
+            cur_state: attr_u32(attrs, ATTR_CDEV_CUR_STATE),
+            max_state: attr_u32(attrs, ATTR_CDEV_MAX_STATE),
+        })
+    };
+    Some(match cmd {
+        EVENT_TZ_TRIP_UP => ThermalEvent::TripUp(trip()?),
+        EVENT_TZ_TRIP_DOWN => ThermalEvent::TripDown(trip()?),
+        EVENT_TZ_TRIP_CHANGE => ThermalEvent::TripChange(trip()?),
+        EVENT_CDEV_ADD => ThermalEvent::CdevAdd(cdev()?),
+        EVENT_CDEV_DELETE => ThermalEvent::CdevDelete(cdev()?),
+        EVENT_CDEV_STATE_UPDATE => ThermalEvent::CdevUpdate(cdev()?),
+        _ => return None,
+    })
+}
+
+/// Decode a message of the thermal family's event group. Returns
+/// `None` for the events pwrsurge does not handle, such as thermal
+/// zones being created.
+pub fn thermal_event(
+    msg: Nlmsghdr<GenlId, Genlmsghdr<u8, u16>>,
+) -> Result<Option<ThermalEvent>, NlError> {
+    let genl = match msg.nl_payload {
+        NlPayload::Payload(genl) => genl,
+        NlPayload::Err(e) => return Err(NlError::from(e)),
+        NlPayload::Ack(_) => {
+            return Err(NlError::new("Received unexpected ACK from netlink"));
+        }
+        NlPayload::Empty => {
+            return Err(NlError::new("Received empty packet from netlink"));
+        }
+    };
+    let attrs = genl
+        .get_attr_handle()
+        .iter()
+        .map(|attr| (attr.nla_type, attr.nla_payload.as_ref().to_vec()))
+        .collect::<HashMap<_, _>>();
+    Ok(decode_event(
+        genl.cmd,
+        &attrs,
+        |id| sysfs_type(&format!("thermal_zone{}", id)),
+        |id| sysfs_type(&format!("cooling_device{}", id)),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn attrs(list: &[(u16, &[u8])]) -> HashMap<u16, Vec<u8>> {
+        list.iter().map(|(t, p)| (*t, p.to_vec())).collect()
+    }
+
+    fn zone_type(id: u32) -> String {
+        format!("zone{}", id)
+    }
+
+    fn cdev_type(id: u32) -> String {
+        format!("cdev{}", id)
+    }
+
+    #[test]
+    fn test_decode_trip_event() {
+        let event = decode_event(
+            EVENT_TZ_TRIP_UP,
+            &attrs(&[
+                (ATTR_TZ_ID, &1u32.to_ne_bytes()),
+                (ATTR_TZ_TRIP_ID, &0u32.to_ne_bytes()),
+                (ATTR_TZ_TEMP, &95_000u32.to_ne_bytes()),
+            ]),
+            zone_type,
+            cdev_type,
+        )
+        .unwrap();
+        assert_eq!(
+            event,
+            ThermalEvent::TripUp(TripEvent {
+                zone_id: 1,
+                zone_type: "zone1".to_string(),
+                trip_id: 0,
+                trip_type: None,
+                temp: Some(95_000),
+                hyst: None,
+            })
+        );
+        assert_eq!(event.to_string(), "trip_up zone 1 (zone1) trip 0 at 95.000°C");
+
+        let event = decode_event(
+            EVENT_TZ_TRIP_CHANGE,
+            &attrs(&[
+                (ATTR_TZ_ID, &1u32.to_ne_bytes()),
+                (ATTR_TZ_TRIP_ID, &2u32.to_ne_bytes()),
+                (ATTR_TZ_TRIP_TYPE, &1u32.to_ne_bytes()),
+                (ATTR_TZ_TRIP_TEMP, &80_000u32.to_ne_bytes()),
+                (ATTR_TZ_TRIP_HYST, &2_000u32.to_ne_bytes()),
+            ]),
+            zone_type,
+            cdev_type,
+        )
+        .unwrap();
+        let s = event.to_struct();
+        assert_eq!(s.event, 7);
+        assert_eq!((s.id, s.trip_id, s.trip_type), (1, 2, 1));
+        assert_eq!((s.temp, s.hyst, s.cur_state), (80_000, 2_000, -1));
+        assert_eq!(&s.device_type[..6], b"zone1\0");
+        assert_eq!(ThermalEventStruct::from_bytes(s.as_bytes()), Some(s));
+
+        // The zone is required
+        assert_eq!(
+            decode_event(
+                EVENT_TZ_TRIP_DOWN,
+                &attrs(&[(ATTR_TZ_TRIP_ID, &0u32.to_ne_bytes())]),
+                zone_type,
+                cdev_type
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_decode_cdev_event() {
+        let event = decode_event(
+            EVENT_CDEV_ADD,
+            &attrs(&[
+                (ATTR_CDEV_ID, &3u32.to_ne_bytes()),
+                (ATTR_CDEV_NAME, b"Fan\0"),
+                (ATTR_CDEV_MAX_STATE, &10u32.to_ne_bytes()),
+            ]),
+            zone_type,
+            cdev_type,
+        )
+        .unwrap();
+        assert_eq!(event.kind(), ThermalEventKind::CdevAdd);
+        assert_eq!(event.device_type(), "Fan");
+
+        let event = decode_event(
+            EVENT_CDEV_STATE_UPDATE,
+            &attrs(&[
+                (ATTR_CDEV_ID, &3u32.to_ne_bytes()),
+                (ATTR_CDEV_CUR_STATE, &4u32.to_ne_bytes()),
+            ]),
+            zone_type,
+            cdev_type,
+        )
+        .unwrap();
+        assert_eq!(
+            event,
+            ThermalEvent::CdevUpdate(CoolingDeviceEvent {
+                cdev_id: 3,
+                cdev_type: "cdev3".to_string(),
+                cur_state: Some(4),
+                max_state: None,
+            })
+        );
+        assert_eq!(event.to_struct().cur_state, 4);
+
+        // Thermal zone creation is not handled
+        assert_eq!(
+            decode_event(1, &attrs(&[(ATTR_TZ_ID, &1u32.to_ne_bytes())]), zone_type, cdev_type),
+            None
+        );
+    }
+
+    #[test]
+    fn test_thermal_event_kind_names() {
+        for kind in ThermalEventKind::ALL.iter() {
+            assert_eq!(ThermalEventKind::from_name(kind.name()), Some(*kind));
+        }
+        assert_eq!(ThermalEventKind::from_name("tz_create"), None);
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    spawn,
+    time::{sleep, Instant},
+};
+
+use crate::{acpi::AcpiEvent, evdev::InputEvent, filter::AcpiFilter, rule::Expr};
+
+/// Selects the events a throttle rule applies to
+pub trait EventMatcher: Send + Sync + 'static {
+    type Event: Display + Send + 'static;
+
+    fn matches(&self, event: &Self::Event) -> bool;
+}
+
+impl EventMatcher for AcpiFilter {
+    type Event = AcpiEvent;
+
+    fn matches(&self, event: &AcpiEvent) -> bool {
+        AcpiFilter::matches(self, event)
+    }
+}
+
+impl EventMatcher for Expr {
+    type Event = InputEvent;
+
+    fn matches(&self, event: &InputEvent) -> bool {
+        Expr::matches(self, event)
+    }
+}
+
+/// How a throttle rule holds back events with the same key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    /// Deliver the latest event once no other one arrived for this long
+    Debounce(Duration),
+    /// Deliver the latest event this long after the first one
+    Coalesce(Duration),
+}
+
+/// Token bucket refilled with `rate` tokens per second up to `burst`.
+/// Delivering an event takes a token; events finding the bucket empty
+/// are dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// A `[throttle.NAME]` section
+pub struct ThrottleRule<M> {
+    pub name: String,
+    pub matcher: M,
+    pub window: Option<Window>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// The throttle rules of each event source
+pub struct ThrottleConfig {
+    pub acpi: Vec<ThrottleRule<AcpiFilter>>,
+    pub evdev: Vec<ThrottleRule<Expr>>,
+}
+
+/// An event held back by a window and how to deliver it
+struct Pending<E> {
+    /// Tells the timer of a debounce window whether another event
+    /// arrived since it was started
+    generation: u64,
+    event: E,
+    deliver: Box<dyn FnOnce(E) + Send>,
+}
+
+/// Held back events by rule index and key
+type PendingEvents<E> = HashMap<(usize, String), Pending<E>>;
+
+/// Applies the first matching throttle rule to each event before it is
+/// dispatched. Windows and pending events are kept per rule and key;
+/// rate limits are shared by all keys of a rule.
+pub struct Throttle<M: EventMatcher> {
+    rules: Vec<(ThrottleRule<M>, Mutex<Bucket>)>,
+    pending: Mutex<PendingEvents<M::Event>>,
+    generation: AtomicU64,
+}
+
+impl<M: EventMatcher> Throttle<M> {
+    pub fn new(rules: Vec<ThrottleRule<M>>) -> Self {
+        let now = Instant::now();
+        Throttle {
+            rules: rules
+                .into_iter()
+                .map(|rule| {
+                    let tokens = rule.rate_limit.map(|l| l.burst.into()).unwrap_or_default();
+                    (
+                        rule,
+                        Mutex::new(Bucket {
+                            tokens,
+                            refilled: now,
+                        }),
+                    )
+                })
+                .collect(),
+            pending: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the event if it can be dispatched right away. Otherwise
+    /// it was dropped or is held back and later passed to `deliver`.
+    pub fn submit<F>(self: &Arc<Self>, key: String, event: M::Event, deliver: F) -> Option<M::Event>
+    where
+        F: FnOnce(M::Event) + Send + 'static,
+    {
+        let index = match self
+            .rules
+            .iter()
+            .position(|(rule, _)| rule.matcher.matches(&event))
+        {
+            Some(index) => index,
+            None => return Some(event),
+        };
+        let window = match self.rules[index].0.window {
+            Some(window) => window,
+            None => return self.rate_limit(index, event),
+        };
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let pending = Pending {
+            generation,
+            event,
+            deliver: Box::new(deliver),
+        };
+        let mut held = self.pending.lock().expect("Lock poisoned");
+        let (duration, check) = match (window, held.entry((index, key.clone()))) {
+            (Window::Debounce(duration), entry) => {
+                match entry {
+                    Entry::Occupied(mut entry) => *entry.get_mut() = pending,
+                    Entry::Vacant(entry) => {
+                        entry.insert(pending);
+                    }
+                }
+                (duration, Some(generation))
+            }
+            // The timer of the window is already running
+            (Window::Coalesce(_), Entry::Occupied(mut entry)) => {
+                *entry.get_mut() = pending;
+                return None;
+            }
+            (Window::Coalesce(duration), Entry::Vacant(entry)) => {
+                entry.insert(pending);
+                (duration, None)
+            }
+        };
+        let throttle = Arc::clone(self);
+        spawn(async move {
+            sleep(duration).await;
+            throttle.expire(index, key, check);
+        });
+        None
+    }
+
+    /// Deliver the event held back under the key unless a debounce
+    /// window was restarted by a later event.
+    fn expire(&self, index: usize, key: String, generation: Option<u64>) {
+        let mut held = self.pending.lock().expect("Lock poisoned");
+        let entry = match held.entry((index, key)) {
+            Entry::Occupied(entry) => entry,
+            Entry::Vacant(_) => return,
+        };
+        if generation
+            .map(|g| g != entry.get().generation)
+            .unwrap_or(false)
+        {
+            return;
+        }
+        let Pending { event, deliver, .. } = entry.remove();
+        drop(held);
+        if let Some(event) = self.rate_limit(index, event) {
+            deliver(event);
+        }
+    }
+
+    fn rate_limit(&self, index: usize, event: M::Event) -> Option<M::Event> {
+        let (rule, bucket) = &self.rules[index];
+        let limit = match rule.rate_limit {
+            Some(limit) => limit,
+            None => return Some(event),
+        };
+        let mut bucket = bucket.lock().expect("Lock poisoned");
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst.into());
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Some(event)
+        } else {
+            println!(
+                "Dropping event {} over the rate limit of throttle rule {}",
+                event, rule.name
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::{runtime::Builder, sync::mpsc, time};
+
+    fn rule(window: Option<Window>, rate_limit: Option<RateLimit>) -> ThrottleRule<Expr> {
+        ThrottleRule {
+            name: "lid".to_string(),
+            matcher: Expr::parse("type=EV_SW").unwrap(),
+            window,
+            rate_limit,
+        }
+    }
+
+    /// Submit the lid events with the given values this far apart and
+    /// collect the values dispatched right away and delivered later.
+    /// Time is paused, so the timers fire in order of their deadlines
+    /// however loaded the machine is.
+    fn run(throttle: Throttle<Expr>, values: &[(u64, i32)]) -> (Vec<i32>, Vec<i32>) {
+        let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            time::pause();
+            let throttle = Arc::new(throttle);
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            let mut now = Vec::new();
+            for &(delay, value) in values {
+                sleep(Duration::from_millis(delay)).await;
+                let sender = sender.clone();
+                let event = InputEvent::synthetic(5, 0, value);
+                let deliver = move |event: InputEvent| {
+                    let _ = sender.send(event.get_event_value());
+                };
+                if let Some(event) = throttle.submit("lid".to_string(), event, deliver) {
+                    now.push(event.get_event_value());
+                }
+            }
+            sleep(Duration::from_millis(150)).await;
+            drop(sender);
+            drop(throttle);
+            let mut later = Vec::new();
+            while let Ok(value) = receiver.try_recv() {
+                later.push(value);
+            }
+            (now, later)
+        })
+    }
+
+    #[test]
+    fn test_throttle() {
+        let keys = Throttle::new(vec![rule(None, None)]);
+        let (now, later) = run(keys, &[(0, 1)]);
+        assert_eq!((now, later), (vec![1], vec![]));
+
+        let debounce = Throttle::new(vec![rule(
+            Some(Window::Debounce(Duration::from_millis(50))),
+            None,
+        )]);
+        // Restarted by each bounce, then delivered once
+        let (now, later) = run(debounce, &[(0, 1), (30, 0), (30, 1)]);
+        assert_eq!((now, later), (vec![], vec![1]));
+
+        let coalesce = Throttle::new(vec![rule(
+            Some(Window::Coalesce(Duration::from_millis(50))),
+            None,
+        )]);
+        let (now, later) = run(coalesce, &[(0, 1), (10, 0), (60, 1)]);
+        assert_eq!((now, later), (vec![], vec![0, 1]));
+
+        let limited = Throttle::new(vec![rule(
+            None,
+            Some(RateLimit {
+                rate: 1.0,
+                burst: 2,
+            }),
+        )]);
+        let (now, later) = run(limited, &[(0, 1), (0, 0), (0, 1)]);
+        assert_eq!((now, later), (vec![1, 0], vec![]));
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    mem,
+    path::Path,
+    str,
+    sync::Mutex,
+    time::Duration,
+};
+
+use neli::{utils::serialize, Nl};
+use wasmtime::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::{
+    acpi::AcpiEvent,
+    args::WasmConfig,
+    evdev::{InputEvent, MAX_FRAME_EVENTS},
+    plugin::{
+        Backend, HandlerResult, PluginError, PluginInfo, ABI_VERSION, CAP_ACPI, CAP_EVDEV,
+        CAP_EVDEV_FRAME, CAP_IDLE, CAP_POST_RESUME, CAP_POWER_SUPPLY, CAP_PRE_SUSPEND,
+        CAP_RESUME, CAP_THERMAL,
+    },
+    power::PowerSupplyState,
+    sleep::ResumeReason,
+    thermal::ThermalEvent,
+};
+
+/// Module name of the functions the daemon provides to WebAssembly
+/// handlers
+const HOST_MODULE: &str = "pwrsurge";
+
+/// Size of an input event as passed to a WebAssembly handler: seconds
+/// (i64), microseconds (i64), type (u16), code (u16) and value (i32),
+/// all little endian. Unlike `InputEventStruct`, this layout does not
+/// depend on the target the daemon was built for.
+const WASM_INPUT_EVENT_SIZE: usize = 24;
+
+/// Size of a power supply state as passed to a WebAssembly handler:
+/// the fields of `PowerSupplyStruct` in order, all little endian
+const WASM_POWER_SUPPLY_SIZE: usize = 88;
+
+/// Size of a thermal event as passed to a WebAssembly handler: the
+/// fields of `ThermalEventStruct` in order, all little endian
+const WASM_THERMAL_EVENT_SIZE: usize = 52;
+
+/// Roughly the number of WebAssembly instructions a handler may
+/// execute per call before it is stopped
+const CALL_FUEL: u64 = 100_000_000;
+
+type HandlerFn = TypedFunc<(i32, i32), i32>;
+
+// time_t and suseconds_t are only 64 bits wide on some targets
+#[allow(clippy::unnecessary_cast)]
+fn wasm_input_event(event: &InputEvent) -> [u8; WASM_INPUT_EVENT_SIZE] {
+    let timestamp = event.get_timestamp();
+    let mut buf = [0; WASM_INPUT_EVENT_SIZE];
+    buf[0..8].copy_from_slice(&(timestamp.tv_sec as i64).to_le_bytes());
+    buf[8..16].copy_from_slice(&(timestamp.tv_usec as i64).to_le_bytes());
+    buf[16..18].copy_from_slice(&event.get_event_type().to_le_bytes());
+    buf[18..20].copy_from_slice(&event.get_event_code().to_le_bytes());
+    buf[20..24].copy_from_slice(&event.get_event_value().to_le_bytes());
+    buf
+}
+
+fn wasm_power_supply(state: &PowerSupplyState) -> [u8; WASM_POWER_SUPPLY_SIZE] {
+    let s = state.to_struct();
+    let mut buf = [0; WASM_POWER_SUPPLY_SIZE];
+    buf[0..32].copy_from_slice(&s.name);
+    buf[32..36].copy_from_slice(&s.supply_type.to_le_bytes());
+    buf[36..40].copy_from_slice(&s.status.to_le_bytes());
+    buf[40..44].copy_from_slice(&s.online.to_le_bytes());
+    buf[44..48].copy_from_slice(&s.capacity.to_le_bytes());
+    buf[48..56].copy_from_slice(&s.energy_now.to_le_bytes());
+    buf[56..64].copy_from_slice(&s.energy_full.to_le_bytes());
+    buf[64..72].copy_from_slice(&s.charge_now.to_le_bytes());
+    buf[72..80].copy_from_slice(&s.charge_full.to_le_bytes());
+    buf[80..88].copy_from_slice(&s.time_to_empty.to_le_bytes());
+    buf
+}
+
+fn wasm_thermal_event(event: &ThermalEvent) -> [u8; WASM_THERMAL_EVENT_SIZE] {
+    let s = event.to_struct();
+    let mut buf = [0; WASM_THERMAL_EVENT_SIZE];
+    buf[0..4].copy_from_slice(&s.event.to_le_bytes());
+    buf[4..8].copy_from_slice(&s.id.to_le_bytes());
+    buf[8..28].copy_from_slice(&s.device_type);
+    buf[28..32].copy_from_slice(&s.trip_id.to_le_bytes());
+    buf[32..36].copy_from_slice(&s.trip_type.to_le_bytes());
+    buf[36..40].copy_from_slice(&s.temp.to_le_bytes());
+    buf[40..44].copy_from_slice(&s.hyst.to_le_bytes());
+    buf[44..48].copy_from_slice(&s.cur_state.to_le_bytes());
+    buf[48..52].copy_from_slice(&s.max_state.to_le_bytes());
+    buf
+}
+
+fn errno(e: &io::Error) -> i32 {
+    -e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// State available to host functions
+struct HostState {
+    path: Box<str>,
+    config: WasmConfig,
+}
+
+fn caller_memory(caller: &mut Caller<'_, HostState>) -> Option<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Some(memory),
+        _ => None,
+    }
+}
+
+/// Copy `len` bytes at `ptr` out of the module's memory.
+fn read_guest(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> Result<Vec<u8>, i32> {
+    let memory = caller_memory(caller).ok_or(-libc::EFAULT)?;
+    let mut buf = vec![0; len.max(0) as usize];
+    memory
+        .read(&*caller, ptr as u32 as usize, &mut buf)
+        .map_err(|_| -libc::EFAULT)?;
+    Ok(buf)
+}
+
+fn read_guest_path(
+    caller: &mut Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+) -> Result<String, i32> {
+    String::from_utf8(read_guest(caller, ptr, len)?).map_err(|_| -libc::EINVAL)
+}
+
+fn host_log(mut caller: Caller<'_, HostState>, ptr: i32, len: i32) {
+    if let Ok(buf) = read_guest(&mut caller, ptr, len) {
+        println!("{}: {}", caller.data().path, String::from_utf8_lossy(&buf));
+    }
+}
+
+/// Read up to `buf_len` bytes of an allowed sysfs file into the module's
+/// memory. Returns the number of bytes read or a negative errno.
+fn host_sysfs_read(
+    mut caller: Caller<'_, HostState>,
+    path_ptr: i32,
+    path_len: i32,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i32 {
+    let path = match read_guest_path(&mut caller, path_ptr, path_len) {
+        Ok(path) => path,
+        Err(e) => return e,
+    };
+    if !caller.data().config.can_read(Path::new(&path)) {
+        return -libc::EACCES;
+    }
+    let mut contents = Vec::new();
+    if let Err(e) = File::open(&path)
+        .and_then(|file| file.take(buf_len.max(0) as u64).read_to_end(&mut contents))
+    {
+        return errno(&e);
+    }
+    let memory = match caller_memory(&mut caller) {
+        Some(memory) => memory,
+        None => return -libc::EFAULT,
+    };
+    match memory.write(&mut caller, buf_ptr as u32 as usize, &contents) {
+        Ok(()) => contents.len() as i32,
+        Err(_) => -libc::EFAULT,
+    }
+}
+
+/// Write `buf_len` bytes from the module's memory to an allowed sysfs
+/// file. Returns the number of bytes written or a negative errno.
+fn host_sysfs_write(
+    mut caller: Caller<'_, HostState>,
+    path_ptr: i32,
+    path_len: i32,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i32 {
+    let path = match read_guest_path(&mut caller, path_ptr, path_len) {
+        Ok(path) => path,
+        Err(e) => return e,
+    };
+    if !caller.data().config.can_write(Path::new(&path)) {
+        return -libc::EACCES;
+    }
+    let contents = match read_guest(&mut caller, buf_ptr, buf_len) {
+        Ok(contents) => contents,
+        Err(e) => return e,
+    };
+    // sysfs attributes must be written with a single write call
+    match OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|mut file| file.write(&contents))
+    {
+        Ok(n) => n as i32,
+        Err(e) => errno(&e),
+    }
+}
+
+/// An instantiated module and the exports the daemon calls
+struct Instance {
+    store: Store<HostState>,
+    memory: Memory,
+    /// Buffer in the module's memory that events are copied into
+    buffer: i32,
+    evdev_handler: Option<HandlerFn>,
+    evdev_frame_handler: Option<HandlerFn>,
+    acpi_handler: Option<HandlerFn>,
+    idle_handler: Option<HandlerFn>,
+    resume_handler: Option<HandlerFn>,
+    pre_suspend: Option<HandlerFn>,
+    post_resume: Option<HandlerFn>,
+    power_supply_handler: Option<HandlerFn>,
+    thermal_handler: Option<HandlerFn>,
+}
+
+/// A handler compiled to WebAssembly. The module can only reach the
+/// system through the host functions, which restrict it to the sysfs
+/// paths allowed in its configuration.
+pub struct WasmPlugin {
+    path: Box<str>,
+    info: PluginInfo,
+    config: WasmConfig,
+    module: Module,
+    linker: Linker<HostState>,
+    instance: Mutex<Instance>,
+}
+
+impl WasmPlugin {
+    pub fn load(path: &str, config: WasmConfig) -> Result<Self, Box<dyn Error>> {
+        let mut engine_config = Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config)?;
+        let module = Module::from_file(&engine, path)?;
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap(HOST_MODULE, "log", host_log)?;
+        linker.func_wrap(HOST_MODULE, "sysfs_read", host_sysfs_read)?;
+        linker.func_wrap(HOST_MODULE, "sysfs_write", host_sysfs_write)?;
+
+        let instance = Self::instantiate(path, &config, &module, &linker)?;
+        let mut capabilities = 0;
+        if instance.evdev_handler.is_some() {
+            capabilities |= CAP_EVDEV;
+        }
+        if instance.evdev_frame_handler.is_some() {
+            capabilities |= CAP_EVDEV_FRAME;
+        }
+        if instance.acpi_handler.is_some() {
+            capabilities |= CAP_ACPI;
+        }
+        if instance.idle_handler.is_some() {
+            capabilities |= CAP_IDLE;
+        }
+        if instance.resume_handler.is_some() {
+            capabilities |= CAP_RESUME;
+        }
+        if instance.pre_suspend.is_some() {
+            capabilities |= CAP_PRE_SUSPEND;
+        }
+        if instance.post_resume.is_some() {
+            capabilities |= CAP_POST_RESUME;
+        }
+        if instance.power_supply_handler.is_some() {
+            capabilities |= CAP_POWER_SUPPLY;
+        }
+        if instance.thermal_handler.is_some() {
+            capabilities |= CAP_THERMAL;
+        }
+        Ok(WasmPlugin {
+            path: Box::from(path),
+            info: PluginInfo {
+                abi_version: ABI_VERSION,
+                info_size: mem::size_of::<PluginInfo>() as u32,
+                input_event_size: WASM_INPUT_EVENT_SIZE as u32,
+                acpi_event_size: AcpiEvent::type_size().expect("Constant size") as u32,
+                capabilities,
+                power_supply_size: WASM_POWER_SUPPLY_SIZE as u32,
+                thermal_event_size: WASM_THERMAL_EVENT_SIZE as u32,
+            },
+            config,
+            module,
+            linker,
+            instance: Mutex::new(instance),
+        })
+    }
+
+    fn instantiate(
+        path: &str,
+        config: &WasmConfig,
+        module: &Module,
+        linker: &Linker<HostState>,
+    ) -> Result<Instance, Box<dyn Error>> {
+        let mut store = Store::new(
+            module.engine(),
+            HostState {
+                path: Box::from(path),
+                config: config.clone(),
+            },
+        );
+        store.set_fuel(CALL_FUEL)?;
+        let instance = linker.instantiate(&mut store, module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| PluginError(format!("Module {} does not export memory", path)))?;
+        let evdev_handler = instance
+            .get_func(&mut store, "evdev_handler")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        let evdev_frame_handler = instance
+            .get_func(&mut store, "evdev_frame_handler")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        let acpi_handler = instance
+            .get_func(&mut store, "acpi_handler")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        let idle_handler = instance
+            .get_func(&mut store, "idle_handler")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        let resume_handler = instance
+            .get_func(&mut store, "resume_handler")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        let pre_suspend = instance
+            .get_func(&mut store, "pre_suspend")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        let post_resume = instance
+            .get_func(&mut store, "post_resume")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        let power_supply_handler = instance
+            .get_func(&mut store, "power_supply_handler")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        let thermal_handler = instance
+            .get_func(&mut store, "thermal_handler")
+            .map(|f| f.typed::<(i32, i32), i32>(&store))
+            .transpose()?;
+        if evdev_handler.is_none()
+            && evdev_frame_handler.is_none()
+            && acpi_handler.is_none()
+            && idle_handler.is_none()
+            && resume_handler.is_none()
+            && pre_suspend.is_none()
+            && post_resume.is_none()
+            && power_supply_handler.is_none()
+            && thermal_handler.is_none()
+        {
+            return Err(Box::new(PluginError(format!(
+                "Module {} exports none of evdev_handler, evdev_frame_handler, acpi_handler, \
+                 idle_handler, resume_handler, pre_suspend, post_resume, power_supply_handler \
+                 and thermal_handler",
+                path
+            ))));
+        }
+
+        if let Some(init) = instance.get_func(&mut store, "pwrsurge_init") {
+            let code = init.typed::<(), i32>(&store)?.call(&mut store, ())?;
+            if code != 0 {
+                return Err(Box::new(PluginError(format!(
+                    "pwrsurge_init of module {} returned {}",
+                    path, code
+                ))));
+            }
+        }
+
+        // Frames only need room if they are handled
+        let frame_len = match evdev_frame_handler {
+            Some(_) => MAX_FRAME_EVENTS * WASM_INPUT_EVENT_SIZE,
+            None => 0,
+        };
+        let buffer_len = frame_len
+            .max(WASM_INPUT_EVENT_SIZE)
+            .max(AcpiEvent::type_size().expect("Constant size"))
+            .max(WASM_POWER_SUPPLY_SIZE)
+            .max(WASM_THERMAL_EVENT_SIZE);
+        store.set_fuel(CALL_FUEL)?;
+        let buffer = instance
+            .get_typed_func::<i32, i32>(&mut store, "pwrsurge_alloc")?
+            .call(&mut store, buffer_len as i32)?;
+        if buffer <= 0 || (buffer as usize).saturating_add(buffer_len) > memory.data_size(&store) {
+            return Err(Box::new(PluginError(format!(
+                "pwrsurge_alloc of module {} returned invalid buffer {:#x}",
+                path, buffer
+            ))));
+        }
+
+        Ok(Instance {
+            store,
+            memory,
+            buffer,
+            evdev_handler,
+            evdev_frame_handler,
+            acpi_handler,
+            idle_handler,
+            resume_handler,
+            pre_suspend,
+            post_resume,
+            power_supply_handler,
+            thermal_handler,
+        })
+    }
+
+    fn call(
+        &self,
+        select: fn(&Instance) -> Option<HandlerFn>,
+        event: &[u8],
+    ) -> Option<HandlerResult> {
+        let mut instance = self.instance.lock().expect("Lock poisoned");
+        let handler = select(&instance)?;
+        let Instance {
+            ref mut store,
+            memory,
+            buffer,
+            ..
+        } = *instance;
+        let result = memory
+            .write(&mut *store, buffer as usize, event)
+            .map_err(Box::<dyn Error + Send + Sync>::from)
+            .and_then(|()| store.set_fuel(CALL_FUEL).map_err(Into::into))
+            .and_then(|()| {
+                // Include the cause of a trap, such as running out of fuel
+                handler
+                    .call(&mut *store, (buffer, event.len() as i32))
+                    .map_err(|e| format!("{:#}", e).into())
+            });
+        if result.is_err() {
+            // A trap can leave the module's own state half updated, so
+            // start over with a fresh instance.
+            match Self::instantiate(&self.path, &self.config, &self.module, &self.linker) {
+                Ok(fresh) => *instance = fresh,
+                Err(e) => println!("Failed to reinstantiate module {}: {}", self.path, e),
+            }
+        }
+        Some(result)
+    }
+}
+
+impl Backend for WasmPlugin {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    fn handle_evdev(&self, event: &InputEvent) -> Option<HandlerResult> {
+        self.call(|instance| instance.evdev_handler.clone(), &wasm_input_event(event))
+    }
+
+    fn handle_evdev_frame(&self, events: &[InputEvent]) -> Option<HandlerResult> {
+        let frame = events.iter().flat_map(wasm_input_event).collect::<Vec<_>>();
+        self.call(|instance| instance.evdev_frame_handler.clone(), &frame)
+    }
+
+    fn handle_acpi(&self, event: &AcpiEvent) -> Option<HandlerResult> {
+        match serialize(event, false) {
+            Ok(buf) => self.call(|instance| instance.acpi_handler.clone(), buf.as_slice()),
+            Err(e) => Some(Err(Box::new(e))),
+        }
+    }
+
+    fn handle_idle(&self, level: u32) -> Option<HandlerResult> {
+        self.call(|instance| instance.idle_handler.clone(), &level.to_le_bytes())
+    }
+
+    fn handle_resume(&self, reason: ResumeReason) -> Option<HandlerResult> {
+        self.call(
+            |instance| instance.resume_handler.clone(),
+            &(reason as u32).to_le_bytes(),
+        )
+    }
+
+    fn handle_pre_suspend(&self) -> Option<HandlerResult> {
+        self.call(|instance| instance.pre_suspend.clone(), &[])
+    }
+
+    fn handle_post_resume(&self, slept: Duration) -> Option<HandlerResult> {
+        self.call(
+            |instance| instance.post_resume.clone(),
+            &(slept.as_millis() as u64).to_le_bytes(),
+        )
+    }
+
+    fn handle_power_supply(&self, state: &PowerSupplyState) -> Option<HandlerResult> {
+        self.call(
+            |instance| instance.power_supply_handler.clone(),
+            &wasm_power_supply(state),
+        )
+    }
+
+    fn handle_thermal(&self, event: &ThermalEvent) -> Option<HandlerResult> {
+        self.call(
+            |instance| instance.thermal_handler.clone(),
+            &wasm_thermal_event(event),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::evdev::InputEventStruct;
+
+    #[test]
+    fn test_wasm_input_event() {
+        let mut event = InputEvent::new_buffer([0; mem::size_of::<InputEventStruct>()]);
+        event.set_timestamp(libc::timeval {
+            tv_sec: 5,
+            tv_usec: 7,
+        });
+        event.set_event_type(5);
+        event.set_event_code(0);
+        event.set_event_value(-1);
+        let buf = wasm_input_event(&event);
+        assert_eq!(&buf[0..8], &5i64.to_le_bytes());
+        assert_eq!(&buf[8..16], &7i64.to_le_bytes());
+        assert_eq!(&buf[16..20], &[5, 0, 0, 0]);
+        assert_eq!(&buf[20..24], &(-1i32).to_le_bytes());
+    }
+
+    #[test]
+    fn test_wasm_power_supply() {
+        let props = [
+            ("POWER_SUPPLY_NAME", "AC"),
+            ("POWER_SUPPLY_TYPE", "Mains"),
+            ("POWER_SUPPLY_ONLINE", "1"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let state = PowerSupplyState::from_properties(&props).unwrap();
+        let buf = wasm_power_supply(&state);
+        assert_eq!(&buf[0..3], b"AC\0");
+        assert_eq!(&buf[32..44], &[2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&buf[80..88], &(-1i64).to_le_bytes());
+        assert_eq!(WASM_POWER_SUPPLY_SIZE, mem::size_of::<crate::power::PowerSupplyStruct>());
+        #[cfg(target_endian = "little")]
+        assert_eq!(&buf[..], state.to_struct().as_bytes());
+    }
+
+    #[test]
+    fn test_wasm_thermal_event() {
+        let event = ThermalEvent::CdevUpdate(crate::thermal::CoolingDeviceEvent {
+            cdev_id: 3,
+            cdev_type: "Processor".to_string(),
+            cur_state: Some(2),
+            max_state: None,
+        });
+        let buf = wasm_thermal_event(&event);
+        assert_eq!(&buf[0..8], &[12, 0, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(&buf[8..18], b"Processor\0");
+        assert_eq!(&buf[44..52], &[2, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(WASM_THERMAL_EVENT_SIZE, mem::size_of::<crate::thermal::ThermalEventStruct>());
+        #[cfg(target_endian = "little")]
+        assert_eq!(&buf[..], event.to_struct().as_bytes());
+    }
+}
@@ -0,0 +1,679 @@
+use std::{
+    convert::TryInto,
+    env,
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    mem,
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        net::UnixStream,
+        process::CommandExt,
+    },
+    process::{Child, Command},
+    slice,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use neli::{utils::serialize, Nl};
+
+use crate::{
+    acpi::AcpiEvent,
+    evdev::{InputEvent, InputEventStruct},
+    plugin::{Backend, HandlerResult, Plugin, PluginError, PluginInfo},
+    power::{PowerSupplyState, PowerSupplyStruct},
+    sleep::ResumeReason,
+    thermal::{ThermalEvent, ThermalEventStruct},
+};
+
+/// First argument marking a process as a plugin worker
+const WORKER_FLAG: &str = "--plugin-worker";
+/// File descriptor of the worker's end of the socket pair
+const WORKER_FD: i32 = 3;
+
+// Frame types. Every frame is a native endian u32 type and u32 payload
+// length followed by the payload.
+/// Supervisor to worker: `InputEventStruct` as laid out in memory
+const MSG_EVDEV: u32 = 1;
+/// Supervisor to worker: serialized `AcpiEvent`
+const MSG_ACPI: u32 = 2;
+/// Worker to supervisor: i32 handler return code
+const MSG_RESULT: u32 = 3;
+/// Worker to supervisor: the plugin does not handle this event type
+const MSG_UNSUPPORTED: u32 = 4;
+/// Worker to supervisor: `PluginInfo` of the loaded plugin
+const MSG_INFO: u32 = 5;
+/// Worker to supervisor: UTF-8 error message
+const MSG_ERROR: u32 = 6;
+/// Supervisor to worker: u32 idle level
+const MSG_IDLE: u32 = 7;
+/// Supervisor to worker: `PowerSupplyStruct` as laid out in memory
+const MSG_POWER_SUPPLY: u32 = 8;
+/// Supervisor to worker: `ThermalEventStruct` as laid out in memory
+const MSG_THERMAL: u32 = 9;
+/// Supervisor to worker: consecutive `InputEventStruct`s as laid out in
+/// memory
+const MSG_EVDEV_FRAME: u32 = 10;
+/// Supervisor to worker: u32 resume reason
+const MSG_RESUME: u32 = 11;
+/// Supervisor to worker: no payload
+const MSG_PRE_SUSPEND: u32 = 12;
+/// Supervisor to worker: u64 milliseconds slept
+const MSG_POST_RESUME: u32 = 13;
+
+/// Large enough for a frame of `MAX_FRAME_EVENTS` input events
+const MAX_FRAME_LEN: usize = 8192;
+
+/// How long a handler call in a worker may take before the worker is
+/// considered hung and restarted
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a worker gets to call `pwrsurge_shutdown` and exit
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+/// A worker that dies sooner than this after starting has its restart
+/// backoff doubled
+const STABLE_TIME: Duration = Duration::from_secs(10);
+
+fn write_frame<W: Write>(w: &mut W, kind: u32, payload: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(8 + payload.len());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    buf.extend_from_slice(payload);
+    w.write_all(&buf)
+}
+
+fn read_frame<R: Read>(r: &mut R) -> io::Result<(u32, Vec<u8>)> {
+    let mut header = [0u8; 8];
+    r.read_exact(&mut header)?;
+    let kind = u32::from_ne_bytes(header[..4].try_into().expect("Constant size"));
+    let len = u32::from_ne_bytes(header[4..].try_into().expect("Constant size")) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds the maximum of {}", len, MAX_FRAME_LEN),
+        ));
+    }
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)?;
+    Ok((kind, payload))
+}
+
+fn info_to_bytes(info: &PluginInfo) -> &[u8] {
+    unsafe { slice::from_raw_parts(info as *const _ as *const u8, mem::size_of::<PluginInfo>()) }
+}
+
+fn info_from_bytes(bytes: &[u8]) -> Option<PluginInfo> {
+    if bytes.len() != mem::size_of::<PluginInfo>() {
+        return None;
+    }
+    Some(unsafe { (bytes.as_ptr() as *const PluginInfo).read_unaligned() })
+}
+
+/// A running worker process and the supervisor's end of its socket
+struct Connection {
+    child: Child,
+    stream: UnixStream,
+    started: Instant,
+}
+
+impl Connection {
+    fn spawn(
+        path: &str,
+        config_path: &str,
+        private_network: bool,
+    ) -> Result<(Self, PluginInfo), Box<dyn Error>> {
+        let (stream, child_stream) = UnixStream::pair()?;
+        let child_fd = child_stream.as_raw_fd();
+        let mut cmd = Command::new("/proc/self/exe");
+        cmd.arg0("pwrsurge-worker")
+            .arg(WORKER_FLAG)
+            .arg(path)
+            .arg(config_path);
+        if private_network {
+            cmd.arg("--private-network");
+        }
+        unsafe {
+            cmd.pre_exec(move || {
+                if child_fd == WORKER_FD {
+                    if libc::fcntl(child_fd, libc::F_SETFD, 0) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                } else if libc::dup2(child_fd, WORKER_FD) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Keep signals from the controlling terminal away from the
+                // worker; it exits when the daemon closes the socket.
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = cmd.spawn()?;
+        drop(child_stream);
+        stream.set_read_timeout(Some(CALL_TIMEOUT))?;
+
+        let mut conn = Connection {
+            child,
+            stream,
+            started: Instant::now(),
+        };
+        let info = match read_frame(&mut conn.stream)? {
+            (MSG_INFO, payload) => info_from_bytes(&payload)
+                .ok_or_else(|| PluginError(format!("Worker for {} sent malformed plugin info", path)))?,
+            (MSG_ERROR, payload) => {
+                return Err(Box::new(PluginError(String::from_utf8_lossy(&payload).into_owned())))
+            }
+            (kind, _) => {
+                return Err(Box::new(PluginError(format!(
+                    "Worker for {} sent unexpected frame type {} on startup",
+                    path, kind
+                ))))
+            }
+        };
+        Ok((conn, info))
+    }
+
+    fn call(&mut self, kind: u32, payload: &[u8]) -> io::Result<Option<Result<i32, String>>> {
+        write_frame(&mut self.stream, kind, payload)?;
+        match read_frame(&mut self.stream)? {
+            (MSG_RESULT, payload) if payload.len() == 4 => Ok(Some(Ok(i32::from_ne_bytes(
+                payload[..].try_into().expect("Constant size"),
+            )))),
+            (MSG_UNSUPPORTED, _) => Ok(None),
+            (MSG_ERROR, payload) => Ok(Some(Err(String::from_utf8_lossy(&payload).into_owned()))),
+            (kind, _) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected frame type {} from worker", kind),
+            )),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Closing the socket tells the worker to shut the plugin down
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while Instant::now() < deadline {
+            match self.child.try_wait() {
+                Ok(Some(_)) | Err(_) => return,
+                Ok(None) => thread::sleep(Duration::from_millis(20)),
+            }
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+struct WorkerState {
+    conn: Option<Connection>,
+    next_restart: Instant,
+    backoff: Duration,
+}
+
+/// A plugin hosted by a separate, sandboxed worker process. Calls are
+/// serialized over a socket pair. If the worker crashes, hangs or
+/// exits, it is restarted with an increasing backoff and the events
+/// arriving in the meantime fail with an error.
+pub struct Worker {
+    path: Box<str>,
+    config_path: Box<str>,
+    private_network: bool,
+    info: PluginInfo,
+    state: Mutex<WorkerState>,
+}
+
+impl Worker {
+    pub fn spawn(
+        path: &str,
+        config_path: &str,
+        private_network: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (conn, info) = Connection::spawn(path, config_path, private_network)?;
+        println!("Started worker {} for plugin {}", conn.child.id(), path);
+        Ok(Worker {
+            path: Box::from(path),
+            config_path: Box::from(config_path),
+            private_network,
+            info,
+            state: Mutex::new(WorkerState {
+                conn: Some(conn),
+                next_restart: Instant::now(),
+                backoff: MIN_RESTART_BACKOFF,
+            }),
+        })
+    }
+
+    fn retire(&self, state: &mut WorkerState, reason: &dyn std::fmt::Display) {
+        if let Some(mut conn) = state.conn.take() {
+            let status = conn.child.try_wait().ok().flatten();
+            match status {
+                Some(status) => println!(
+                    "Worker {} for plugin {} exited ({}): {}",
+                    conn.child.id(),
+                    self.path,
+                    status,
+                    reason,
+                ),
+                None => println!(
+                    "Stopping worker {} for plugin {}: {}",
+                    conn.child.id(),
+                    self.path,
+                    reason,
+                ),
+            }
+            state.backoff = if conn.started.elapsed() < STABLE_TIME {
+                (state.backoff * 2).min(MAX_RESTART_BACKOFF)
+            } else {
+                MIN_RESTART_BACKOFF
+            };
+            state.next_restart = Instant::now() + state.backoff;
+        }
+    }
+
+    fn call(&self, kind: u32, payload: &[u8]) -> Option<HandlerResult> {
+        let mut state = self.state.lock().expect("Lock poisoned");
+        if let Some(Ok(Some(status))) = state.conn.as_mut().map(|c| c.child.try_wait()) {
+            self.retire(&mut state, &format!("exited with {}", status));
+        }
+        if state.conn.is_none() {
+            if Instant::now() < state.next_restart {
+                return Some(Err(format!("Worker for plugin {} is restarting", self.path).into()));
+            }
+            match Connection::spawn(&self.path, &self.config_path, self.private_network) {
+                Ok((conn, _)) => {
+                    println!("Restarted worker {} for plugin {}", conn.child.id(), self.path);
+                    state.conn = Some(conn);
+                }
+                Err(e) => {
+                    state.backoff = (state.backoff * 2).min(MAX_RESTART_BACKOFF);
+                    state.next_restart = Instant::now() + state.backoff;
+                    return Some(Err(format!(
+                        "Failed to restart worker for plugin {}: {}",
+                        self.path, e
+                    )
+                    .into()));
+                }
+            }
+        }
+        let result = state
+            .conn
+            .as_mut()
+            .expect("Connection was established above")
+            .call(kind, payload);
+        match result {
+            Ok(Some(Ok(code))) => Some(Ok(code)),
+            Ok(Some(Err(e))) => Some(Err(e.into())),
+            Ok(None) => None,
+            Err(e) => {
+                self.retire(&mut state, &e);
+                Some(Err(format!("Worker for plugin {} failed: {}", self.path, e).into()))
+            }
+        }
+    }
+}
+
+impl Backend for Worker {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn info(&self) -> &PluginInfo {
+        &self.info
+    }
+
+    fn handle_evdev(&self, event: &InputEvent) -> Option<HandlerResult> {
+        self.call(MSG_EVDEV, event.as_buffer())
+    }
+
+    fn handle_evdev_frame(&self, events: &[InputEvent]) -> Option<HandlerResult> {
+        let payload = events
+            .iter()
+            .flat_map(|event| event.as_buffer().iter().copied())
+            .collect::<Vec<_>>();
+        self.call(MSG_EVDEV_FRAME, &payload)
+    }
+
+    fn handle_acpi(&self, event: &AcpiEvent) -> Option<HandlerResult> {
+        match serialize(event, false) {
+            Ok(buf) => self.call(MSG_ACPI, buf.as_slice()),
+            Err(e) => Some(Err(Box::new(e))),
+        }
+    }
+
+    fn handle_idle(&self, level: u32) -> Option<HandlerResult> {
+        self.call(MSG_IDLE, &level.to_ne_bytes())
+    }
+
+    fn handle_resume(&self, reason: ResumeReason) -> Option<HandlerResult> {
+        self.call(MSG_RESUME, &(reason as u32).to_ne_bytes())
+    }
+
+    fn handle_pre_suspend(&self) -> Option<HandlerResult> {
+        self.call(MSG_PRE_SUSPEND, &[])
+    }
+
+    fn handle_post_resume(&self, slept: Duration) -> Option<HandlerResult> {
+        self.call(MSG_POST_RESUME, &(slept.as_millis() as u64).to_ne_bytes())
+    }
+
+    fn handle_power_supply(&self, state: &PowerSupplyState) -> Option<HandlerResult> {
+        self.call(MSG_POWER_SUPPLY, state.to_struct().as_bytes())
+    }
+
+    fn handle_thermal(&self, event: &ThermalEvent) -> Option<HandlerResult> {
+        self.call(MSG_THERMAL, event.to_struct().as_bytes())
+    }
+}
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+// Offsets into struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// System calls a power management handler has no business making
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_open_by_handle_at,
+];
+
+fn seccomp_program(arch: u32) -> Vec<SockFilter> {
+    let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let mut prog = vec![
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_ARCH },
+        // Calls made through another ABI would bypass the numbers below
+        SockFilter { code: BPF_JMP_JEQ_K, jt: 1, jf: 0, k: arch },
+        SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: deny },
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_NR },
+        // x32 system calls on x86_64
+        SockFilter { code: BPF_JMP_JGE_K, jt: DENIED_SYSCALLS.len() as u8 + 1, jf: 0, k: 0x4000_0000 },
+    ];
+    for (i, nr) in DENIED_SYSCALLS.iter().enumerate() {
+        let remaining = (DENIED_SYSCALLS.len() - i - 1) as u8;
+        prog.push(SockFilter {
+            code: BPF_JMP_JEQ_K,
+            jt: remaining + 1,
+            jf: 0,
+            k: *nr as u32,
+        });
+    }
+    prog.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ALLOW });
+    prog.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: deny });
+    prog
+}
+
+fn install_seccomp() -> io::Result<()> {
+    let arch = match AUDIT_ARCH {
+        Some(arch) => arch,
+        None => return Err(io::Error::other("unsupported architecture")),
+    };
+    let prog = seccomp_program(arch);
+    let fprog = SockFprog {
+        len: prog.len() as u16,
+        filter: prog.as_ptr(),
+    };
+    if unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+            &fprog as *const SockFprog,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Drop every capability from the bounding, effective, permitted and
+/// inheritable sets. A worker running as root keeps access to files
+/// owned by root, such as those in sysfs, but not to anything that
+/// needs a capability.
+fn drop_capabilities() -> io::Result<()> {
+    let last_cap = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|s| s.trim().parse::<libc::c_ulong>().ok())
+        .unwrap_or(40);
+    for cap in 0..=last_cap {
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } < 0 {
+            let err = io::Error::last_os_error();
+            // EINVAL for capabilities this kernel does not know about;
+            // EPERM when the worker never had CAP_SETPCAP.
+            if err.raw_os_error() != Some(libc::EINVAL) && err.raw_os_error() != Some(libc::EPERM) {
+                return Err(err);
+            }
+        }
+    }
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &header as *const CapHeader, data.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Restrict the worker process as far as the kernel and the privileges
+/// it was started with allow. Each step that is unavailable is logged
+/// and skipped.
+fn sandbox(private_network: bool) {
+    if private_network && unsafe { libc::unshare(libc::CLONE_NEWNET) } < 0 {
+        println!(
+            "Worker could not create a private network namespace: {}",
+            io::Error::last_os_error()
+        );
+    }
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        println!("Worker could not set no_new_privs: {}", io::Error::last_os_error());
+    }
+    if let Err(e) = drop_capabilities() {
+        println!("Worker could not drop capabilities: {}", e);
+    }
+    if let Err(e) = install_seccomp() {
+        println!("Worker could not install seccomp filter: {}", e);
+    }
+}
+
+fn handle_frame(plugin: &Plugin, kind: u32, payload: &[u8]) -> Option<HandlerResult> {
+    match kind {
+        MSG_EVDEV => {
+            let buf: [u8; mem::size_of::<InputEventStruct>()] = match payload.try_into() {
+                Ok(buf) => buf,
+                Err(_) => return Some(Err("Malformed evdev event".into())),
+            };
+            plugin.handle_evdev(&InputEvent::new_buffer(buf))
+        }
+        MSG_EVDEV_FRAME => {
+            let events = payload
+                .chunks(mem::size_of::<InputEventStruct>())
+                .map(|chunk| chunk.try_into().map(InputEvent::new_buffer))
+                .collect::<Result<Vec<_>, _>>();
+            match events {
+                Ok(events) => plugin.handle_evdev_frame(&events),
+                Err(_) => Some(Err("Malformed evdev frame".into())),
+            }
+        }
+        MSG_ACPI => match AcpiEvent::deserialize(payload) {
+            Ok(event) => plugin.handle_acpi(&event),
+            Err(e) => Some(Err(Box::new(e))),
+        },
+        MSG_IDLE => match payload.try_into() {
+            Ok(buf) => plugin.handle_idle(u32::from_ne_bytes(buf)),
+            Err(_) => Some(Err("Malformed idle level".into())),
+        },
+        MSG_RESUME => match payload.try_into().ok().and_then(ResumeReason::from_raw) {
+            Some(reason) => plugin.handle_resume(reason),
+            None => Some(Err("Malformed resume reason".into())),
+        },
+        MSG_PRE_SUSPEND => plugin.handle_pre_suspend(),
+        MSG_POST_RESUME => match payload.try_into() {
+            Ok(buf) => plugin.handle_post_resume(Duration::from_millis(u64::from_ne_bytes(buf))),
+            Err(_) => Some(Err("Malformed sleep duration".into())),
+        },
+        MSG_POWER_SUPPLY => match PowerSupplyStruct::from_bytes(payload) {
+            Some(state) => plugin.handle_power_supply_struct(&state),
+            None => Some(Err("Malformed power supply state".into())),
+        },
+        MSG_THERMAL => match ThermalEventStruct::from_bytes(payload) {
+            Some(event) => plugin.handle_thermal_struct(&event),
+            None => Some(Err("Malformed thermal event".into())),
+        },
+        kind => Some(Err(format!("Unknown frame type {}", kind).into())),
+    }
+}
+
+fn run_worker(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (path, config_path) = match args {
+        [path, config_path, ..] => (path, config_path),
+        _ => return Err("Usage: pwrsurge --plugin-worker LIBRARY_PATH CONFIG_PATH".into()),
+    };
+    let private_network = args.get(2).map(|s| s.as_str()) == Some("--private-network");
+    let mut stream = unsafe { UnixStream::from_raw_fd(WORKER_FD) };
+
+    sandbox(private_network);
+    let plugin = match Plugin::load(path, config_path) {
+        Ok(p) => p,
+        Err(e) => {
+            write_frame(&mut stream, MSG_ERROR, e.to_string().as_bytes())?;
+            return Err(e);
+        }
+    };
+    write_frame(&mut stream, MSG_INFO, info_to_bytes(plugin.info()))?;
+
+    loop {
+        let (kind, payload) = match read_frame(&mut stream) {
+            Ok(frame) => frame,
+            // The daemon closed the socket; drop the plugin to shut it down
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        match handle_frame(&plugin, kind, &payload) {
+            Some(Ok(code)) => write_frame(&mut stream, MSG_RESULT, &code.to_ne_bytes())?,
+            Some(Err(e)) => write_frame(&mut stream, MSG_ERROR, e.to_string().as_bytes())?,
+            None => write_frame(&mut stream, MSG_UNSUPPORTED, &[])?,
+        }
+    }
+}
+
+/// If this process was started as a plugin worker, run the worker and
+/// return its exit code.
+pub fn run_if_worker() -> Option<i32> {
+    let args = env::args().collect::<Vec<_>>();
+    if args.get(1).map(|s| s.as_str()) != Some(WORKER_FLAG) {
+        return None;
+    }
+    Some(match run_worker(&args[2..]) {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Plugin worker failed: {}", e);
+            1
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, MSG_RESULT, &(-5i32).to_ne_bytes()).unwrap();
+        write_frame(&mut buf, MSG_UNSUPPORTED, &[]).unwrap();
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(
+            read_frame(&mut cursor).unwrap(),
+            (MSG_RESULT, (-5i32).to_ne_bytes().to_vec())
+        );
+        assert_eq!(read_frame(&mut cursor).unwrap(), (MSG_UNSUPPORTED, Vec::new()));
+        assert_eq!(
+            read_frame(&mut cursor).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&MSG_EVDEV.to_ne_bytes());
+        buf.extend_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_ne_bytes());
+        assert_eq!(
+            read_frame(&mut Cursor::new(buf)).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_seccomp_program_jumps() {
+        let prog = seccomp_program(0xc000_003e);
+        let allow = prog.len() - 2;
+        let deny = prog.len() - 1;
+        assert_eq!(prog[allow].k, SECCOMP_RET_ALLOW);
+        // Every syscall comparison jumps to the final deny instruction
+        for (i, insn) in prog.iter().enumerate().skip(4).take(DENIED_SYSCALLS.len() + 1) {
+            assert_eq!(i + 1 + insn.jt as usize, deny);
+        }
+    }
+}